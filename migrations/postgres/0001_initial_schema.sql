@@ -0,0 +1,49 @@
+-- Users and their current credit balance
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    credits BIGINT NOT NULL CHECK (credits >= 0),
+    created_at TIMESTAMPTZ NOT NULL,
+    last_credit_update_at TIMESTAMPTZ NOT NULL
+);
+
+-- Payment requests, stored as JSON with the columns needed for lookups
+CREATE TABLE payment_requests (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    external_id TEXT UNIQUE,
+    status TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    data JSONB NOT NULL
+);
+
+CREATE INDEX payment_requests_user_idx ON payment_requests (user_id, created_at);
+CREATE INDEX payment_requests_pending_idx ON payment_requests (status) WHERE status = 'pending';
+
+-- Every change to a user's credits, written in the same transaction as the balance
+CREATE TABLE credit_ledger (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    delta BIGINT NOT NULL,
+    balance BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX credit_ledger_user_idx ON credit_ledger (user_id, id);
+
+-- Claimed webhook event IDs, for replay protection
+CREATE TABLE webhook_events (
+    key TEXT PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX webhook_events_expires_idx ON webhook_events (expires_at);
+
+-- Idempotency-Key records and their stored responses
+CREATE TABLE idempotency_keys (
+    key TEXT PRIMARY KEY,
+    record JSONB NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idempotency_keys_expires_idx ON idempotency_keys (expires_at);
@@ -0,0 +1,24 @@
+-- Record why each credit change happened and what caused it
+ALTER TABLE credit_ledger ADD COLUMN reason TEXT;
+ALTER TABLE credit_ledger ADD COLUMN reference TEXT;
+
+-- Existing entries: the first one per user is the signup grant, after that only
+-- purchases added credits and only spends removed them
+UPDATE credit_ledger SET reason = CASE
+    WHEN id IN (SELECT MIN(id) FROM credit_ledger GROUP BY user_id) THEN 'grant'
+    WHEN delta < 0 THEN 'spend'
+    ELSE 'purchase'
+END;
+
+ALTER TABLE credit_ledger ALTER COLUMN reason SET NOT NULL;
+
+-- Entries are never changed once written; corrections are new entries
+CREATE FUNCTION credit_ledger_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'credit_ledger is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER credit_ledger_append_only
+    BEFORE UPDATE OR DELETE ON credit_ledger
+    FOR EACH ROW EXECUTE FUNCTION credit_ledger_append_only();
@@ -0,0 +1,2 @@
+-- API route credits were spent on, for usage history
+ALTER TABLE credit_ledger ADD COLUMN route TEXT;
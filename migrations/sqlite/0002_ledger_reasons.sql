@@ -0,0 +1,22 @@
+-- Record why each credit change happened and what caused it
+ALTER TABLE credit_ledger ADD COLUMN reason TEXT NOT NULL DEFAULT 'adjustment';
+ALTER TABLE credit_ledger ADD COLUMN reference TEXT;
+
+-- Existing entries: the first one per user is the signup grant, after that only
+-- purchases added credits and only spends removed them
+UPDATE credit_ledger SET reason = CASE
+    WHEN id IN (SELECT MIN(id) FROM credit_ledger GROUP BY user_id) THEN 'grant'
+    WHEN delta < 0 THEN 'spend'
+    ELSE 'purchase'
+END;
+
+-- Entries are never changed once written; corrections are new entries
+CREATE TRIGGER credit_ledger_no_update BEFORE UPDATE ON credit_ledger
+BEGIN
+    SELECT RAISE(ABORT, 'credit_ledger is append-only');
+END;
+
+CREATE TRIGGER credit_ledger_no_delete BEFORE DELETE ON credit_ledger
+BEGIN
+    SELECT RAISE(ABORT, 'credit_ledger is append-only');
+END;
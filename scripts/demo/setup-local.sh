@@ -43,7 +43,7 @@ REDIS_URL=redis://localhost:6379
 # LNBits configuration
 LNBITS_URL=https://legend.lnbits.com
 LNBITS_API_KEY=your_lnbits_api_key
-LNBITS_WEBHOOK_KEY=your_lnbits_webhook_key
+LNBITS_WEBHOOK_URL=http://localhost:8080/webhook/lightning
 LNBITS_INVOICE_READ_KEY=your_lnbits_invoice_read_key
 EOL
   echo -e "${GREEN}✓ Created default .env file${NC}"
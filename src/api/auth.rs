@@ -1,7 +1,8 @@
 use crate::storage::StorageError;
+use crate::utils::constant_time_eq;
 use axum::{
     extract::{FromRequestParts, Request, State},
-    http::{StatusCode, header, request::Parts},
+    http::{HeaderMap, StatusCode, header, request::Parts},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -20,6 +21,8 @@ pub enum AuthError {
     /// Storage error
     #[allow(dead_code)]
     StorageError(StorageError),
+    /// Backend service key missing or not configured
+    InvalidServiceKey,
 }
 
 impl IntoResponse for AuthError {
@@ -28,6 +31,7 @@ impl IntoResponse for AuthError {
             AuthError::MissingToken | AuthError::InvalidTokenFormat => StatusCode::UNAUTHORIZED,
             AuthError::UserNotFound => StatusCode::UNAUTHORIZED,
             AuthError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthError::InvalidServiceKey => StatusCode::UNAUTHORIZED,
         };
 
         let message = match self {
@@ -38,6 +42,7 @@ impl IntoResponse for AuthError {
                 error!("Storage error during authentication: {}", e);
                 "Internal server error"
             }
+            AuthError::InvalidServiceKey => "Invalid service key",
         };
 
         let body = serde_json::json!({
@@ -82,6 +87,27 @@ where
     }
 }
 
+/// Check that a request comes from a backend service holding one of the keys
+///
+/// Services authenticate with `Authorization: Bearer <service key>`.
+pub fn verify_service_key(headers: &HeaderMap, keys: &[String]) -> Result<(), AuthError> {
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or(AuthError::InvalidServiceKey)?;
+
+    if keys
+        .iter()
+        .any(|expected| constant_time_eq(key.as_bytes(), expected.as_bytes()))
+    {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidServiceKey)
+    }
+}
+
 /// Authentication middleware to verify a user's token
 #[allow(dead_code)]
 pub async fn require_auth(
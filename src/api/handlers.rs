@@ -1,10 +1,32 @@
-use crate::api::auth::UserId;
-use crate::models::{PaymentRequestInput, PaymentRequestResponse, PaymentRequiredResponse, User};
-use crate::storage::StorageError;
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
-use chrono::{Duration, Utc};
+use crate::api::auth::{UserId, verify_service_key};
+use crate::api::idempotency;
+use crate::events::Event;
+use crate::models::{
+    CreditAdjustmentRequest, CreditReason, IntrospectionRequest, IntrospectionResponse,
+    PaymentRequestInput, PaymentRequestResponse, PaymentStatusResponse, UsageEntry, UsageResponse,
+    User,
+};
+use crate::payments::PaymentError;
+use crate::payments::context::PaymentContext;
+use crate::paywall::{CREDITS_REMAINING_HEADER, MeteredFeed, REQUEST_ID_HEADER};
+use crate::storage::{LedgerQuery, StorageError};
+use axum::{
+    Json,
+    extract::{Path, Query, State, ws::WebSocketUpgrade},
+    http::{HeaderMap, Method, StatusCode, Uri, header},
+    response::{
+        IntoResponse, Response,
+        sse::{Event as SseEvent, KeepAlive, Sse},
+    },
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
 use serde_json::json;
-use tracing::{error, info};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tracing::{error, info, warn};
 
 /// Handler for creating a new user
 pub async fn signup(State(state): State<crate::api::routes::AppState>) -> impl IntoResponse {
@@ -53,17 +75,207 @@ pub async fn get_user_info(
     }
 }
 
+/// Entries per usage page when no limit is given
+const DEFAULT_USAGE_PAGE_SIZE: usize = 50;
+
+/// Largest usage page that can be requested
+const MAX_USAGE_PAGE_SIZE: usize = 500;
+
+/// Output format of the usage endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    /// A page of entries as JSON
+    #[default]
+    Json,
+    /// Every matching entry as a CSV download
+    Csv,
+}
+
+/// Query parameters of the usage endpoint
+#[derive(Debug, Deserialize)]
+pub struct UsageParams {
+    /// Only entries at or after this time (RFC 3339)
+    from: Option<DateTime<Utc>>,
+    /// Only entries before this time (RFC 3339)
+    to: Option<DateTime<Utc>>,
+    /// `next_cursor` of the previous page
+    cursor: Option<u64>,
+    /// Maximum number of entries to return
+    limit: Option<usize>,
+    /// Output format
+    #[serde(default)]
+    format: UsageFormat,
+}
+
+/// Handler for the caller's history of credit purchases, spends and refunds
+///
+/// JSON responses are paginated with `cursor`; CSV exports contain every matching
+/// entry unless a `limit` is given.
+pub async fn get_usage(
+    State(state): State<crate::api::routes::AppState>,
+    UserId(user_id): UserId,
+    Query(params): Query<UsageParams>,
+) -> impl IntoResponse {
+    let limit = match params.format {
+        UsageFormat::Json => Some(
+            params
+                .limit
+                .unwrap_or(DEFAULT_USAGE_PAGE_SIZE)
+                .clamp(1, MAX_USAGE_PAGE_SIZE),
+        ),
+        UsageFormat::Csv => params.limit,
+    };
+
+    let query = LedgerQuery {
+        reasons: vec![
+            CreditReason::Purchase,
+            CreditReason::Spend,
+            CreditReason::Refund,
+            CreditReason::Adjustment,
+        ],
+        from: params.from,
+        to: params.to,
+        after: params.cursor,
+        // One extra entry tells whether there is another page
+        limit: limit.map(|limit| limit + 1),
+    };
+
+    let mut entries = match state.storage.list_ledger_entries(&user_id, &query).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Error listing usage for user {}: {}", user_id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to retrieve usage"})),
+            )
+                .into_response();
+        }
+    };
+
+    let has_more = limit.is_some_and(|limit| entries.len() > limit);
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+    let next_cursor = if has_more {
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    let entries: Vec<UsageEntry> = entries.into_iter().map(UsageEntry::from).collect();
+
+    match params.format {
+        UsageFormat::Json => (
+            StatusCode::OK,
+            Json(UsageResponse {
+                entries,
+                next_cursor,
+            }),
+        )
+            .into_response(),
+        UsageFormat::Csv => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"usage.csv\"",
+                ),
+            ],
+            usage_csv(&entries),
+        )
+            .into_response(),
+    }
+}
+
+/// Render usage entries as CSV, with a header row
+fn usage_csv(entries: &[UsageEntry]) -> String {
+    let mut csv = String::from("id,created_at,type,route,credits,balance,payment_id,request_id\n");
+
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry
+                .created_at
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            entry.kind.as_str().to_string(),
+            entry.route.clone().unwrap_or_default(),
+            entry.credits.to_string(),
+            entry.balance.to_string(),
+            entry.payment_id.clone().unwrap_or_default(),
+            entry.request_id.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 /// Handler for initiating a payment
+///
+/// Honors an `Idempotency-Key` header scoped to the paying user: a retry with the
+/// same key and body gets the original response instead of a new invoice.
 #[axum::debug_handler]
 pub async fn initiate_payment(
     State(state): State<crate::api::routes::AppState>,
+    headers: HeaderMap,
     Json(input): Json<PaymentRequestInput>,
-) -> impl IntoResponse {
+) -> Response {
+    let idempotency_key = match idempotency::idempotency_key(&headers) {
+        Ok(key) => key,
+        Err(e) => return e.into_response(),
+    };
+
+    // Scope keys to the user the context token was issued to, like the keys of
+    // authenticated routes. Invalid tokens are turned away without reserving one.
+    let user_id = PaymentContext::verify(
+        &input.payment_context_token,
+        &state.config.payment_context_secret,
+    )
+    .map(|context| context.user_id);
+    let (Some(key), Ok(user_id)) = (idempotency_key, user_id) else {
+        return process_payment_request(&state, input).await;
+    };
+    let idempotency_key = format!("payment-request:{}:{}", user_id, key);
+
+    let storage = state.storage.as_ref();
+    let ttl_secs = state.config.idempotency_ttl_secs;
+    let lease_secs = state.config.idempotency_lease_secs;
+    let body = serde_json::to_vec(&input).unwrap_or_default();
+    let fingerprint = idempotency::fingerprint(&[&body]);
+
+    match idempotency::begin(storage, &idempotency_key, &fingerprint, lease_secs).await {
+        Ok(Some(replayed)) => return replayed,
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+
+    let response = process_payment_request(&state, input).await;
+    idempotency::complete(storage, &idempotency_key, &fingerprint, response, ttl_secs).await
+}
+
+/// Create the payment request and build the handler response
+async fn process_payment_request(
+    state: &crate::api::routes::AppState,
+    input: PaymentRequestInput,
+) -> Response {
     let payment_service = &state.payment_service;
     match payment_service.process_payment_request(input).await {
         Ok((request, payment_details)) => {
             // Create the response
             let response = PaymentRequestResponse {
+                id: request.id,
                 payment_request: payment_details,
                 offer_id: request.offer_id,
                 expires_at: request.expires_at,
@@ -73,17 +285,20 @@ pub async fn initiate_payment(
         }
         Err(e) => {
             error!("Error processing payment request: {}", e);
-            let message = match e {
-                _ if e.to_string().contains("Offer not found") => "Invalid offer ID",
-                _ if e.to_string().contains("Invalid payment method") => "Invalid payment method",
-                _ if e.to_string().contains("User not found") => "Invalid user token",
-                _ => "Failed to process payment request",
-            };
-
-            let status = if message == "Invalid user token" {
-                StatusCode::UNAUTHORIZED
-            } else {
-                StatusCode::BAD_REQUEST
+            let (status, message) = match e {
+                PaymentError::InvalidOffer(_) => (StatusCode::BAD_REQUEST, "Invalid offer ID"),
+                PaymentError::OfferChanged(_) => (
+                    StatusCode::CONFLICT,
+                    "Offer changed since it was quoted, request a new payment context",
+                ),
+                PaymentError::InvalidPaymentMethod(_) => {
+                    (StatusCode::BAD_REQUEST, "Invalid payment method")
+                }
+                PaymentError::InvalidContext(_) => {
+                    (StatusCode::UNAUTHORIZED, "Invalid payment context token")
+                }
+                PaymentError::UserNotFound(_) => (StatusCode::UNAUTHORIZED, "Invalid user token"),
+                _ => (StatusCode::BAD_REQUEST, "Failed to process payment request"),
             };
 
             (status, Json(json!({"error": message}))).into_response()
@@ -91,22 +306,160 @@ pub async fn initiate_payment(
     }
 }
 
+/// Longest a client may wait for a payment status change, in seconds
+const MAX_PAYMENT_STATUS_WAIT_SECS: u64 = 60;
+
+/// Query parameters of the payment status endpoint
+#[derive(Debug, Deserialize)]
+pub struct PaymentStatusParams {
+    /// Seconds to wait for the status to change before responding
+    wait: Option<u64>,
+}
+
+/// Handler for retrieving the status of a payment request
+///
+/// With `wait=N`, the response is held until the status changes or N seconds
+/// have passed (long polling).
+pub async fn get_payment_status(
+    State(state): State<crate::api::routes::AppState>,
+    UserId(user_id): UserId,
+    Path(payment_id): Path<String>,
+    Query(params): Query<PaymentStatusParams>,
+) -> impl IntoResponse {
+    let payment_service = &state.payment_service;
+
+    // Subscribe before reading, so a change in between isn't missed
+    let mut events = payment_service.events().subscribe();
+
+    let mut request = match payment_service.get_payment_request(&payment_id).await {
+        // Other users' payment requests are reported as missing
+        Ok(request) if request.user_id == user_id => request,
+        Ok(_) | Err(PaymentError::PaymentNotFound(_)) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "Payment request not found"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("Error retrieving payment request: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to retrieve payment request"})),
+            )
+                .into_response();
+        }
+    };
+
+    let wait = params.wait.unwrap_or(0).min(MAX_PAYMENT_STATUS_WAIT_SECS);
+    if wait > 0 {
+        let initial_status = request.status;
+        let deadline = time::Instant::now() + time::Duration::from_secs(wait);
+
+        loop {
+            match time::timeout_at(deadline, events.recv()).await {
+                Ok(Ok(Event::PaymentStatusChanged {
+                    payment_id: changed_id,
+                    status,
+                    ..
+                })) if changed_id == payment_id && status != initial_status => break,
+                Ok(Ok(_)) => continue,
+                // Missed some events, check storage directly
+                Ok(Err(RecvError::Lagged(_))) => {
+                    match payment_service.get_payment_request(&payment_id).await {
+                        Ok(current) if current.status != initial_status => break,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    }
+                }
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+            }
+        }
+
+        match payment_service.get_payment_request(&payment_id).await {
+            Ok(current) => request = current,
+            Err(e) => error!("Error refreshing payment request {}: {}", payment_id, e),
+        }
+    }
+
+    (StatusCode::OK, Json(PaymentStatusResponse::from(request))).into_response()
+}
+
+/// Handler streaming the user's payment and balance updates as server-sent events
+///
+/// The current balance is sent first, so clients don't need a separate request
+/// to initialize.
+pub async fn get_event_stream(
+    State(state): State<crate::api::routes::AppState>,
+    UserId(user_id): UserId,
+) -> impl IntoResponse {
+    // Subscribe before reading the balance, so a change in between isn't missed
+    let events = state.payment_service.events().subscribe();
+
+    let user = match state.storage.get_user(&user_id).await {
+        Ok(user) => user,
+        Err(StorageError::UserNotFound) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "User not found"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            error!("Error retrieving user: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to retrieve user"})),
+            )
+                .into_response();
+        }
+    };
+
+    let initial = Event::BalanceChanged {
+        user_id: user.id,
+        credits: user.credits,
+    };
+
+    let updates = BroadcastStream::new(events).filter_map(move |received| match received {
+        Ok(event) if event.user_id() == user_id => Some(event),
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            warn!(
+                "Event stream for user {} skipped {} events",
+                user_id, skipped
+            );
+            None
+        }
+    });
+
+    let stream = tokio_stream::once(initial)
+        .chain(updates)
+        .map(|event| SseEvent::default().event(event.name()).json_data(&event));
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Query parameters of the per-invoice Lightning webhook URL
+#[derive(Debug, Deserialize)]
+pub struct LightningWebhookParams {
+    /// Secret token generated when the invoice was created
+    #[serde(default)]
+    token: String,
+}
+
 /// Handler for Lightning webhooks
 pub async fn lightning_webhook(
     State(state): State<crate::api::routes::AppState>,
-    headers: axum::http::HeaderMap,
+    Query(params): Query<LightningWebhookParams>,
     body: axum::body::Bytes,
 ) -> impl IntoResponse {
     let payment_service = &state.payment_service;
-    // Get the signature from headers
-    let signature = headers
-        .get("X-Lightning-Signature")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
 
     // Process the webhook
     match payment_service
-        .process_lightning_webhook(&body, signature)
+        .process_lightning_webhook(&body, &params.token)
         .await
     {
         Ok(Some(user_id)) => {
@@ -125,80 +478,275 @@ pub async fn lightning_webhook(
 }
 
 /// Handler for retrieving Bitcoin latest block hash
+///
+/// Paid for through the paywall, which charges the route's price and refunds it if
+/// the block can't be fetched.
 pub async fn get_latest_block(
     State(state): State<crate::api::routes::AppState>,
-    UserId(user_id): UserId,
 ) -> impl IntoResponse {
-    let storage = &state.storage;
-    let block_service = &state.block_service;
-    let config = &state.config;
+    match state.block_service.get_latest_block().await {
+        Ok(block_data) => (StatusCode::OK, Json(block_data)).into_response(),
+        Err(e) => {
+            error!("Error fetching latest block hash: {}", e);
 
-    // Get the user
-    let user = match storage.get_user(&user_id).await {
-        Ok(user) => user,
-        Err(StorageError::UserNotFound) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({"error": "User not found"})),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"error": format!("Failed to fetch latest block hash: {}", e)})),
             )
-                .into_response();
+                .into_response()
         }
+    }
+}
+
+/// Path of the external authorization endpoint
+pub const AUTHZ_PATH: &str = "/authz";
+
+/// Header carrying the method of the request being authorized (nginx `auth_request`)
+const ORIGINAL_METHOD_HEADER: &str = "x-original-method";
+
+/// Header carrying the URI of the request being authorized (nginx `auth_request`)
+const ORIGINAL_URI_HEADER: &str = "x-original-uri";
+
+/// Header asking for the payment challenge in headers rather than a 402 body
+const CHALLENGE_MODE_HEADER: &str = "x-authz-challenge";
+
+/// Handler authorizing and charging a request made to another service
+///
+/// Works with Envoy's HTTP `ext_authz` filter, which sends the original method and
+/// headers with the original path appended to `/authz`, and with nginx
+/// `auth_request`, which passes them in `X-Original-Method` and `X-Original-URI`.
+/// The request is priced from the pricing table by its original method and path.
+///
+/// nginx turns any denial other than a 401 or 403 into a 500, so with
+/// `X-Authz-Challenge: headers` a user out of credits gets a 401 carrying the
+/// challenge in `WWW-Authenticate` instead of a 402.
+pub async fn authorize(
+    State(state): State<crate::api::routes::AppState>,
+    UserId(user_id): UserId,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let method = header(ORIGINAL_METHOD_HEADER)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+        .unwrap_or(method);
+    let challenge_in_headers =
+        header(CHALLENGE_MODE_HEADER).is_some_and(|mode| mode.eq_ignore_ascii_case("headers"));
+    let path = match header(ORIGINAL_URI_HEADER) {
+        Some(uri) => uri.split('?').next().unwrap_or(uri),
+        None => uri
+            .path()
+            .strip_prefix(AUTHZ_PATH)
+            .filter(|path| !path.is_empty())
+            .unwrap_or("/"),
+    };
+
+    let result = match state.config.pricing.route_price(method.as_str(), path) {
+        Some(cost) => state
+            .storage
+            .spend_user_credits(&user_id, cost, header(REQUEST_ID_HEADER), Some(path))
+            .await
+            .inspect(|_| {
+                info!(
+                    "User {} spent {} credits on {} {}",
+                    user_id, cost, method, path
+                )
+            }),
+        None => state.storage.get_user(&user_id).await,
+    };
+
+    match result {
+        Ok(user) => (
+            StatusCode::OK,
+            [(CREDITS_REMAINING_HEADER, user.credits.to_string())],
+        )
+            .into_response(),
+        Err(StorageError::InsufficientCredits { balance }) => {
+            info!("User {} is out of credits for {} {}", user_id, method, path);
+            let payment_required = state.payment_service.payment_required(&user_id);
+            let mut response = if challenge_in_headers {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(
+                        header::WWW_AUTHENTICATE,
+                        payment_required.www_authenticate(),
+                    )],
+                    Json(payment_required),
+                )
+                    .into_response()
+            } else {
+                (StatusCode::PAYMENT_REQUIRED, Json(payment_required)).into_response()
+            };
+            response
+                .headers_mut()
+                .insert(CREDITS_REMAINING_HEADER, balance.into());
+            response
+        }
+        Err(StorageError::UserNotFound) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Invalid token"})),
+        )
+            .into_response(),
         Err(e) => {
-            error!("Error retrieving user: {}", e);
-            return (
+            error!("Error authorizing request for user {}: {}", user_id, e);
+            (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Failed to retrieve user"})),
+                Json(json!({"error": "Failed to authorize request"})),
             )
-                .into_response();
+                .into_response()
         }
-    };
+    }
+}
 
-    // Check if the user has enough credits
-    if user.credits < 1 {
-        // User is out of credits, return 402 Payment Required
-        info!("User {} is out of credits", user_id);
-
-        // Create the payment required response
-        let expiry = Utc::now() + Duration::minutes(30);
-        let payment_required = PaymentRequiredResponse {
-            expiry,
-            offers: config.offers.clone(),
-            payment_context_token: user_id,
-            payment_request_url: config.get_payment_request_url(),
-        };
-
-        return (StatusCode::PAYMENT_REQUIRED, Json(payment_required)).into_response();
+/// Handler letting backend services check a user's token and spend their credits
+///
+/// Callers authenticate with a service key. Unknown tokens are reported as
+/// inactive; a debit the user can't cover is answered with a 402 and the payment
+/// challenge, for the service to pass on.
+pub async fn introspect_token(
+    State(state): State<crate::api::routes::AppState>,
+    headers: HeaderMap,
+    Json(input): Json<IntrospectionRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = verify_service_key(&headers, &state.config.service_keys) {
+        return rejection.into_response();
     }
 
-    // User has credits, try to get the latest block data
-    match block_service.get_latest_block().await {
-        Ok(block_data) => {
-            // Deduct one credit for the successful request
-            match storage.update_user_credits(&user_id, -1).await {
-                Ok(_) => {
-                    info!("User {} used 1 credit for latest block hash", user_id);
-                }
-                Err(e) => {
-                    error!("Failed to deduct credit from user {}: {}", user_id, e);
-                    // Continue anyway since the data was fetched
-                }
-            }
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok());
 
-            // Return the block data
-            (StatusCode::OK, Json(block_data)).into_response()
+    let result = match input.debit.filter(|debit| *debit > 0) {
+        Some(debit) => state
+            .storage
+            .spend_user_credits(&input.token, debit, request_id, input.route.as_deref())
+            .await
+            .map(|user| (user, debit)),
+        None => state
+            .storage
+            .get_user(&input.token)
+            .await
+            .map(|user| (user, 0)),
+    };
+
+    match result {
+        Ok((user, debited)) => {
+            if debited > 0 {
+                info!(
+                    "Service debited {} credits from user {} for {:?}",
+                    debited, user.id, input.route
+                );
+            }
+            (
+                StatusCode::OK,
+                Json(IntrospectionResponse::active(&user, debited)),
+            )
+                .into_response()
+        }
+        Err(StorageError::UserNotFound) => {
+            (StatusCode::OK, Json(IntrospectionResponse::inactive())).into_response()
+        }
+        Err(StorageError::InsufficientCredits { balance }) => {
+            let payment_required = state.payment_service.payment_required(&input.token);
+            (
+                StatusCode::PAYMENT_REQUIRED,
+                [(CREDITS_REMAINING_HEADER, balance.to_string())],
+                Json(payment_required),
+            )
+                .into_response()
         }
         Err(e) => {
-            error!("Error fetching latest block hash: {}", e);
+            error!("Error introspecting token: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to introspect token"})),
+            )
+                .into_response()
+        }
+    }
+}
 
+/// Handler letting operators correct a user's balance
+///
+/// Callers authenticate with an admin key. The change is recorded in the ledger
+/// as an adjustment; removing more credits than the user has stops at zero.
+pub async fn adjust_user_credits(
+    State(state): State<crate::api::routes::AppState>,
+    Path(user_id): Path<String>,
+    headers: HeaderMap,
+    Json(input): Json<CreditAdjustmentRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = verify_service_key(&headers, &state.config.admin_keys) {
+        return rejection.into_response();
+    }
+
+    match state
+        .storage
+        .update_user_credits(
+            &user_id,
+            input.credits,
+            CreditReason::Adjustment,
+            input.reference.as_deref(),
+            None,
+        )
+        .await
+    {
+        Ok(user) => {
+            info!(
+                "Adjusted credits of user {} by {} ({:?})",
+                user.id, input.credits, input.reference
+            );
+            (StatusCode::OK, Json(user)).into_response()
+        }
+        Err(StorageError::UserNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "User not found"})),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Error adjusting credits of user {}: {}", user_id, e);
             (
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(json!({"error": format!("Failed to fetch latest block hash: {}", e)})),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to adjust credits"})),
             )
                 .into_response()
         }
     }
 }
 
+/// Route of the live block feed
+const BLOCK_FEED_ROUTE: &str = "/feeds/blocks";
+
+/// Handler for the live feed of new Bitcoin blocks over WebSocket
+///
+/// Charged per message or per minute according to the feed pricing.
+pub async fn get_block_feed(
+    State(state): State<crate::api::routes::AppState>,
+    UserId(user_id): UserId,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let blocks = state
+        .block_service
+        .watch_blocks()
+        .map(|block| json!({"type": "block", "block": block}).to_string());
+
+    MeteredFeed::new(
+        state.config.feed_pricing.clone(),
+        state.storage.clone(),
+        state.payment_service.clone(),
+    )
+    .serve(upgrade, user_id, request_id, BLOCK_FEED_ROUTE, blocks)
+    .await
+}
+
 /// Handler for Coinbase webhooks
 pub async fn coinbase_webhook(
     State(state): State<crate::api::routes::AppState>,
@@ -237,20 +785,13 @@ pub async fn get_payment_options(
     State(state): State<crate::api::routes::AppState>,
     UserId(user_id): UserId,
 ) -> impl IntoResponse {
-    let config = &state.config;
     let storage = &state.storage;
 
     // Verify the user exists
     match storage.get_user(&user_id).await {
         Ok(_) => {
             // Create the payment options response
-            let expiry = Utc::now() + Duration::minutes(30);
-            let payment_options = PaymentRequiredResponse {
-                expiry,
-                offers: config.offers.clone(),
-                payment_context_token: user_id,
-                payment_request_url: config.get_payment_request_url(),
-            };
+            let payment_options = state.payment_service.payment_required(&user_id);
 
             (StatusCode::OK, Json(payment_options)).into_response()
         }
@@ -0,0 +1,283 @@
+use crate::api::auth::UserId;
+use crate::api::routes::AppState;
+use crate::models::{IdempotencyRecord, StoredResponse};
+use crate::storage::{Storage, StorageError};
+use axum::{
+    Json,
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, warn};
+
+/// Header clients send to make a request idempotent
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set on responses replayed from a previous request
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Longest accepted Idempotency-Key value
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Largest request or response body buffered for idempotent replay
+const MAX_STORED_BODY_BYTES: usize = 1024 * 1024;
+
+/// Content type of server-sent event streams
+const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+/// Error when an idempotent request can't proceed
+#[derive(Debug)]
+pub enum IdempotencyError {
+    /// Malformed Idempotency-Key header
+    InvalidKey,
+    /// The key was already used for a different request
+    KeyReused,
+    /// The original request with this key hasn't finished yet
+    InProgress,
+    /// Storage error
+    StorageError(StorageError),
+}
+
+impl IntoResponse for IdempotencyError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            IdempotencyError::InvalidKey => {
+                (StatusCode::BAD_REQUEST, "Invalid Idempotency-Key header")
+            }
+            IdempotencyError::KeyReused => (
+                StatusCode::CONFLICT,
+                "Idempotency-Key was already used for a different request",
+            ),
+            IdempotencyError::InProgress => (
+                StatusCode::CONFLICT,
+                "A request with this Idempotency-Key is still in progress",
+            ),
+            IdempotencyError::StorageError(e) => {
+                error!("Storage error during idempotency check: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to process idempotency key",
+                )
+            }
+        };
+
+        (status, Json(json!({"error": message}))).into_response()
+    }
+}
+
+/// Read the Idempotency-Key header, if the client sent one
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, IdempotencyError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key.to_string())),
+        _ => Err(IdempotencyError::InvalidKey),
+    }
+}
+
+/// Hash the parts that identify a request
+pub fn fingerprint(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Reserve an idempotency key before running the request
+///
+/// The key is only reserved for `lease_secs` until [`complete`] keeps the response
+/// for longer, so a key whose request never finished (e.g. because the server
+/// crashed) frees up again. Returns `Ok(None)` if the request should run, or
+/// `Ok(Some(response))` with the original response if it already completed.
+pub async fn begin(
+    storage: &dyn Storage,
+    key: &str,
+    fingerprint: &str,
+    lease_secs: u64,
+) -> Result<Option<Response>, IdempotencyError> {
+    let record = IdempotencyRecord::in_progress(fingerprint.to_string());
+
+    let existing = storage
+        .begin_idempotent_request(key, &record, lease_secs)
+        .await
+        .map_err(IdempotencyError::StorageError)?;
+
+    let Some(existing) = existing else {
+        return Ok(None);
+    };
+
+    if existing.fingerprint != fingerprint {
+        warn!("Idempotency key {} reused with a different request", key);
+        return Err(IdempotencyError::KeyReused);
+    }
+
+    match existing.response {
+        Some(stored) => {
+            debug!("Replaying response for idempotency key {}", key);
+            Ok(Some(replay(stored)))
+        }
+        None => Err(IdempotencyError::InProgress),
+    }
+}
+
+/// Save a successful response so retries replay it, and return it to the caller
+///
+/// Unsuccessful responses release the key instead, so the client can retry, and so
+/// do responses that can't be replayed: streams and bodies that are too large.
+pub async fn complete(
+    storage: &dyn Storage,
+    key: &str,
+    fingerprint: &str,
+    response: Response,
+    ttl_secs: u64,
+) -> Response {
+    if !response.status().is_success() || !is_replayable(&response) {
+        abandon(storage, key).await;
+        return response;
+    }
+
+    // Buffer the body so it can be stored and still returned
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_STORED_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error buffering idempotent response: {}", e);
+            abandon(storage, key).await;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut record = IdempotencyRecord::in_progress(fingerprint.to_string());
+    record.response = Some(StoredResponse {
+        status: parts.status.as_u16(),
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        body: body.to_vec(),
+    });
+
+    if let Err(e) = storage
+        .complete_idempotent_request(key, &record, ttl_secs)
+        .await
+    {
+        error!("Error saving idempotent response: {}", e);
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Middleware making authenticated routes idempotent per user
+///
+/// A request carrying an `Idempotency-Key` that was already answered gets the
+/// original response replayed without reaching the handler, so it isn't charged
+/// again. Streams (server-sent events, WebSockets and metered responses) are
+/// passed through, as they can't be replayed.
+pub async fn idempotent_requests(
+    State(state): State<AppState>,
+    UserId(user_id): UserId,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = match idempotency_key(request.headers()) {
+        Ok(Some(key)) => key,
+        Ok(None) => return next.run(request).await,
+        Err(e) => return e.into_response(),
+    };
+
+    let metered = request
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| {
+            state
+                .config
+                .pricing
+                .route_metering(request.method().as_str(), route.as_str())
+        })
+        .is_some();
+    if metered || opens_stream(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_STORED_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| parts.uri.path());
+    let key = format!("route:{}:{}", user_id, key);
+    let fingerprint = fingerprint(&[parts.method.as_str().as_bytes(), path.as_bytes(), &body]);
+
+    let storage = state.storage.as_ref();
+    let ttl_secs = state.config.idempotency_ttl_secs;
+    let lease_secs = state.config.idempotency_lease_secs;
+    match begin(storage, &key, &fingerprint, lease_secs).await {
+        Ok(Some(replayed)) => return replayed,
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    complete(storage, &key, &fingerprint, response, ttl_secs).await
+}
+
+/// Release the key of a request that failed, so the client can retry it
+pub async fn abandon(storage: &dyn Storage, key: &str) {
+    if let Err(e) = storage.abandon_idempotent_request(key).await {
+        error!("Error releasing idempotency key: {}", e);
+    }
+}
+
+/// Whether a request asks for a WebSocket or an event stream
+fn opens_stream(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        || headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains(EVENT_STREAM_CONTENT_TYPE))
+}
+
+/// Whether a response can be stored and replayed as a whole
+fn is_replayable(response: &Response) -> bool {
+    let stream = response.status() == StatusCode::SWITCHING_PROTOCOLS
+        || response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(EVENT_STREAM_CONTENT_TYPE));
+
+    // Bodies of unknown length may be streams that never end
+    let size = http_body::Body::size_hint(response.body()).upper();
+    !stream && size.is_some_and(|size| size <= MAX_STORED_BODY_BYTES as u64)
+}
+
+/// Rebuild a stored response, marked as replayed
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+
+    let headers = response.headers_mut();
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    response
+}
@@ -1,5 +1,6 @@
-mod auth;
+pub(crate) mod auth;
 mod handlers;
+mod idempotency;
 mod routes;
 
 pub use routes::create_router;
@@ -1,14 +1,19 @@
-use crate::api::handlers;
+use crate::api::{handlers, idempotency};
 use crate::config::Config;
+use crate::gateway::Gateway;
 use crate::payments::PaymentService;
+use crate::paywall::{L402Layer, REQUEST_ID_HEADER};
 use crate::services::BlockService;
-use crate::storage::RedisStorage;
+use crate::storage::Storage;
 use axum::{
     Router,
-    routing::{get, post},
+    extract::Request,
+    middleware,
+    routing::{any, get, post},
 };
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
 
 /// Create the API router with all routes
@@ -16,14 +21,14 @@ use tower_http::trace::TraceLayer;
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
-    pub storage: RedisStorage,
+    pub storage: Arc<dyn Storage>,
     pub payment_service: PaymentService,
     pub block_service: BlockService,
 }
 
 pub fn create_router(
     config: Arc<Config>,
-    storage: RedisStorage,
+    storage: Arc<dyn Storage>,
     payment_service: PaymentService,
     block_service: BlockService,
 ) -> Router {
@@ -33,34 +38,85 @@ pub fn create_router(
         .allow_methods(Any)
         .allow_headers(Any);
 
+    let state = AppState {
+        config,
+        storage,
+        payment_service,
+        block_service,
+    };
+
     // Public routes that don't require authentication
     let public_routes = Router::new()
         .route("/signup", get(handlers::signup))
         .route("/l402/payment-request", post(handlers::initiate_payment))
         .route("/webhook/lightning", post(handlers::lightning_webhook))
-        .route("/webhook/coinbase", post(handlers::coinbase_webhook));
+        .route("/webhook/coinbase", post(handlers::coinbase_webhook))
+        // Authorization checks for proxies in front of other services, which
+        // authenticate and charge the callers themselves
+        .route(handlers::AUTHZ_PATH, any(handlers::authorize))
+        .route(
+            &format!("{}/{{*path}}", handlers::AUTHZ_PATH),
+            any(handlers::authorize),
+        )
+        // Token checks for backend services, authenticated with a service key
+        .route("/introspect", post(handlers::introspect_token))
+        // Balance corrections for operators, authenticated with an admin key
+        .route(
+            "/admin/users/{user_id}/credits",
+            post(handlers::adjust_user_credits),
+        );
 
-    // Protected routes that require authentication
+    // Protected routes that require authentication, charged according to the
+    // pricing table and replayed for retried requests carrying an Idempotency-Key
+    // so they aren't charged twice
     let protected_routes = Router::new()
         .route("/info", get(handlers::get_user_info))
         .route("/block", get(handlers::get_latest_block))
+        .route("/events", get(handlers::get_event_stream))
+        .route("/feeds/blocks", get(handlers::get_block_feed))
+        .route("/usage", get(handlers::get_usage))
+        .route(
+            "/l402/payment-request/{id}",
+            get(handlers::get_payment_status),
+        )
         .route(
             "/credits-payment-options",
             get(handlers::get_payment_options),
-        );
-
-    let state = AppState {
-        config,
-        storage,
-        payment_service,
-        block_service,
-    };
+        )
+        .route_layer(L402Layer::new(
+            state.config.pricing.clone(),
+            state.storage.clone(),
+            state.payment_service.clone(),
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::idempotent_requests,
+        ));
 
     // Combine all routes with shared state
-    Router::new()
-        .merge(public_routes)
-        .merge(protected_routes)
+    let mut router = Router::new().merge(public_routes).merge(protected_routes);
+
+    // Anything else is forwarded to the gateway services, if any are configured
+    let gateway = Gateway::new(state.config.gateway_services.clone());
+    if !gateway.is_empty() {
+        router = router
+            .fallback_service(gateway.router(state.storage.clone(), state.payment_service.clone()));
+    }
+
+    router
         .layer(TraceLayer::new_for_http())
         .layer(cors)
+        // Tag every request with a fresh X-Request-Id, echoed in the response and
+        // used to reference ledger entries. Clients' own IDs are dropped so they
+        // can't choose what their entries are recorded against.
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(middleware::map_request(strip_request_id))
         .with_state(state)
 }
+
+/// Remove any X-Request-Id the client sent, so one is always generated
+async fn strip_request_id(mut request: Request) -> Request {
+    request.headers_mut().remove(REQUEST_ID_HEADER);
+    request
+}
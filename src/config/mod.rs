@@ -1,8 +1,9 @@
 use dotenvy::dotenv;
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::num::NonZeroU64;
 use std::sync::Arc;
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// Represents a credit purchase offer
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +22,242 @@ pub struct Offer {
     pub currency: String,
 }
 
+/// What metered responses are charged for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterUnit {
+    /// Bytes of response body sent
+    Bytes,
+    /// Seconds spent streaming the response body
+    Seconds,
+}
+
+/// Charging for a response by its size or streaming time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metering {
+    /// What is counted
+    pub unit: MeterUnit,
+    /// Bytes or seconds covered by each credit, never 0
+    pub per_credit: NonZeroU64,
+    /// Credits held ahead at a time while streaming, unused ones are refunded
+    #[serde(default = "default_hold_credits")]
+    pub hold_credits: u32,
+}
+
+fn default_hold_credits() -> u32 {
+    1
+}
+
+/// Credits charged for a request to a route
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutePrice {
+    /// HTTP method (e.g., "GET")
+    pub method: String,
+    /// Route path as registered with the router (e.g., "/block" or "/items/{id}")
+    pub route: String,
+    /// Credits charged per successful request, 0 for a free route
+    pub credits: u32,
+    /// Additional charge for the response body, by size or streaming time, on top of `credits`
+    #[serde(default)]
+    pub metering: Option<Metering>,
+}
+
+/// Credits charged per route; routes not listed are free
+///
+/// Routes are matched the way axum matches them, so a route like `/items/{id}`
+/// prices both the route as registered and paths like `/items/42`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<RoutePrice>", into = "Vec<RoutePrice>")]
+pub struct PricingTable {
+    /// Prices in the order they were configured
+    prices: Vec<RoutePrice>,
+    /// Each route, with the positions of its prices (one per method)
+    routes: matchit::Router<Vec<usize>>,
+}
+
+impl PricingTable {
+    /// Credits charged for a request to a route, `None` if the route is free
+    pub fn route_price(&self, method: &str, route: &str) -> Option<u32> {
+        self.find(method, route)
+            .map(|price| price.credits)
+            .filter(|credits| *credits > 0)
+    }
+
+    /// How responses of a route are metered, `None` if they aren't
+    pub fn route_metering(&self, method: &str, route: &str) -> Option<Metering> {
+        self.find(method, route)
+            .and_then(|price| price.metering.clone())
+    }
+
+    /// The price for a method and a route or path
+    fn find(&self, method: &str, route: &str) -> Option<&RoutePrice> {
+        let positions = self.routes.at(route).ok()?.value;
+        positions
+            .iter()
+            .map(|position| &self.prices[*position])
+            .find(|price| price.method.eq_ignore_ascii_case(method))
+    }
+}
+
+impl TryFrom<Vec<RoutePrice>> for PricingTable {
+    type Error = matchit::InsertError;
+
+    fn try_from(prices: Vec<RoutePrice>) -> Result<Self, Self::Error> {
+        let mut positions: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (position, price) in prices.iter().enumerate() {
+            match positions
+                .iter_mut()
+                .find(|(route, _)| *route == price.route)
+            {
+                Some((_, route_positions)) => route_positions.push(position),
+                None => positions.push((&price.route, vec![position])),
+            }
+        }
+
+        let mut routes = matchit::Router::new();
+        for (route, route_positions) in positions {
+            routes.insert(route, route_positions)?;
+        }
+
+        Ok(Self { prices, routes })
+    }
+}
+
+impl From<PricingTable> for Vec<RoutePrice> {
+    fn from(table: PricingTable) -> Self {
+        table.prices
+    }
+}
+
+/// Restrictions on the requests a gateway service forwards
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceCaveats {
+    /// HTTP methods forwarded (e.g. ["GET"]), any method if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Largest request body forwarded, in bytes
+    #[serde(default)]
+    pub max_request_bytes: Option<u64>,
+    /// How long the upstream has to respond, in seconds
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+impl ServiceCaveats {
+    /// Whether requests with this method are forwarded
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+/// An upstream API served through the paywalled gateway
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayService {
+    /// Name of the service, used in logs
+    pub name: String,
+    /// Host the request must be addressed to (e.g. "api.example.com"), any host if unset
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Path prefix the request must start with (e.g. "/weather")
+    pub path_prefix: String,
+    /// Base URL requests are forwarded to (e.g. "http://127.0.0.1:9000")
+    pub upstream_url: String,
+    /// Whether to remove the path prefix before forwarding
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Credits charged per successful request, 0 for a free service
+    pub credits: u32,
+    /// Restrictions on the requests forwarded
+    #[serde(default)]
+    pub caveats: ServiceCaveats,
+    /// Additional charge for the response body, by size or streaming time
+    #[serde(default)]
+    pub metering: Option<Metering>,
+}
+
+impl GatewayService {
+    /// Whether a request to this host and path is served by this service
+    pub fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+
+        let prefix = self.path_prefix.trim_end_matches('/');
+        let path_matches = match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+
+        host_matches && path_matches
+    }
+
+    /// Upstream URL a request path and query are forwarded to
+    pub fn upstream_url(&self, path: &str, query: Option<&str>) -> String {
+        let path = if self.strip_prefix {
+            path.strip_prefix(self.path_prefix.trim_end_matches('/'))
+                .unwrap_or(path)
+        } else {
+            path
+        };
+
+        let mut url = format!("{}{}", self.upstream_url.trim_end_matches('/'), path);
+        if let Some(query) = query {
+            url.push('?');
+            url.push_str(query);
+        }
+        url
+    }
+}
+
+/// How a metered WebSocket feed is billed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedBilling {
+    /// Charge for every message sent
+    Message,
+    /// Charge for every minute connected, each minute paid at its start
+    Minute,
+}
+
+/// Pricing of metered WebSocket feeds
+#[derive(Debug, Clone)]
+pub struct FeedPricing {
+    /// What is charged for
+    pub billing: FeedBilling,
+    /// Credits charged per message or minute
+    pub credits: u32,
+    /// Balance at or below which subscribers are warned to top up
+    pub warn_credits: u32,
+}
+
+/// What to do with a payment that arrives after its payment request expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LatePaymentPolicy {
+    /// Credit the user as if the payment had arrived in time
+    Credit,
+    /// Don't credit the user and flag the payment for a refund
+    Refund,
+}
+
+/// How to credit a Coinbase charge paid for more or less than it asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MispaymentPolicy {
+    /// Credit in proportion to the amount actually paid
+    ProRata,
+    /// Credit the full offer regardless of the amount paid
+    Full,
+    /// Don't credit automatically, hold the payment for manual review
+    Hold,
+}
+
 /// Global application configuration
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -30,8 +267,14 @@ pub struct Config {
     pub port: u16,
     /// Redis connection URL
     pub redis_url: String,
+    /// SQL database URL, used for storage instead of Redis when set
+    pub database_url: Option<String>,
     /// URL for payment requests
     pub payment_request_url: Option<String>,
+    /// Secret used to sign payment context tokens
+    pub payment_context_secret: String,
+    /// How long a payment context token stays valid, in seconds
+    pub payment_context_ttl_secs: u64,
     /// Whether Lightning payments are enabled
     pub lightning_enabled: bool,
     /// LNBits URL (if using LNBits)
@@ -40,17 +283,52 @@ pub struct Config {
     pub lnbits_admin_key: Option<String>,
     /// LNBits invoice read key (if using LNBits)
     pub lnbits_invoice_read_key: Option<String>,
-    /// LNBits webhook url
-    #[allow(dead_code)]
+    /// Public URL of the Lightning webhook endpoint, passed to LNBits for each invoice
     pub lnbits_webhook_url: Option<String>,
+    /// Whether to subscribe to the LNBits real-time payment stream
+    pub lnbits_payment_stream: bool,
     /// Whether Coinbase payments are enabled
     pub coinbase_enabled: bool,
     /// Coinbase Commerce API key (if applicable)
     pub coinbase_api_key: Option<String>,
     /// Coinbase webhook secret for verification (if applicable)
     pub coinbase_webhook_secret: Option<String>,
+    /// Policy for underpaid and overpaid Coinbase charges
+    pub coinbase_mispayment_policy: MispaymentPolicy,
+    /// Maximum number of pending payments checked per reconciler tick
+    pub settlement_batch_size: usize,
+    /// Maximum number of concurrent settlement checks against the backend
+    pub settlement_max_concurrency: usize,
+    /// Delay before the first settlement check of a new invoice, in seconds
+    pub settlement_min_interval_secs: u64,
+    /// Upper bound for the delay between settlement checks, in seconds
+    pub settlement_max_interval_secs: u64,
+    /// Webhook events older than this are rejected, in seconds
+    pub webhook_max_event_age_secs: u64,
+    /// How long processed webhook event IDs are remembered, in seconds
+    pub webhook_event_retention_secs: u64,
+    /// How long Idempotency-Key responses are kept, in seconds
+    pub idempotency_ttl_secs: u64,
+    /// How long an Idempotency-Key is reserved by a request in progress, in seconds
+    pub idempotency_lease_secs: u64,
+    /// How often pending payment requests are checked for expiry, in seconds
+    pub expiry_sweep_interval_secs: u64,
+    /// How often balances are verified against the credit ledger, in seconds (0 disables)
+    pub ledger_verify_interval_secs: u64,
+    /// Policy applied to payments received after expiry
+    pub late_payment_policy: LatePaymentPolicy,
     /// Available credit purchase offers
     pub offers: Vec<Offer>,
+    /// Credits charged per route
+    pub pricing: PricingTable,
+    /// Upstream APIs served through the paywalled gateway
+    pub gateway_services: Vec<GatewayService>,
+    /// Keys backend services authenticate to the introspection API with
+    pub service_keys: Vec<String>,
+    /// Keys operators authenticate to the admin API with
+    pub admin_keys: Vec<String>,
+    /// Pricing of the WebSocket feeds
+    pub feed_pricing: FeedPricing,
 }
 
 impl Config {
@@ -86,6 +364,29 @@ impl Config {
         let offers: Vec<Offer> = serde_json::from_str(&offers_json)
             .expect("Failed to parse OFFERS_JSON environment variable");
 
+        // Parse route prices from JSON
+        let pricing_json = env::var("PRICING_JSON").unwrap_or_else(|_| {
+            // By default only the block endpoint is paid
+            r#"[
+                {
+                    "method": "GET",
+                    "route": "/block",
+                    "credits": 1
+                }
+            ]"#
+            .to_string()
+        });
+
+        let pricing: PricingTable = serde_json::from_str(&pricing_json)
+            .expect("Failed to parse PRICING_JSON environment variable");
+
+        // Parse gateway services from JSON, none by default
+        let gateway_services_json =
+            env::var("GATEWAY_SERVICES_JSON").unwrap_or_else(|_| "[]".to_string());
+
+        let gateway_services: Vec<GatewayService> = serde_json::from_str(&gateway_services_json)
+            .expect("Failed to parse GATEWAY_SERVICES_JSON environment variable");
+
         // Get configuration from environment
         let host = env::var("HOST").unwrap_or_else(|_| {
             debug!("HOST not found in environment, using default");
@@ -109,6 +410,11 @@ impl Config {
         });
         debug!("REDIS_URL: {}", redis_url);
 
+        let database_url = env::var("DATABASE_URL").ok().filter(|url| !url.is_empty());
+        if database_url.is_some() {
+            debug!("Found DATABASE_URL");
+        }
+
         let payment_request_url = env::var("PAYMENT_REQUEST_URL").ok();
         if let Some(url) = &payment_request_url {
             debug!("Found PAYMENT_REQUEST_URL: {}", url);
@@ -118,6 +424,30 @@ impl Config {
             );
         }
 
+        let payment_context_secret = match env::var("PAYMENT_CONTEXT_SECRET") {
+            Ok(secret) if !secret.is_empty() => {
+                debug!("Found PAYMENT_CONTEXT_SECRET");
+                secret
+            }
+            _ => {
+                warn!(
+                    "PAYMENT_CONTEXT_SECRET not set, using a random secret: payment context tokens won't survive restarts or be accepted by other instances"
+                );
+                crate::utils::generate_secret_token()
+            }
+        };
+        let payment_context_ttl_secs = parse_env_or("PAYMENT_CONTEXT_TTL_SECS", 30 * 60);
+
+        let service_keys = parse_key_list("SERVICE_KEYS");
+        if service_keys.is_empty() {
+            debug!("SERVICE_KEYS not set, the introspection API is disabled");
+        }
+
+        let admin_keys = parse_key_list("ADMIN_KEYS");
+        if admin_keys.is_empty() {
+            debug!("ADMIN_KEYS not set, the admin API is disabled");
+        }
+
         let lightning_enabled = env::var("LIGHTNING_ENABLED")
             .map(|val| {
                 debug!("Found LIGHTNING_ENABLED in environment: {}", val);
@@ -146,6 +476,16 @@ impl Config {
             debug!("Found LNBITS_WEBHOOK_URL");
         }
 
+        let lnbits_payment_stream = env::var("LNBITS_PAYMENT_STREAM")
+            .map(|val| {
+                debug!("Found LNBITS_PAYMENT_STREAM in environment: {}", val);
+                val.parse().unwrap_or(true)
+            })
+            .unwrap_or_else(|_| {
+                debug!("LNBITS_PAYMENT_STREAM not found in environment, using default: true");
+                true
+            });
+
         let coinbase_enabled = env::var("COINBASE_ENABLED")
             .map(|val| {
                 debug!("Found COINBASE_ENABLED in environment: {}", val);
@@ -165,20 +505,99 @@ impl Config {
             debug!("Found COINBASE_WEBHOOK_SECRET");
         }
 
+        let coinbase_mispayment_policy = match env::var("COINBASE_MISPAYMENT_POLICY") {
+            Ok(val) => {
+                debug!("Found COINBASE_MISPAYMENT_POLICY in environment: {}", val);
+                serde_json::from_value(serde_json::Value::String(val.to_lowercase()))
+                    .expect("COINBASE_MISPAYMENT_POLICY must be 'pro_rata', 'full' or 'hold'")
+            }
+            Err(_) => {
+                debug!("COINBASE_MISPAYMENT_POLICY not found in environment, using default: hold");
+                MispaymentPolicy::Hold
+            }
+        };
+
+        let settlement_batch_size = parse_env_or("SETTLEMENT_BATCH_SIZE", 50);
+        let settlement_max_concurrency = parse_env_or("SETTLEMENT_MAX_CONCURRENCY", 8);
+        let settlement_min_interval_secs = parse_env_or("SETTLEMENT_MIN_INTERVAL_SECS", 2);
+        let settlement_max_interval_secs = parse_env_or("SETTLEMENT_MAX_INTERVAL_SECS", 60);
+        let expiry_sweep_interval_secs = parse_env_or("EXPIRY_SWEEP_INTERVAL_SECS", 60);
+        let idempotency_ttl_secs = parse_env_or("IDEMPOTENCY_TTL_SECS", 24 * 60 * 60);
+        let idempotency_lease_secs = parse_env_or("IDEMPOTENCY_LEASE_SECS", 2 * 60);
+        let ledger_verify_interval_secs = parse_env_or("LEDGER_VERIFY_INTERVAL_SECS", 60 * 60);
+
+        // Coinbase retries deliveries for up to three days
+        let webhook_max_event_age_secs =
+            parse_env_or("WEBHOOK_MAX_EVENT_AGE_SECS", 3 * 24 * 60 * 60);
+        // Event IDs must be remembered for at least as long as events are accepted
+        let webhook_event_retention_secs =
+            parse_env_or("WEBHOOK_EVENT_RETENTION_SECS", 4 * 24 * 60 * 60)
+                .max(webhook_max_event_age_secs);
+
+        let feed_billing = match env::var("FEED_BILLING") {
+            Ok(val) => {
+                debug!("Found FEED_BILLING in environment: {}", val);
+                serde_json::from_value(serde_json::Value::String(val.to_lowercase()))
+                    .expect("FEED_BILLING must be either 'message' or 'minute'")
+            }
+            Err(_) => {
+                debug!("FEED_BILLING not found in environment, using default: minute");
+                FeedBilling::Minute
+            }
+        };
+        let feed_pricing = FeedPricing {
+            billing: feed_billing,
+            credits: parse_env_or("FEED_CREDITS", 1),
+            warn_credits: parse_env_or("FEED_WARN_CREDITS", 2),
+        };
+
+        let late_payment_policy = match env::var("LATE_PAYMENT_POLICY") {
+            Ok(val) => {
+                debug!("Found LATE_PAYMENT_POLICY in environment: {}", val);
+                serde_json::from_value(serde_json::Value::String(val.to_lowercase()))
+                    .expect("LATE_PAYMENT_POLICY must be either 'credit' or 'refund'")
+            }
+            Err(_) => {
+                debug!("LATE_PAYMENT_POLICY not found in environment, using default: credit");
+                LatePaymentPolicy::Credit
+            }
+        };
+
         Self {
             host,
             port,
             redis_url,
+            database_url,
             payment_request_url,
+            payment_context_secret,
+            payment_context_ttl_secs,
             lightning_enabled,
             lnbits_url,
             lnbits_admin_key,
             lnbits_invoice_read_key,
             lnbits_webhook_url,
+            lnbits_payment_stream,
             coinbase_enabled,
             coinbase_api_key,
             coinbase_webhook_secret,
+            coinbase_mispayment_policy,
+            settlement_batch_size,
+            settlement_max_concurrency,
+            settlement_min_interval_secs,
+            settlement_max_interval_secs,
+            webhook_max_event_age_secs,
+            webhook_event_retention_secs,
+            idempotency_ttl_secs,
+            idempotency_lease_secs,
+            expiry_sweep_interval_secs,
+            ledger_verify_interval_secs,
+            late_payment_policy,
             offers,
+            pricing,
+            gateway_services,
+            service_keys,
+            admin_keys,
+            feed_pricing,
         }
     }
 
@@ -187,6 +606,11 @@ impl Config {
         Arc::new(self)
     }
 
+    /// URL of the storage backend: the SQL database if configured, otherwise Redis
+    pub fn storage_url(&self) -> &str {
+        self.database_url.as_deref().unwrap_or(&self.redis_url)
+    }
+
     /// Get the payment request URL, or construct a default based on host and port
     pub fn get_payment_request_url(&self) -> String {
         self.payment_request_url
@@ -194,3 +618,96 @@ impl Config {
             .unwrap_or_else(|| format!("http://{}:{}/l402/payment-request", self.host, self.port))
     }
 }
+
+/// Parse a comma-separated list of keys from an environment variable, empty when it is unset
+fn parse_key_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|keys| {
+            keys.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parse a numeric environment variable, falling back to a default when it is unset
+fn parse_env_or<T>(name: &str, default: T) -> T
+where
+    T: std::str::FromStr + std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(val) => {
+            debug!("Found {} in environment: {}", name, val);
+            val.parse()
+                .unwrap_or_else(|_| panic!("{} must be a number", name))
+        }
+        Err(_) => {
+            debug!(
+                "{} not found in environment, using default: {}",
+                name, default
+            );
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pricing(json: &str) -> PricingTable {
+        serde_json::from_str(json).expect("pricing table parses")
+    }
+
+    #[test]
+    fn route_price_matches_templated_routes_and_paths() {
+        let table = pricing(
+            r#"[
+                {"method": "GET", "route": "/items/{id}", "credits": 2},
+                {"method": "GET", "route": "/items/featured", "credits": 5},
+                {"method": "POST", "route": "/items/{id}", "credits": 3}
+            ]"#,
+        );
+
+        assert_eq!(table.route_price("GET", "/items/{id}"), Some(2));
+        assert_eq!(table.route_price("GET", "/items/42"), Some(2));
+        assert_eq!(table.route_price("GET", "/items/featured"), Some(5));
+        assert_eq!(table.route_price("post", "/items/42"), Some(3));
+        assert_eq!(table.route_price("DELETE", "/items/42"), None);
+        assert_eq!(table.route_price("GET", "/items/42/reviews"), None);
+        assert_eq!(table.route_price("GET", "/other"), None);
+    }
+
+    #[test]
+    fn route_price_treats_zero_credits_as_free() {
+        let table = pricing(
+            r#"[{"method": "GET", "route": "/export", "credits": 0,
+                 "metering": {"unit": "bytes", "per_credit": 1024}}]"#,
+        );
+
+        assert_eq!(table.route_price("GET", "/export"), None);
+        assert!(table.route_metering("GET", "/export").is_some());
+    }
+
+    #[test]
+    fn pricing_tables_reject_conflicting_routes() {
+        let result = serde_json::from_str::<PricingTable>(
+            r#"[
+                {"method": "GET", "route": "/items/{id}", "credits": 1},
+                {"method": "GET", "route": "/items/{name}", "credits": 1}
+            ]"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn metering_rejects_zero_per_credit() {
+        let result = serde_json::from_str::<PricingTable>(
+            r#"[{"method": "GET", "route": "/export", "credits": 0,
+                 "metering": {"unit": "bytes", "per_credit": 0}}]"#,
+        );
+        assert!(result.is_err());
+    }
+}
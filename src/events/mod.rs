@@ -0,0 +1,186 @@
+use crate::models::PaymentStatus;
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::time::{self, Duration};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, warn};
+
+/// Number of events buffered per subscriber before old ones are dropped
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// Redis pub/sub channel events are shared on between server instances
+const EVENTS_CHANNEL: &str = "l402:events";
+
+/// Delay before resubscribing after the pub/sub connection dropped
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Events published when a user's payments or balance change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A payment request changed status (e.g., settled or expired)
+    PaymentStatusChanged {
+        /// The user who owns the payment request
+        user_id: String,
+        /// ID of the payment request
+        payment_id: String,
+        /// The new status
+        status: PaymentStatus,
+    },
+    /// A user's credit balance changed
+    BalanceChanged {
+        /// The user whose balance changed
+        user_id: String,
+        /// The new number of credits
+        credits: u32,
+    },
+}
+
+impl Event {
+    /// The user this event concerns
+    pub fn user_id(&self) -> &str {
+        match self {
+            Event::PaymentStatusChanged { user_id, .. } | Event::BalanceChanged { user_id, .. } => {
+                user_id
+            }
+        }
+    }
+
+    /// Short name of the event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PaymentStatusChanged { .. } => "payment_status_changed",
+            Event::BalanceChanged { .. } => "balance_changed",
+        }
+    }
+}
+
+/// Event bus for payment and balance updates
+///
+/// Events are delivered to subscribers in this process. When connected to Redis,
+/// they are relayed through pub/sub so subscribers on every server instance see
+/// them.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    redis: Option<ConnectionManager>,
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBus")
+            .field("redis", &self.redis.is_some())
+            .finish()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    /// Create an event bus local to this process
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        Self {
+            sender,
+            redis: None,
+        }
+    }
+
+    /// Create an event bus shared with other instances through Redis pub/sub
+    pub async fn connect(redis_url: &str) -> Result<Self, redis::RedisError> {
+        let client = redis::Client::open(redis_url)?;
+        let publisher = ConnectionManager::new(client.clone()).await?;
+
+        let bus = Self {
+            redis: Some(publisher),
+            ..Self::new()
+        };
+
+        let sender = bus.sender.clone();
+        tokio::spawn(async move { relay(client, sender).await });
+
+        Ok(bus)
+    }
+
+    /// Publish an event to all subscribers
+    pub async fn publish(&self, event: Event) {
+        if let Some(redis) = &self.redis {
+            let payload = match serde_json::to_string(&event) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Failed to serialize event: {}", e);
+                    return;
+                }
+            };
+
+            let mut conn = redis.clone();
+            let published: Result<(), _> = conn.publish(EVENTS_CHANNEL, payload).await;
+            match published {
+                // The relay delivers the event locally as well
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "Failed to publish event to Redis, delivering locally: {}",
+                    e
+                ),
+            }
+        }
+
+        self.deliver(event);
+    }
+
+    /// Subscribe to events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    fn deliver(&self, event: Event) {
+        deliver(&self.sender, event);
+    }
+}
+
+/// Hand an event to local subscribers
+fn deliver(sender: &broadcast::Sender<Event>, event: Event) {
+    // Sending only fails when nobody is listening, which is fine
+    if sender.send(event).is_err() {
+        debug!("Event published without subscribers");
+    }
+}
+
+/// Forward events from the Redis channel to local subscribers, resubscribing on errors
+async fn relay(client: redis::Client, sender: broadcast::Sender<Event>) {
+    loop {
+        match client.get_async_connection().await {
+            Ok(conn) => {
+                let mut pubsub = conn.into_pubsub();
+                if let Err(e) = pubsub.subscribe(EVENTS_CHANNEL).await {
+                    warn!("Failed to subscribe to event channel: {}", e);
+                } else {
+                    info!("Subscribed to event channel {}", EVENTS_CHANNEL);
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let event = message
+                            .get_payload::<String>()
+                            .map_err(|e| e.to_string())
+                            .and_then(|payload| {
+                                serde_json::from_str::<Event>(&payload).map_err(|e| e.to_string())
+                            });
+                        match event {
+                            Ok(event) => deliver(&sender, event),
+                            Err(e) => warn!("Ignoring malformed event: {}", e),
+                        }
+                    }
+                    warn!("Event channel subscription ended");
+                }
+            }
+            Err(e) => warn!("Failed to connect to Redis for events: {}", e),
+        }
+
+        time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
@@ -0,0 +1,304 @@
+//! Paywalled reverse proxy in front of upstream APIs
+//!
+//! Each [`GatewayService`] maps a host and path prefix to an upstream URL. Requests
+//! are charged through the [`L402Layer`] like the server's own routes, and
+//! forwarded with their bodies streamed in both directions.
+
+use crate::config::{GatewayService, Metering};
+use crate::paywall::{L402Layer, PaymentChallenge, Pricing};
+use crate::storage::Storage;
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header, uri::Authority},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info, warn};
+
+/// Headers that only apply to a single connection and are never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Header telling the upstream which host the client addressed
+const FORWARDED_HOST_HEADER: &str = "x-forwarded-host";
+
+/// Request body chunks buffered between the client and the upstream
+const BODY_BUFFER_CHUNKS: usize = 16;
+
+/// Routes requests to upstream services and forwards them
+#[derive(Clone)]
+pub struct Gateway {
+    services: Arc<[GatewayService]>,
+    client: reqwest::Client,
+}
+
+impl Gateway {
+    /// Create a gateway in front of the given services
+    pub fn new(services: Vec<GatewayService>) -> Self {
+        Self {
+            services: services.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether any services are configured
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Router forwarding every request to its service, charging for it first
+    ///
+    /// Meant as the fallback of the main router, so the server's own routes take
+    /// precedence over the services.
+    pub fn router(&self, storage: Arc<dyn Storage>, challenge: impl PaymentChallenge) -> Router {
+        Router::new()
+            .fallback(proxy)
+            .layer(L402Layer::new(self.clone(), storage, challenge))
+            .with_state(self.clone())
+    }
+
+    /// Service a request is routed to, the first one matching
+    pub fn route(&self, request: &Request) -> Option<&GatewayService> {
+        let host = request_host(request);
+        let path = request.uri().path();
+        self.services
+            .iter()
+            .find(|service| service.matches(host.as_deref(), path))
+    }
+
+    /// Forward a request to a service and stream back its response
+    async fn forward(&self, service: &GatewayService, request: Request) -> Response {
+        let url = service.upstream_url(request.uri().path(), request.uri().query());
+        let Ok(method) = reqwest::Method::from_bytes(request.method().as_str().as_bytes()) else {
+            return method_not_allowed();
+        };
+
+        let mut headers = upstream_headers(request.headers());
+        if let Some(host) = request_host(&request)
+            .and_then(|host| reqwest::header::HeaderValue::from_str(&host).ok())
+        {
+            headers.insert(FORWARDED_HOST_HEADER, host);
+        }
+
+        // reqwest needs a body it can share between threads, so chunks are relayed
+        // through a channel, cutting the body off once it passes the size limit
+        let (tx, rx) = mpsc::channel(BODY_BUFFER_CHUNKS);
+        let max_request_bytes = service.caveats.max_request_bytes;
+        let relay = tokio::spawn(async move {
+            let mut body = request.into_body().into_data_stream();
+            let mut received: u64 = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        let _ = tx.send(Err(std::io::Error::other(e))).await;
+                        return false;
+                    }
+                };
+                received += chunk.len() as u64;
+                if max_request_bytes.is_some_and(|max| received > max) {
+                    let _ = tx
+                        .send(Err(std::io::Error::other("request body too large")))
+                        .await;
+                    return true;
+                }
+                if tx.send(Ok(chunk)).await.is_err() {
+                    break;
+                }
+            }
+            false
+        });
+
+        let mut upstream = self
+            .client
+            .request(method, &url)
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(ReceiverStream::new(rx)));
+        if let Some(timeout_secs) = service.caveats.timeout_secs {
+            upstream = upstream.timeout(Duration::from_secs(timeout_secs));
+        }
+
+        match upstream.send().await {
+            Ok(upstream) => {
+                info!(
+                    "Forwarded request to service {}: {} {}",
+                    service.name,
+                    url,
+                    upstream.status()
+                );
+                let status = StatusCode::from_u16(upstream.status().as_u16())
+                    .unwrap_or(StatusCode::BAD_GATEWAY);
+                let headers = client_headers(upstream.headers());
+
+                let mut response = Body::from_stream(upstream.bytes_stream()).into_response();
+                *response.status_mut() = status;
+                *response.headers_mut() = headers;
+                response
+            }
+            Err(e) if e.is_timeout() => {
+                warn!("Service {} timed out on {}", service.name, url);
+                (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    Json(json!({"error": "Upstream service timed out"})),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                if relay.await.unwrap_or(false) {
+                    return payload_too_large();
+                }
+                error!(
+                    "Failed to forward request to service {}: {}",
+                    service.name, e
+                );
+                (
+                    StatusCode::BAD_GATEWAY,
+                    Json(json!({"error": "Upstream service unavailable"})),
+                )
+                    .into_response()
+            }
+        }
+    }
+}
+
+impl Pricing for Gateway {
+    /// Always `None`: which service a request goes to depends on its host, so
+    /// requests are only priced by [`Pricing::price_request`]
+    fn price(&self, _method: &Method, _route: &str) -> Option<u32> {
+        None
+    }
+
+    fn price_request(&self, request: &Request, _route: &str) -> Option<u32> {
+        // Requests the service would turn away aren't charged for
+        self.route(request)
+            .filter(|service| rejection(service, request).is_none())
+            .map(|service| service.credits)
+            .filter(|credits| *credits > 0)
+    }
+
+    fn metering(&self, request: &Request, _route: &str) -> Option<Metering> {
+        self.route(request)
+            .filter(|service| rejection(service, request).is_none())
+            .and_then(|service| service.metering.clone())
+    }
+}
+
+/// Forward a request to the service it is routed to
+async fn proxy(State(gateway): State<Gateway>, request: Request) -> Response {
+    let Some(service) = gateway.route(&request) else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+
+    if let Some(rejection) = rejection(service, &request) {
+        return rejection;
+    }
+
+    gateway.forward(service, request).await
+}
+
+/// Response for a request breaking a service's caveats, if it does
+fn rejection(service: &GatewayService, request: &Request) -> Option<Response> {
+    if !service.caveats.allows_method(request.method().as_str()) {
+        return Some(method_not_allowed());
+    }
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let (Some(max), Some(length)) = (service.caveats.max_request_bytes, content_length)
+        && length > max
+    {
+        return Some(payload_too_large());
+    }
+
+    None
+}
+
+fn method_not_allowed() -> Response {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        Json(json!({"error": "Method not allowed"})),
+    )
+        .into_response()
+}
+
+fn payload_too_large() -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({"error": "Request body too large"})),
+    )
+        .into_response()
+}
+
+/// Host the client addressed, without the port or the brackets of an IPv6 address
+fn request_host(request: &Request) -> Option<String> {
+    let authority: Authority = match request.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?.parse().ok()?,
+        None => request.uri().authority()?.clone(),
+    };
+    let host = authority.host();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some(host.to_string())
+}
+
+/// Whether a header is passed on through the gateway
+fn is_forwarded(name: &str) -> bool {
+    !HOP_BY_HOP_HEADERS.contains(&name)
+}
+
+/// Client headers sent upstream
+///
+/// The client's credentials are for the gateway and stay here, and the upstream
+/// gets its own Host header.
+fn upstream_headers(headers: &HeaderMap) -> reqwest::header::HeaderMap {
+    let mut forwarded = reqwest::header::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if !is_forwarded(name.as_str()) || name == header::HOST || name == header::AUTHORIZATION {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(name.as_ref()),
+            reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            forwarded.append(name, value);
+        }
+    }
+    forwarded
+}
+
+/// Upstream response headers sent to the client
+fn client_headers(headers: &reqwest::header::HeaderMap) -> HeaderMap {
+    let mut forwarded = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if !is_forwarded(name.as_str()) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_ref()),
+            HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            forwarded.append(name, value);
+        }
+    }
+    forwarded
+}
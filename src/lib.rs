@@ -1,7 +1,10 @@
 pub mod api;
 pub mod config;
+pub mod events;
+pub mod gateway;
 pub mod models;
 pub mod payments;
+pub mod paywall;
 pub mod services;
 pub mod storage;
 pub mod utils;
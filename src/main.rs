@@ -1,17 +1,20 @@
 mod api;
 mod config;
+mod events;
+mod gateway;
 mod models;
 mod payments;
+mod paywall;
 mod services;
 mod storage;
 mod utils;
 
 use anyhow::Result;
 use config::Config;
+use events::EventBus;
 use payments::PaymentService;
-use services::BlockService;
-use storage::RedisStorage;
-use tracing::{error, info};
+use services::{BlockService, LedgerService};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -26,31 +29,52 @@ async fn main() -> Result<()> {
     let config = Config::from_env();
     info!("Server will run on {}:{}", config.host, config.port);
 
-    // Initialize Redis storage
-    let redis_url = config.redis_url.clone();
-    let storage = match RedisStorage::new(&redis_url) {
-        Ok(storage) => {
-            info!("Connected to Redis at {}", redis_url);
-            storage
+    // Initialize the event bus, shared with other instances through Redis whenever
+    // a Redis server is configured, whatever the storage backend
+    let storage_url = config.storage_url().to_string();
+    let events = if storage::is_redis_url(&config.redis_url) {
+        match EventBus::connect(&config.redis_url).await {
+            Ok(events) => events,
+            Err(e) if storage::is_redis_url(&storage_url) => {
+                error!("Failed to connect event bus to Redis: {}", e);
+                return Err(anyhow::anyhow!("{}", e));
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect event bus to Redis, events will NOT reach other instances: {}",
+                    e
+                );
+                EventBus::new()
+            }
         }
+    } else {
+        warn!("No Redis server configured, events will NOT reach other instances");
+        EventBus::new()
+    };
+
+    // Initialize storage
+    let storage = match storage::open(&storage_url, events.clone()).await {
+        Ok(storage) => storage,
         Err(e) => {
-            error!("Failed to connect to Redis: {}", e);
+            error!("Failed to open storage: {}", e);
             return Err(anyhow::anyhow!("{}", e));
         }
     };
 
-    // Check Redis connection
+    // Check the storage connection
     if let Err(e) = storage.check_connection().await {
-        error!("Redis connection test failed: {}", e);
+        error!("Storage connection test failed: {}", e);
         return Err(anyhow::anyhow!("{}", e));
     }
+    info!("Storage connection established");
 
     // Create shared config
     let config_arc = config.into_arc();
 
     // Initialize payment service
     let mut payment_service =
-        PaymentService::new_without_providers(config_arc.clone(), storage.clone());
+        PaymentService::new_without_providers(config_arc.clone(), storage.clone())
+            .with_event_bus(events);
     match payment_service.init_providers() {
         Ok(_) => info!("Payment service initialized"),
         Err(e) => {
@@ -58,9 +82,22 @@ async fn main() -> Result<()> {
             return Err(anyhow::anyhow!("{}", e));
         }
     }
+    payment_service.start_reconciler();
+    payment_service.start_expiry_sweeper();
+    match payment_service.resume_pending_payments().await {
+        Ok(resumed) => info!("Resumed tracking of {} pending payments", resumed),
+        Err(e) => error!("Failed to resume pending payments: {}", e),
+    }
+
+    // Periodically check that balances still match the credit ledger
+    if config_arc.ledger_verify_interval_secs > 0 {
+        LedgerService::new(storage.clone()).start_verifier(config_arc.ledger_verify_interval_secs);
+    }
 
     // Initialize block service
-    let block_service = BlockService::new(storage.clone());
+    let block_service = BlockService::new();
+    // Poll new blocks for WebSocket feed subscribers
+    block_service.start_feed();
     info!("Block service initialized");
 
     // Create the router
@@ -1,4 +1,4 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -28,6 +28,65 @@ impl User {
     }
 }
 
+/// Why a user's credits changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreditReason {
+    /// Free credits, such as the signup bonus
+    Grant,
+    /// Credits bought with a settled payment
+    Purchase,
+    /// Credits used by a paid request
+    Spend,
+    /// Credits returned for a paid request that failed
+    Refund,
+    /// Manual correction by an operator
+    Adjustment,
+    /// Credits set aside for a response while it is sent
+    Hold,
+    /// Held credits returned once the response is settled
+    Release,
+}
+
+impl CreditReason {
+    /// Name of the reason, as stored in the ledger
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CreditReason::Grant => "grant",
+            CreditReason::Purchase => "purchase",
+            CreditReason::Spend => "spend",
+            CreditReason::Refund => "refund",
+            CreditReason::Adjustment => "adjustment",
+            CreditReason::Hold => "hold",
+            CreditReason::Release => "release",
+        }
+    }
+}
+
+/// An immutable record of a change to a user's credits
+///
+/// A user's balance is always the sum of the amounts of their entries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Sequence number, increasing in the order entries were written
+    pub id: u64,
+    /// The user whose credits changed
+    pub user_id: String,
+    /// Credits added (positive) or removed (negative)
+    pub amount: i64,
+    /// Balance after the change
+    pub balance: u32,
+    /// Why the credits changed
+    pub reason: CreditReason,
+    /// Payment request or API request that caused the change
+    pub reference: Option<String>,
+    /// API route the credits were spent on or refunded for
+    #[serde(default)]
+    pub route: Option<String>,
+    /// When the change happened
+    pub created_at: DateTime<Utc>,
+}
+
 /// Payment methods supported by the service
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -48,6 +107,41 @@ pub enum PaymentStatus {
     Paid,
     /// Payment has expired without being paid
     Expired,
+    /// Payment arrived after expiry and must be refunded instead of credited
+    #[serde(rename = "refund_due")]
+    RefundDue,
+    /// Payment amount didn't match and is held for manual review
+    #[serde(rename = "on_hold")]
+    OnHold,
+}
+
+impl PaymentStatus {
+    /// Name of the status, as used in serialized payment requests
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::Paid => "paid",
+            PaymentStatus::Expired => "expired",
+            PaymentStatus::RefundDue => "refund_due",
+            PaymentStatus::OnHold => "on_hold",
+        }
+    }
+
+    /// Statuses a payment request can move to this status from
+    ///
+    /// A payment is settled (paid or flagged for refund) at most once.
+    pub fn reachable_from(&self) -> &'static [PaymentStatus] {
+        match self {
+            PaymentStatus::Pending => &[],
+            PaymentStatus::Paid | PaymentStatus::RefundDue => &[
+                PaymentStatus::Pending,
+                PaymentStatus::Expired,
+                PaymentStatus::OnHold,
+            ],
+            PaymentStatus::Expired => &[PaymentStatus::Pending],
+            PaymentStatus::OnHold => &[PaymentStatus::Pending, PaymentStatus::Expired],
+        }
+    }
 }
 
 /// Represents a payment request to purchase credits
@@ -61,14 +155,50 @@ pub struct PaymentRequest {
     pub offer_id: String,
     /// How many credits will be added when paid
     pub credits: u32,
+    /// Price of the offer in fiat currency
+    #[serde(default)]
+    pub amount: f64,
+    /// Fiat currency of the offer (e.g., "USD")
+    #[serde(default)]
+    pub currency: String,
+    /// Amount invoiced in satoshis (Lightning only)
+    #[serde(default)]
+    pub amount_sats: Option<u64>,
+    /// BTC/USD rate used to convert the price to satoshis (Lightning only)
+    #[serde(default)]
+    pub btc_usd_rate: Option<f64>,
+    /// BOLT11 invoice the user was asked to pay (Lightning only)
+    #[serde(default)]
+    pub lightning_invoice: Option<String>,
+    /// Fee charged by the Lightning backend for the invoice, in millisatoshis
+    #[serde(default)]
+    pub fee_msat: Option<u64>,
+    /// Amount actually received, in millisatoshis (Lightning only, set at settlement)
+    #[serde(default)]
+    pub amount_received_msat: Option<u64>,
+    /// Amount actually received in `currency` (Coinbase only, set at settlement)
+    #[serde(default)]
+    pub amount_received: Option<f64>,
     /// Current status of the payment
     pub status: PaymentStatus,
     /// Which payment method is being used
     pub method: PaymentMethod,
+    /// When the payment request was created
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
     /// When the payment request expires
     pub expires_at: DateTime<Utc>,
+    /// When the payment was settled
+    #[serde(default)]
+    pub settled_at: Option<DateTime<Utc>>,
     /// External payment reference (e.g., invoice ID, charge ID)
     pub external_id: Option<String>,
+    /// Secret token embedded in the per-invoice webhook URL
+    #[serde(default)]
+    pub webhook_token: Option<String>,
+    /// When a payment was received after the request had expired
+    #[serde(default)]
+    pub late_payment_at: Option<DateTime<Utc>>,
 }
 
 impl PaymentRequest {
@@ -85,16 +215,28 @@ impl PaymentRequest {
             user_id,
             offer_id,
             credits,
+            amount: 0.0,
+            currency: String::new(),
+            amount_sats: None,
+            btc_usd_rate: None,
+            lightning_invoice: None,
+            fee_msat: None,
+            amount_received_msat: None,
+            amount_received: None,
             status: PaymentStatus::Pending,
             method,
+            created_at: Utc::now(),
             expires_at,
+            settled_at: None,
             external_id: None,
+            webhook_token: None,
+            late_payment_at: None,
         }
     }
 }
 
 /// Request to initiate a payment
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PaymentRequestInput {
     /// ID of the offer to purchase
     pub offer_id: String,
@@ -108,29 +250,11 @@ pub struct PaymentRequestInput {
     pub asset: Option<String>,
 }
 
-/// Details for a Lightning payment
-#[derive(Debug, Serialize)]
-pub struct LightningPaymentDetails {
-    /// BOLT11 invoice string
-    pub lightning_invoice: String,
-}
-
-/// Details for a Coinbase payment
-#[derive(Debug, Serialize)]
-pub struct CoinbasePaymentDetails {
-    /// URL to the hosted checkout page
-    pub checkout_url: String,
-    /// Crypto address for direct payment (if available)
-    pub address: Option<String>,
-    /// Which asset to pay with (if specified)
-    pub asset: Option<String>,
-    /// Which blockchain to use (if specified)
-    pub chain: Option<String>,
-}
-
 /// Response for a payment request
 #[derive(Debug, Serialize)]
 pub struct PaymentRequestResponse {
+    /// ID of the payment request, used to query its status
+    pub id: String,
     /// Details specific to the payment method
     #[serde(flatten)]
     pub payment_request: PaymentRequestDetails,
@@ -162,6 +286,175 @@ pub enum PaymentRequestDetails {
     },
 }
 
+/// Status of a payment request, as returned to its owner
+#[derive(Debug, Serialize)]
+pub struct PaymentStatusResponse {
+    /// ID of the payment request
+    pub id: String,
+    /// Current status of the payment
+    pub status: PaymentStatus,
+    /// Which payment method is being used
+    pub method: PaymentMethod,
+    /// ID of the offer being purchased
+    pub offer_id: String,
+    /// How many credits are added when paid
+    pub credits: u32,
+    /// Amount invoiced in satoshis (Lightning only)
+    pub amount_sats: Option<u64>,
+    /// BOLT11 invoice to pay (Lightning only)
+    pub lightning_invoice: Option<String>,
+    /// Price in fiat currency
+    pub amount: f64,
+    /// Fiat currency of the price
+    pub currency: String,
+    /// When the payment request was created
+    pub created_at: DateTime<Utc>,
+    /// When the payment request expires
+    pub expires_at: DateTime<Utc>,
+    /// When the payment was settled
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+impl From<PaymentRequest> for PaymentStatusResponse {
+    fn from(request: PaymentRequest) -> Self {
+        Self {
+            id: request.id,
+            status: request.status,
+            method: request.method,
+            offer_id: request.offer_id,
+            credits: request.credits,
+            amount_sats: request.amount_sats,
+            lightning_invoice: request.lightning_invoice,
+            amount: request.amount,
+            currency: request.currency,
+            created_at: request.created_at,
+            expires_at: request.expires_at,
+            settled_at: request.settled_at,
+        }
+    }
+}
+
+/// A purchase, spend or refund of credits, as shown in a user's usage history
+#[derive(Debug, Serialize)]
+pub struct UsageEntry {
+    /// Ledger entry ID
+    pub id: u64,
+    /// What happened
+    #[serde(rename = "type")]
+    pub kind: CreditReason,
+    /// API route the credits were spent on or refunded for
+    pub route: Option<String>,
+    /// Credits added (positive) or removed (negative)
+    pub credits: i64,
+    /// Balance after the change
+    pub balance: u32,
+    /// Payment request that bought the credits
+    pub payment_id: Option<String>,
+    /// API request the credits were spent on or refunded for (its X-Request-Id)
+    pub request_id: Option<String>,
+    /// When it happened
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<LedgerEntry> for UsageEntry {
+    fn from(entry: LedgerEntry) -> Self {
+        let (payment_id, request_id) = match entry.reason {
+            CreditReason::Purchase => (entry.reference, None),
+            _ => (None, entry.reference),
+        };
+
+        Self {
+            id: entry.id,
+            kind: entry.reason,
+            route: entry.route,
+            credits: entry.amount,
+            balance: entry.balance,
+            payment_id,
+            request_id,
+            created_at: entry.created_at,
+        }
+    }
+}
+
+/// A page of a user's usage history
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    /// Entries, oldest first
+    pub entries: Vec<UsageEntry>,
+    /// Cursor for the next page, `None` on the last page
+    pub next_cursor: Option<u64>,
+}
+
+/// A backend service's question about a user's token
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    /// The bearer token the user presented (their user ID)
+    pub token: String,
+    /// Credits to spend from the user's balance if the token is valid
+    #[serde(default)]
+    pub debit: Option<u32>,
+    /// What the credits are spent on, recorded in the ledger
+    #[serde(default)]
+    pub route: Option<String>,
+}
+
+/// What a token gives access to
+#[derive(Debug, Serialize)]
+pub struct IntrospectionResponse {
+    /// Whether the token belongs to a user
+    pub active: bool,
+    /// The user the token belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Credits left, after any debit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credits: Option<u32>,
+    /// Restrictions on the token's use; bearer tokens carry none
+    pub caveats: Vec<String>,
+    /// When the token stops being valid; bearer tokens don't expire
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Credits spent by this request
+    pub debited: u32,
+}
+
+impl IntrospectionResponse {
+    /// Response for a token that doesn't belong to any user
+    pub fn inactive() -> Self {
+        Self {
+            active: false,
+            user_id: None,
+            credits: None,
+            caveats: Vec::new(),
+            expires_at: None,
+            debited: 0,
+        }
+    }
+
+    /// Response for a user's token
+    ///
+    /// Only bearer tokens (user IDs) are issued, which carry no caveats and don't expire.
+    pub fn active(user: &User, debited: u32) -> Self {
+        Self {
+            active: true,
+            user_id: Some(user.id.clone()),
+            credits: Some(user.credits),
+            caveats: Vec::new(),
+            expires_at: None,
+            debited,
+        }
+    }
+}
+
+/// An operator's correction to a user's balance
+#[derive(Debug, Deserialize)]
+pub struct CreditAdjustmentRequest {
+    /// Credits to add, or remove if negative
+    pub credits: i32,
+    /// Why the balance was corrected (e.g. a support ticket), recorded in the ledger
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
 /// Response for a 402 Payment Required status
 #[derive(Debug, Serialize)]
 pub struct PaymentRequiredResponse {
@@ -169,14 +462,78 @@ pub struct PaymentRequiredResponse {
     pub expiry: DateTime<Utc>,
     /// Available credit purchase options
     pub offers: Vec<crate::config::Offer>,
-    /// Token to identify the user in the payment flow
+    /// Signed, expiring token binding the payment to the user and the quoted offers
     pub payment_context_token: String,
     /// URL to initiate payment
     pub payment_request_url: String,
 }
 
+impl PaymentRequiredResponse {
+    /// The challenge as a `WWW-Authenticate` header value, for clients that only see headers
+    ///
+    /// The offers are left out; they are listed by `/credits-payment-options`.
+    pub fn www_authenticate(&self) -> String {
+        format!(
+            "L402 payment_context_token=\"{}\", payment_request_url=\"{}\", expiry=\"{}\"",
+            self.payment_context_token,
+            self.payment_request_url,
+            self.expiry.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )
+    }
+}
+
+/// A request made with an Idempotency-Key, and its response once completed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    /// Hash of the original request, to detect key reuse with a different body
+    pub fingerprint: String,
+    /// Response of the original request, `None` while it is still in progress
+    pub response: Option<StoredResponse>,
+    /// When the original request was received
+    pub created_at: DateTime<Utc>,
+}
+
+impl IdempotencyRecord {
+    /// Create a record for a request that is still in progress
+    pub fn in_progress(fingerprint: String) -> Self {
+        Self {
+            fingerprint,
+            response: None,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// A response saved for replay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// Content type of the body, if any
+    pub content_type: Option<String>,
+    /// Response body, base64 encoded when serialized so binary bodies survive
+    #[serde(with = "base64_bytes")]
+    pub body: Vec<u8>,
+}
+
+/// Serialize bytes as a base64 string
+mod base64_bytes {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
 /// Bitcoin block data
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockData {
     /// Block hash
     pub hash: String,
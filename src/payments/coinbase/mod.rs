@@ -1,6 +1,8 @@
 use crate::config::Config;
 use crate::models::PaymentRequestDetails;
+use crate::utils::constant_time_eq;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use hmac::{Hmac, Mac};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -95,7 +97,6 @@ struct ChargeData {
 #[derive(Debug, Deserialize)]
 struct PricingInfo {
     /// Amount in local currency
-    #[allow(dead_code)]
     local: Option<LocalPrice>,
 }
 
@@ -103,7 +104,6 @@ struct PricingInfo {
 #[derive(Debug, Deserialize)]
 struct LocalPrice {
     /// Amount in local currency
-    #[allow(dead_code)]
     amount: String,
     /// Currency code
     #[allow(dead_code)]
@@ -113,20 +113,100 @@ struct LocalPrice {
 /// Webhook event from Coinbase
 #[derive(Debug, Deserialize)]
 pub struct CoinbaseWebhookEvent {
+    /// Unique event ID, stable across delivery retries
+    id: String,
+    /// When the event was created
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
     /// Type of event
     #[serde(rename = "type")]
-    event_type: String,
+    event_type: ChargeEventType,
     /// Data field containing event details
     data: WebhookData,
 }
 
+/// Webhook delivery body, which wraps the event in an envelope
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WebhookPayload {
+    /// Delivery envelope as sent by Coinbase Commerce
+    Envelope { event: CoinbaseWebhookEvent },
+    /// Bare event
+    Event(CoinbaseWebhookEvent),
+}
+
+/// Charge lifecycle events sent by Coinbase Commerce
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum ChargeEventType {
+    /// The charge was created
+    #[serde(rename = "charge:created")]
+    Created,
+    /// A payment was detected but isn't confirmed yet
+    #[serde(rename = "charge:pending")]
+    Pending,
+    /// The charge was paid and the payment confirmed
+    #[serde(rename = "charge:confirmed")]
+    Confirmed,
+    /// The charge expired, was underpaid, or otherwise failed
+    #[serde(rename = "charge:failed")]
+    Failed,
+    /// A payment arrived after the charge expired
+    #[serde(rename = "charge:delayed")]
+    Delayed,
+    /// The merchant manually marked an unresolved charge as paid
+    #[serde(rename = "charge:resolved")]
+    Resolved,
+    /// Any event type this server doesn't act on
+    #[serde(other)]
+    Unknown,
+}
+
 /// Webhook data from Coinbase
 #[derive(Debug, Deserialize)]
 struct WebhookData {
     /// Charge ID
     id: String,
-    /// Status of the charge
+    /// Pricing the charge was created with
+    #[serde(default)]
+    pricing: Option<PricingInfo>,
+    /// Payments made towards the charge
+    #[serde(default)]
+    payments: Vec<ChargePayment>,
+}
+
+/// A single payment made towards a charge
+#[derive(Debug, Deserialize)]
+struct ChargePayment {
+    /// Payment status (e.g., "CONFIRMED", "PENDING")
     status: String,
+    /// Payment value in local and crypto currency
+    value: PaymentValue,
+}
+
+/// Value of a payment
+#[derive(Debug, Deserialize)]
+struct PaymentValue {
+    /// Value in the charge's local currency
+    local: LocalPrice,
+}
+
+/// What a charge event means for the payment request
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChargeOutcome {
+    /// No money has settled yet
+    Pending,
+    /// The charge failed without any confirmed payment
+    Failed,
+    /// Money has settled for the charge
+    Paid {
+        /// Amount confirmed, in the charge's local currency
+        amount_paid: f64,
+        /// Amount the charge asked for, if known
+        amount_due: Option<f64>,
+        /// Whether the charge counts as paid in full whatever was confirmed, so no
+        /// mispayment policy applies
+        paid_in_full: bool,
+    },
 }
 
 impl CoinbaseProvider {
@@ -219,6 +299,48 @@ impl CoinbaseProvider {
         Ok((charge.id, charge.hosted_url, usdc_address))
     }
 
+    /// Cancel an open charge so it can no longer be paid
+    ///
+    /// Coinbase only allows cancelling charges that haven't received a payment yet.
+    pub async fn cancel_charge(&self, charge_id: &str) -> Result<(), CoinbaseError> {
+        let api_key =
+            self.config.coinbase_api_key.as_ref().ok_or_else(|| {
+                CoinbaseError::ConfigError("Coinbase API key not configured".into())
+            })?;
+
+        let url = format!(
+            "https://api.commerce.coinbase.com/charges/{}/cancel",
+            charge_id
+        );
+
+        let response = self
+            .client
+            .post(&url)
+            .header("X-CC-Api-Key", api_key)
+            .header("X-CC-Version", "2018-03-22")
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            error!(
+                "Failed to cancel Coinbase charge: {} - {}",
+                status, error_text
+            );
+            return Err(CoinbaseError::ApiError(format!(
+                "Coinbase API error: {}",
+                error_text
+            )));
+        }
+
+        info!("Cancelled Coinbase charge: {}", charge_id);
+        Ok(())
+    }
+
     /// Verify a webhook signature from Coinbase
     pub fn verify_webhook(
         &self,
@@ -254,16 +376,81 @@ impl CoinbaseProvider {
         }
 
         // Parse the webhook event
-        let event: CoinbaseWebhookEvent =
+        let payload: WebhookPayload =
             serde_json::from_slice(body).map_err(CoinbaseError::SerializationError)?;
+        let event = match payload {
+            WebhookPayload::Envelope { event } | WebhookPayload::Event(event) => event,
+        };
 
         Ok(event)
     }
 
-    /// Check if a webhook event represents a completed payment
-    pub fn is_payment_completed(&self, event: &CoinbaseWebhookEvent) -> bool {
-        // Check if the event type is a charge with confirmed status
-        event.event_type == "charge:confirmed" && event.data.status == "CONFIRMED"
+    /// Work out what a webhook event means for the charge's payment request
+    pub fn charge_outcome(&self, event: &CoinbaseWebhookEvent) -> ChargeOutcome {
+        let amount_paid = self.amount_paid(event);
+        let amount_due = event
+            .data
+            .pricing
+            .as_ref()
+            .and_then(|pricing| pricing.local.as_ref())
+            .and_then(|local| local.amount.parse::<f64>().ok());
+
+        match event.event_type {
+            ChargeEventType::Created | ChargeEventType::Pending | ChargeEventType::Unknown => {
+                ChargeOutcome::Pending
+            }
+            // Coinbase only confirms charges that were paid in full, and the event can
+            // arrive before its payments are listed as confirmed
+            ChargeEventType::Confirmed if amount_paid <= 0.0 => ChargeOutcome::Paid {
+                amount_paid: amount_due.unwrap_or_default(),
+                amount_due,
+                paid_in_full: true,
+            },
+            ChargeEventType::Confirmed | ChargeEventType::Delayed => ChargeOutcome::Paid {
+                amount_paid,
+                amount_due,
+                paid_in_full: false,
+            },
+            // The merchant accepted the charge as paid in full
+            ChargeEventType::Resolved => ChargeOutcome::Paid {
+                amount_paid,
+                amount_due,
+                paid_in_full: true,
+            },
+            // An underpaid charge fails but still holds the customer's money
+            ChargeEventType::Failed if amount_paid > 0.0 => ChargeOutcome::Paid {
+                amount_paid,
+                amount_due,
+                paid_in_full: false,
+            },
+            ChargeEventType::Failed => ChargeOutcome::Failed,
+        }
+    }
+
+    /// Sum of confirmed payments on the charge, in its local currency
+    fn amount_paid(&self, event: &CoinbaseWebhookEvent) -> f64 {
+        event
+            .data
+            .payments
+            .iter()
+            .filter(|payment| payment.status.eq_ignore_ascii_case("CONFIRMED"))
+            .filter_map(|payment| payment.value.local.amount.parse::<f64>().ok())
+            .sum()
+    }
+
+    /// Get the unique ID of a webhook event
+    pub fn get_event_id<'a>(&self, event: &'a CoinbaseWebhookEvent) -> &'a str {
+        &event.id
+    }
+
+    /// Get the creation time of a webhook event, if present
+    pub fn get_event_time(&self, event: &CoinbaseWebhookEvent) -> Option<DateTime<Utc>> {
+        event.created_at
+    }
+
+    /// Get the type of a webhook event
+    pub fn get_event_type<'a>(&self, event: &'a CoinbaseWebhookEvent) -> &'a ChargeEventType {
+        &event.event_type
     }
 
     /// Get the charge ID from a webhook event
@@ -287,17 +474,3 @@ impl CoinbaseProvider {
         }
     }
 }
-
-/// Constant-time comparison of two byte slices
-fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-
-    let mut result = 0;
-    for (x, y) in a.iter().zip(b.iter()) {
-        result |= x ^ y;
-    }
-
-    result == 0
-}
@@ -0,0 +1,197 @@
+use crate::config::Offer;
+use crate::utils::constant_time_eq;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+/// Errors that can occur when verifying a payment context token
+#[derive(Debug, Error)]
+pub enum ContextError {
+    /// The token is not in the expected format
+    #[error("Malformed payment context token")]
+    Malformed,
+
+    /// The signature doesn't match the payload
+    #[error("Invalid payment context token signature")]
+    InvalidSignature,
+
+    /// The token is past its expiry
+    #[error("Payment context token expired at {0}")]
+    Expired(DateTime<Utc>),
+}
+
+/// Terms of an offer as quoted in a 402 response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OfferTerms {
+    /// Offer ID
+    pub id: String,
+    /// Number of credits the user will receive
+    pub credits: u32,
+    /// Quoted price
+    pub amount: f64,
+    /// Currency of the quoted price
+    pub currency: String,
+}
+
+impl From<&Offer> for OfferTerms {
+    fn from(offer: &Offer) -> Self {
+        Self {
+            id: offer.id.clone(),
+            credits: offer.credits,
+            amount: offer.amount,
+            currency: offer.currency.clone(),
+        }
+    }
+}
+
+impl OfferTerms {
+    /// Whether the offer is still available on these terms
+    pub fn matches(&self, offer: &Offer) -> bool {
+        *self == OfferTerms::from(offer)
+    }
+}
+
+/// What a payment context token vouches for: who is paying, for which offers,
+/// and until when
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentContext {
+    /// The user the 402 was issued to
+    pub user_id: String,
+    /// The offers quoted to the user
+    pub offers: Vec<OfferTerms>,
+    /// When the quote expires
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PaymentContext {
+    /// Create a context quoting the given offers to a user
+    pub fn new(user_id: String, offers: &[Offer], expires_at: DateTime<Utc>) -> Self {
+        Self {
+            user_id,
+            offers: offers.iter().map(OfferTerms::from).collect(),
+            expires_at,
+        }
+    }
+
+    /// Terms quoted for the given offer, if it was part of the quote
+    pub fn offer(&self, offer_id: &str) -> Option<&OfferTerms> {
+        self.offers.iter().find(|terms| terms.id == offer_id)
+    }
+
+    /// Encode the context as a signed token (`<payload>.<signature>`, base64url)
+    pub fn sign(&self, secret: &str) -> String {
+        // Serializing plain data with string keys can't fail
+        let payload = serde_json::to_vec(self).expect("payment context is serializable");
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = URL_SAFE_NO_PAD.encode(signature(secret, &payload));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Decode a signed token, checking its signature and expiry
+    pub fn verify(token: &str, secret: &str) -> Result<Self, ContextError> {
+        let (payload, provided) = token.split_once('.').ok_or(ContextError::Malformed)?;
+        let provided = URL_SAFE_NO_PAD
+            .decode(provided)
+            .map_err(|_| ContextError::Malformed)?;
+
+        // Check the signature before looking at the payload
+        if !constant_time_eq(&signature(secret, payload), &provided) {
+            return Err(ContextError::InvalidSignature);
+        }
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| ContextError::Malformed)?;
+        let context: PaymentContext =
+            serde_json::from_slice(&payload).map_err(|_| ContextError::Malformed)?;
+
+        if Utc::now() > context.expires_at {
+            return Err(ContextError::Expired(context.expires_at));
+        }
+
+        Ok(context)
+    }
+}
+
+/// HMAC-SHA256 of an encoded payload
+fn signature(secret: &str, payload: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn context(expires_at: DateTime<Utc>) -> PaymentContext {
+        let offers = [Offer {
+            id: "offer1".to_string(),
+            title: "10 credits".to_string(),
+            description: "10 credits for the API".to_string(),
+            credits: 10,
+            amount: 0.01,
+            currency: "USD".to_string(),
+        }];
+        PaymentContext::new("user".to_string(), &offers, expires_at)
+    }
+
+    #[test]
+    fn signed_contexts_verify() {
+        let token = context(Utc::now() + Duration::minutes(5)).sign("secret");
+
+        let verified = PaymentContext::verify(&token, "secret").unwrap();
+        assert_eq!(verified.user_id, "user");
+        assert_eq!(
+            verified.offer("offer1").map(|terms| terms.credits),
+            Some(10)
+        );
+        assert!(verified.offer("offer2").is_none());
+    }
+
+    #[test]
+    fn tokens_signed_with_another_secret_are_rejected() {
+        let token = context(Utc::now() + Duration::minutes(5)).sign("secret");
+
+        assert!(matches!(
+            PaymentContext::verify(&token, "other"),
+            Err(ContextError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let token = context(Utc::now() + Duration::minutes(5)).sign("secret");
+        let (_, signature) = token.split_once('.').unwrap();
+
+        let mut forged = context(Utc::now() + Duration::minutes(5));
+        forged.user_id = "someone-else".to_string();
+        let forged = forged.sign("secret");
+        let (payload, _) = forged.split_once('.').unwrap();
+
+        assert!(matches!(
+            PaymentContext::verify(&format!("{}.{}", payload, signature), "secret"),
+            Err(ContextError::InvalidSignature)
+        ));
+        assert!(matches!(
+            PaymentContext::verify("not-a-token", "secret"),
+            Err(ContextError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let token = context(Utc::now() - Duration::seconds(1)).sign("secret");
+
+        assert!(matches!(
+            PaymentContext::verify(&token, "secret"),
+            Err(ContextError::Expired(_))
+        ));
+    }
+}
@@ -1,12 +1,14 @@
 use crate::config::Config;
 use crate::models::PaymentRequestDetails;
 use crate::payments::lnbits::{CreateInvoiceRequest, LNBitsClient, LNBitsError};
-use crate::utils::ConversionError;
+use crate::utils::{ConversionError, constant_time_eq};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::Url;
 use serde::Deserialize;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::error;
+use tracing::debug;
 
 /// Errors that can occur when interacting with Lightning
 #[derive(Debug, Error)]
@@ -31,12 +33,39 @@ pub enum LightningError {
     /// Currency conversion error
     #[error("Currency conversion error: {0}")]
     ConversionError(#[from] ConversionError),
+
+    /// Invalid webhook
+    #[error("Invalid webhook: {0}")]
+    InvalidWebhook(String),
 }
 
 /// Lightning payment provider using LNBits
 #[derive(Clone)]
 pub struct LightningProvider {
     lnbits_client: Option<LNBitsClient>,
+    webhook_url: Option<Url>,
+}
+
+/// A newly created Lightning invoice
+#[derive(Debug, Clone)]
+pub struct Invoice {
+    /// BOLT11 invoice string
+    pub bolt11: String,
+    /// Payment hash identifying the invoice
+    pub payment_hash: String,
+    /// Fee charged by the backend, in millisatoshis
+    pub fee_msat: u64,
+}
+
+/// Settlement state of an invoice
+#[derive(Debug, Clone, Copy)]
+pub struct InvoiceStatus {
+    /// Whether the invoice has been paid
+    pub paid: bool,
+    /// Amount received, in millisatoshis
+    pub amount_msat: u64,
+    /// Fee charged by the backend, in millisatoshis
+    pub fee_msat: u64,
 }
 
 /// Invoice webhook event data from LNBits
@@ -44,6 +73,22 @@ pub struct LightningProvider {
 pub struct WebhookEvent {
     /// Payment hash
     pub payment_hash: String,
+    /// When the payment happened (unix timestamp or RFC 3339, depending on version)
+    #[serde(default)]
+    time: Option<serde_json::Value>,
+}
+
+impl WebhookEvent {
+    /// When the payment happened, if LNBits sent a parseable timestamp
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        match self.time.as_ref()? {
+            serde_json::Value::Number(n) => DateTime::from_timestamp(n.as_i64()?, 0),
+            serde_json::Value::String(s) => DateTime::parse_from_rfc3339(s)
+                .map(|t| t.with_timezone(&Utc))
+                .ok(),
+            _ => None,
+        }
+    }
 }
 
 impl LightningProvider {
@@ -75,15 +120,53 @@ impl LightningProvider {
             None
         };
 
-        Ok(Self { lnbits_client })
+        let webhook_url = config
+            .lnbits_webhook_url
+            .as_deref()
+            .map(|url| {
+                Url::parse(url).map_err(|e| {
+                    LightningError::ConfigError(format!("Invalid LNBits webhook URL: {}", e))
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            lnbits_client,
+            webhook_url,
+        })
+    }
+
+    /// The underlying LNBits client, if configured
+    pub fn lnbits_client(&self) -> Option<&LNBitsClient> {
+        self.lnbits_client.as_ref()
+    }
+
+    /// Whether invoices are created with a webhook callback
+    pub fn webhooks_enabled(&self) -> bool {
+        self.webhook_url.is_some()
+    }
+
+    /// Build the per-invoice webhook URL carrying the secret token
+    fn invoice_webhook_url(&self, webhook_token: &str) -> Option<String> {
+        self.webhook_url.as_ref().map(|base| {
+            let mut url = base.clone();
+            url.query_pairs_mut().append_pair("token", webhook_token);
+            url.to_string()
+        })
     }
 
     /// Create a Lightning invoice for the specified amount
+    ///
+    /// The invoice expires after `expiry_secs`, which should match the payment request.
+    /// When a webhook URL is configured, LNBits calls it back with `webhook_token`
+    /// in the query string once the invoice is paid.
     pub async fn create_invoice(
         &self,
         amount_sats: u64,
         memo: &str,
-    ) -> Result<(String, String), LightningError> {
+        expiry_secs: u32,
+        webhook_token: &str,
+    ) -> Result<Invoice, LightningError> {
         let client = self
             .lnbits_client
             .as_ref()
@@ -93,41 +176,75 @@ impl LightningProvider {
             amount: amount_sats,
             memo: Some(memo.to_owned()),
             unit: "sat".to_string(),
-            expiry: Some(1800), // 30 minutes
-            webhook: None,      // We'll use polling instead
+            expiry: Some(expiry_secs),
+            webhook: self.invoice_webhook_url(webhook_token),
             internal: false,
             out: false,
         };
 
         let invoice = client.create_invoice(&invoice_request).await?;
-        let payment_hash = invoice.payment_hash.clone();
 
-        Ok((invoice.bolt11, payment_hash))
+        Ok(Invoice {
+            bolt11: invoice.bolt11,
+            payment_hash: invoice.payment_hash,
+            fee_msat: invoice.fee,
+        })
     }
 
-    /// Check if an invoice has been paid
-    pub async fn check_invoice(&self, payment_hash: &str) -> Result<bool, LightningError> {
+    /// Check if an invoice has been paid, and how much was received
+    pub async fn check_invoice(&self, payment_hash: &str) -> Result<InvoiceStatus, LightningError> {
         let client = self
             .lnbits_client
             .as_ref()
             .ok_or_else(|| LightningError::ConfigError("LNBits not configured".to_string()))?;
 
-        let status = client.is_invoice_paid(payment_hash).await?;
-        Ok(status)
+        let payment = client.get_payment(payment_hash).await?;
+        Ok(InvoiceStatus {
+            paid: payment.paid,
+            amount_msat: payment.details.amount,
+            fee_msat: payment.details.fee,
+        })
     }
 
-    /// Verify a webhook signature and parse the event
-    pub fn verify_webhook(
-        &self,
-        body: &[u8],
-        _signature: &str,
-    ) -> Result<WebhookEvent, LightningError> {
-        // For now, we just parse the body without verifying the signature
-        // since LNBits doesn't provide webhook signatures
+    /// Cancel an unpaid invoice
+    ///
+    /// LNBits can't cancel regular BOLT11 invoices; they stay payable until their own
+    /// expiry, which is aligned with the payment request. Returns whether the
+    /// invoice was actually cancelled.
+    pub async fn cancel_invoice(&self, payment_hash: &str) -> Result<bool, LightningError> {
+        debug!(
+            "LNBits doesn't support invoice cancellation, {} will lapse on expiry",
+            payment_hash
+        );
+        Ok(false)
+    }
+
+    /// Parse a webhook body sent by LNBits
+    ///
+    /// LNBits doesn't sign webhooks, so the body must not be trusted on its own:
+    /// check the URL token with `verify_webhook_token` and confirm via `check_invoice`.
+    pub fn parse_webhook(&self, body: &[u8]) -> Result<WebhookEvent, LightningError> {
         let event: WebhookEvent = serde_json::from_slice(body)?;
         Ok(event)
     }
 
+    /// Verify the secret token from a per-invoice webhook URL
+    pub fn verify_webhook_token(
+        &self,
+        token: &str,
+        expected_token: Option<&str>,
+    ) -> Result<(), LightningError> {
+        let expected_token = expected_token.ok_or_else(|| {
+            LightningError::InvalidWebhook("No webhook registered for invoice".to_string())
+        })?;
+
+        if !constant_time_eq(token.as_bytes(), expected_token.as_bytes()) {
+            return Err(LightningError::InvalidWebhook("Invalid token".to_string()));
+        }
+
+        Ok(())
+    }
+
     /// Generate payment details for the client
     pub fn generate_payment_details(&self, invoice: &str) -> PaymentRequestDetails {
         PaymentRequestDetails::Lightning {
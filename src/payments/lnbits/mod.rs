@@ -7,6 +7,10 @@ use serde_json;
 use thiserror::Error;
 use tracing::{debug, error};
 
+mod stream;
+
+pub use stream::PaymentStreamListener;
+
 #[derive(Debug, Error)]
 pub enum LNBitsError {
     #[error("Network error: {0}")]
@@ -47,7 +51,6 @@ pub struct CreateInvoiceResponse {
     pub wallet_id: String,
     #[allow(dead_code)]
     pub amount: u64,
-    #[allow(dead_code)]
     pub fee: u64,
     pub bolt11: String,
     #[allow(dead_code)]
@@ -81,7 +84,6 @@ pub struct PaymentStatus {
     pub paid: bool,
     #[allow(dead_code)]
     pub preimage: Option<String>,
-    #[allow(dead_code)]
     pub details: PaymentDetails,
 }
 
@@ -93,13 +95,10 @@ pub struct PaymentDetails {
     pub payment_hash: String,
     #[allow(dead_code)]
     pub wallet_id: String,
-    #[allow(dead_code)]
     pub amount: u64,
-    #[allow(dead_code)]
     pub fee: u64,
     #[allow(dead_code)]
     pub bolt11: String,
-    #[allow(dead_code)]
     pub status: String,
     #[allow(dead_code)]
     pub memo: Option<String>,
@@ -194,7 +193,7 @@ impl LNBitsClient {
         Ok(invoice)
     }
 
-    pub async fn is_invoice_paid(&self, payment_hash: &str) -> Result<bool, LNBitsError> {
+    pub async fn get_payment(&self, payment_hash: &str) -> Result<PaymentStatus, LNBitsError> {
         let url = format!("{}/api/v1/payments/{}", self.base_url, payment_hash);
 
         let mut headers = HeaderMap::new();
@@ -230,6 +229,6 @@ impl LNBitsClient {
             "Payment status: paid={}, status={}",
             payment.paid, payment.details.status
         );
-        Ok(payment.paid)
+        Ok(payment)
     }
 }
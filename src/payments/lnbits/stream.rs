@@ -0,0 +1,270 @@
+use super::{LNBitsClient, LNBitsError};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+/// Initial delay before reconnecting to the payment stream
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Maximum delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Reconnect if the stream stays silent for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// SSE event name LNBits uses for incoming payments
+const PAYMENT_RECEIVED_EVENT: &str = "payment-received";
+
+/// Payment data carried by a `payment-received` event
+#[derive(Debug, Deserialize)]
+struct StreamPayment {
+    payment_hash: String,
+    #[serde(default)]
+    pending: Option<bool>,
+}
+
+/// Long-lived subscription to a wallet's real-time payment stream
+pub struct PaymentStreamListener {
+    client: LNBitsClient,
+    connected: Arc<AtomicBool>,
+}
+
+impl PaymentStreamListener {
+    /// Create a listener for the wallet the client is configured for
+    pub fn new(client: LNBitsClient) -> Self {
+        Self {
+            client,
+            connected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag that is `true` while the stream is connected
+    pub fn connected(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.connected)
+    }
+
+    /// Subscribe in the background, calling `on_payment` with each settled payment hash
+    pub fn spawn<F>(self, on_payment: F)
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        tokio::spawn(async move { self.run(on_payment).await });
+    }
+
+    /// Keep the subscription open, reconnecting with exponential backoff
+    async fn run<F>(self, on_payment: F)
+    where
+        F: Fn(String) + Send + Sync + 'static,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            match self.client.open_payment_stream().await {
+                Ok(response) => {
+                    info!("Subscribed to LNBits payment stream");
+                    self.connected.store(true, Ordering::SeqCst);
+                    let received_any = read_events(response, &on_payment).await;
+                    self.connected.store(false, Ordering::SeqCst);
+
+                    if received_any {
+                        backoff = INITIAL_BACKOFF;
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to subscribe to LNBits payment stream: {}", e);
+                }
+            }
+
+            debug!("Reconnecting to LNBits payment stream in {:?}", backoff);
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Read events until the stream ends, returning whether any data was received
+async fn read_events<F>(mut response: reqwest::Response, on_payment: &F) -> bool
+where
+    F: Fn(String),
+{
+    let mut events = EventSplitter::default();
+    let mut received_any = false;
+
+    loop {
+        let chunk = match time::timeout(IDLE_TIMEOUT, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => {
+                info!("LNBits payment stream closed");
+                return received_any;
+            }
+            Ok(Err(e)) => {
+                warn!("LNBits payment stream error: {}", e);
+                return received_any;
+            }
+            Err(_) => {
+                debug!("LNBits payment stream idle, reconnecting");
+                return received_any;
+            }
+        };
+        received_any = true;
+
+        for event in events.push(&chunk) {
+            if let Some(payment_hash) = parse_event(&event) {
+                debug!("Payment stream reported settled payment {}", payment_hash);
+                on_payment(payment_hash);
+            }
+        }
+    }
+}
+
+/// Splits the bytes of the stream into raw SSE events
+///
+/// Bytes are only decoded once their whole line has arrived, so characters split
+/// between chunks come through intact.
+#[derive(Default)]
+struct EventSplitter {
+    /// Bytes of the line still being received
+    line: Vec<u8>,
+    /// Lines of the event still being received
+    event: String,
+}
+
+impl EventSplitter {
+    /// Add a chunk of the stream, returning the events it completed
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        let mut rest = chunk;
+
+        while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+            self.line.extend_from_slice(&rest[..end]);
+            rest = &rest[end + 1..];
+
+            let line = String::from_utf8_lossy(&self.line);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            // Events are separated by a blank line
+            if line.is_empty() {
+                if !self.event.is_empty() {
+                    events.push(std::mem::take(&mut self.event));
+                }
+            } else {
+                self.event.push_str(line);
+                self.event.push('\n');
+            }
+            self.line.clear();
+        }
+
+        self.line.extend_from_slice(rest);
+        events
+    }
+}
+
+/// Extract the payment hash of a settled incoming payment from a raw SSE event
+fn parse_event(event: &str) -> Option<String> {
+    let mut name = None;
+    let mut data = String::new();
+
+    for line in event.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            name = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.trim());
+        }
+    }
+
+    if name != Some(PAYMENT_RECEIVED_EVENT) {
+        return None;
+    }
+
+    match serde_json::from_str::<StreamPayment>(&data) {
+        Ok(payment) if payment.pending != Some(true) => Some(payment.payment_hash),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Unparseable LNBits payment stream event: {}", e);
+            None
+        }
+    }
+}
+
+impl LNBitsClient {
+    /// Open the wallet's server-sent events payment stream
+    pub async fn open_payment_stream(&self) -> Result<reqwest::Response, LNBitsError> {
+        let url = format!("{}/api/v1/payments/sse", self.base_url);
+
+        let response = self
+            .http_client
+            .get(&url)
+            .query(&[("api-key", &self.invoice_read_key)])
+            .header("Accept", "text/event-stream")
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(LNBitsError::ApiError(error_text));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLED: &str =
+        "event: payment-received\ndata: {\"payment_hash\":\"abc\",\"memo\":\"☕\"}\n\n";
+
+    #[test]
+    fn events_split_between_chunks_are_reassembled() {
+        let bytes = SETTLED.as_bytes();
+        // Split inside the multibyte character of the memo
+        let split = SETTLED.find('☕').unwrap() + 1;
+        let mut splitter = EventSplitter::default();
+
+        assert!(splitter.push(&bytes[..5]).is_empty());
+        assert!(splitter.push(&bytes[5..split]).is_empty());
+        let events = splitter.push(&bytes[split..]);
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains('☕'));
+        assert_eq!(parse_event(&events[0]).as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn crlf_line_endings_are_accepted() {
+        let mut splitter = EventSplitter::default();
+
+        let events = splitter.push(SETTLED.replace('\n', "\r\n").as_bytes());
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(parse_event(&events[0]).as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn comments_and_other_events_are_ignored() {
+        let mut splitter = EventSplitter::default();
+        let stream = format!(": keep-alive\n\nevent: ping\ndata: {{}}\n\n: note\n{SETTLED}");
+
+        let hashes: Vec<_> = splitter
+            .push(stream.as_bytes())
+            .iter()
+            .filter_map(|event| parse_event(event))
+            .collect();
+
+        assert_eq!(hashes, ["abc"]);
+    }
+
+    #[test]
+    fn pending_payments_are_ignored() {
+        let pending =
+            "event: payment-received\ndata: {\"payment_hash\":\"abc\",\"pending\":true}\n";
+        let settled =
+            "event: payment-received\ndata: {\"payment_hash\":\"abc\",\"pending\":false}\n";
+
+        assert_eq!(parse_event(pending), None);
+        assert_eq!(parse_event(settled).as_deref(), Some("abc"));
+    }
+}
@@ -1,29 +1,32 @@
 pub mod coinbase;
+pub mod context;
 pub mod lightning;
 pub mod lnbits;
+pub mod reconciler;
 
-use crate::config::{Config, Offer};
-use crate::storage::{RedisStorage, StorageError};
+use crate::config::{Config, LatePaymentPolicy, MispaymentPolicy, Offer};
+use crate::events::{Event, EventBus};
+use crate::storage::{Storage, StorageError};
 use crate::utils;
 use crate::{
     models::{
-        PaymentMethod, PaymentRequest, PaymentRequestDetails, PaymentRequestInput, PaymentStatus,
+        PaymentMethod, PaymentRequest, PaymentRequestDetails, PaymentRequestInput,
+        PaymentRequiredResponse, PaymentStatus,
     },
     utils::ConversionError,
 };
 use anyhow::Result;
-use chrono::{Duration, Utc};
-use coinbase::CoinbaseProvider;
-use lightning::LightningProvider;
+use chrono::{DateTime, Duration, Utc};
+use coinbase::{ChargeOutcome, CoinbaseProvider, CoinbaseWebhookEvent};
+use context::{ContextError, PaymentContext};
+use lightning::{InvoiceStatus, LightningProvider};
+use lnbits::PaymentStreamListener;
+use reconciler::{PendingSettlement, ReconcilerOptions, SettlementCheck, SettlementReconciler};
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::time;
 use tracing::{debug, error, info, warn};
 
-// No exports needed
-
-// Re-export LNBits types
-
 /// Errors that can occur in payment processing
 #[derive(Debug, Error)]
 pub enum PaymentError {
@@ -43,34 +46,20 @@ pub enum PaymentError {
     #[error("Invalid payment method: {0:?}")]
     InvalidPaymentMethod(PaymentMethod),
 
-    /// Offer not found
-    #[error("Offer not found: {0}")]
-    #[allow(dead_code)]
-    OfferNotFound(String),
-
     /// User not found
     #[error("User not found: {0}")]
-    #[allow(dead_code)]
     UserNotFound(String),
 
-    /// Invalid input
-    #[error("Invalid input: {0}")]
-    #[allow(dead_code)]
-    InvalidInput(String),
-
-    /// Payment already processed
-    #[error("Payment already processed: {0}")]
-    #[allow(dead_code)]
-    AlreadyProcessed(String),
+    /// Invalid payment context token
+    #[error("Invalid payment context: {0}")]
+    InvalidContext(#[from] ContextError),
 
-    /// Payment expired
-    #[error("Payment expired: {0}")]
-    #[allow(dead_code)]
-    PaymentExpired(String),
+    /// The offer's terms changed since they were quoted
+    #[error("Offer terms changed since quoted: {0}")]
+    OfferChanged(String),
 
     /// Payment not found
     #[error("Payment not found: {0}")]
-    #[allow(dead_code)]
     PaymentNotFound(String),
 
     /// Conversion error
@@ -80,25 +69,75 @@ pub enum PaymentError {
     /// Invalid offer
     #[error("Invalid offer: {0}")]
     InvalidOffer(String),
+
+    /// Webhook event is older than the accepted age
+    #[error("Stale webhook event: {0}")]
+    StaleEvent(String),
 }
 
+/// Source names used to scope webhook event IDs
+const LIGHTNING_WEBHOOK_SOURCE: &str = "lightning";
+const COINBASE_WEBHOOK_SOURCE: &str = "coinbase";
+
 /// Service for handling payments
 #[derive(Clone)]
 pub struct PaymentService {
-    storage: RedisStorage,
+    storage: Arc<dyn Storage>,
     config: Arc<Config>,
     lightning_provider: Option<LightningProvider>,
     coinbase_provider: Option<CoinbaseProvider>,
+    reconciler: Option<SettlementReconciler>,
+    events: EventBus,
 }
 
 impl PaymentService {
     /// Create a new payment service without providers
-    pub fn new_without_providers(config: Arc<Config>, storage: RedisStorage) -> Self {
+    pub fn new_without_providers(config: Arc<Config>, storage: Arc<dyn Storage>) -> Self {
         Self {
             storage,
             config,
             lightning_provider: None,
             coinbase_provider: None,
+            reconciler: None,
+            events: EventBus::new(),
+        }
+    }
+
+    /// Publish payment status changes to the given event bus
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Build the 402 response quoting the configured offers to a user
+    pub fn payment_required(&self, user_id: &str) -> PaymentRequiredResponse {
+        let expiry = Utc::now() + Duration::seconds(self.config.payment_context_ttl_secs as i64);
+        let context = PaymentContext::new(user_id.to_string(), &self.config.offers, expiry);
+
+        PaymentRequiredResponse {
+            expiry,
+            offers: self.config.offers.clone(),
+            payment_context_token: context.sign(&self.config.payment_context_secret),
+            payment_request_url: self.config.get_payment_request_url(),
+        }
+    }
+
+    /// Event bus payment status changes are published to
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Get a payment request by ID
+    pub async fn get_payment_request(
+        &self,
+        request_id: &str,
+    ) -> Result<PaymentRequest, PaymentError> {
+        match self.storage.get_payment_request(request_id).await {
+            Ok(request) => Ok(request),
+            Err(StorageError::PaymentRequestNotFound) => {
+                Err(PaymentError::PaymentNotFound(request_id.to_string()))
+            }
+            Err(e) => Err(PaymentError::from(e)),
         }
     }
 
@@ -133,27 +172,125 @@ impl PaymentService {
         Ok(())
     }
 
+    /// Start the background reconciler that tracks pending Lightning payments
+    ///
+    /// Also subscribes to the LNBits payment stream when enabled, so settlements are
+    /// pushed to the reconciler instead of being discovered by polling.
+    /// Must be called after `init_providers` and from within a Tokio runtime.
+    pub fn start_reconciler(&mut self) {
+        let Some(provider) = &self.lightning_provider else {
+            return;
+        };
+        if self.reconciler.is_some() {
+            return;
+        }
+
+        let listener = if self.config.lnbits_payment_stream {
+            provider
+                .lnbits_client()
+                .cloned()
+                .map(PaymentStreamListener::new)
+        } else {
+            None
+        };
+        let push_connected = listener
+            .as_ref()
+            .map(|listener| listener.connected())
+            .unwrap_or_default();
+
+        let options = ReconcilerOptions::from_config(&self.config);
+        let reconciler = SettlementReconciler::spawn(self.clone(), options, push_connected);
+
+        if let Some(listener) = listener {
+            let stream_reconciler = reconciler.clone();
+            listener.spawn(move |payment_hash| stream_reconciler.notify_settled(payment_hash));
+            info!("Listening to the LNBits payment stream");
+        }
+
+        self.reconciler = Some(reconciler);
+    }
+
+    /// Resume tracking of pending Lightning payments found in storage
+    ///
+    /// Called on startup so invoices created before a restart are still credited.
+    pub async fn resume_pending_payments(&self) -> Result<usize, PaymentError> {
+        let Some(reconciler) = &self.reconciler else {
+            return Ok(0);
+        };
+
+        let pending = self
+            .storage
+            .list_pending_payment_requests()
+            .await
+            .map_err(PaymentError::from)?;
+
+        let mut resumed = 0;
+        for payment_request in pending {
+            if payment_request.method != PaymentMethod::Lightning {
+                continue;
+            }
+            let Some(payment_hash) = payment_request.external_id else {
+                debug!(
+                    "Pending payment {} has no invoice, skipping",
+                    payment_request.id
+                );
+                continue;
+            };
+
+            reconciler.track(PendingSettlement {
+                payment_hash,
+                created_at: payment_request.created_at,
+                expires_at: payment_request.expires_at,
+            });
+            resumed += 1;
+        }
+
+        Ok(resumed)
+    }
+
     /// Process a payment request
     pub async fn process_payment_request(
         &self,
         input: PaymentRequestInput,
     ) -> Result<(PaymentRequest, PaymentRequestDetails), PaymentError> {
-        // Get the offer
+        // The context token proves which user the 402 was issued to and what they were quoted
+        let context = PaymentContext::verify(
+            &input.payment_context_token,
+            &self.config.payment_context_secret,
+        )?;
+
+        match self.storage.get_user(&context.user_id).await {
+            Ok(_) => {}
+            Err(StorageError::UserNotFound) => {
+                return Err(PaymentError::UserNotFound(context.user_id));
+            }
+            Err(e) => return Err(PaymentError::from(e)),
+        }
+
+        // Get the offer, which must still be available on the quoted terms
+        let terms = context
+            .offer(&input.offer_id)
+            .ok_or_else(|| PaymentError::InvalidOffer(input.offer_id.clone()))?;
         let offer = self
             .config
             .offers
             .iter()
             .find(|o| o.id == input.offer_id)
             .ok_or_else(|| PaymentError::InvalidOffer(input.offer_id.clone()))?;
+        if !terms.matches(offer) {
+            return Err(PaymentError::OfferChanged(input.offer_id.clone()));
+        }
 
         // Create payment request
-        let payment_request = PaymentRequest::new(
-            input.payment_context_token.clone(),
+        let mut payment_request = PaymentRequest::new(
+            context.user_id.clone(),
             input.offer_id.clone(),
             offer.credits,
             input.payment_method,
             Utc::now() + Duration::minutes(30),
         );
+        payment_request.amount = offer.amount;
+        payment_request.currency = offer.currency.clone();
 
         // Store the payment request
         self.storage
@@ -188,32 +325,54 @@ impl PaymentService {
             .ok_or_else(|| PaymentError::InvalidPaymentMethod(PaymentMethod::Lightning))?;
 
         // Convert USD to sats
-        let amount_sats = utils::convert_usd_to_sats(offer.amount)
+        let conversion = utils::convert_usd_to_sats(offer.amount)
             .await
             .map_err(PaymentError::from)?;
 
-        // Create invoice
-        let (invoice, payment_hash) = provider
-            .create_invoice(amount_sats, &format!("Purchase {} credits", offer.credits))
+        // Create invoice with a webhook URL only LNBits learns about, expiring
+        // together with the payment request
+        let webhook_token = utils::generate_secret_token();
+        let expiry_secs = (payment_request.expires_at - Utc::now())
+            .num_seconds()
+            .max(1) as u32;
+        let invoice = provider
+            .create_invoice(
+                conversion.amount_sats,
+                &format!("Purchase {} credits", offer.credits),
+                expiry_secs,
+                &webhook_token,
+            )
             .await?;
 
-        // Update payment request with external ID
+        // Record the invoice and how its amount was worked out
         let mut updated_request = payment_request.clone();
-        updated_request.external_id = Some(payment_hash.clone());
+        updated_request.external_id = Some(invoice.payment_hash.clone());
+        updated_request.amount_sats = Some(conversion.amount_sats);
+        updated_request.btc_usd_rate = Some(conversion.btc_usd_rate);
+        updated_request.lightning_invoice = Some(invoice.bolt11.clone());
+        updated_request.fee_msat = Some(invoice.fee_msat);
+        if provider.webhooks_enabled() {
+            updated_request.webhook_token = Some(webhook_token);
+        }
         self.storage
             .store_payment_request(&updated_request)
             .await
             .map_err(PaymentError::from)?;
 
-        // Start polling for payment in background
-        let service = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = service.start_payment_polling(payment_hash, None).await {
-                error!("Error polling payment status: {}", e);
-            }
-        });
+        // Hand the invoice over to the settlement reconciler
+        match &self.reconciler {
+            Some(reconciler) => reconciler.track(PendingSettlement {
+                payment_hash: invoice.payment_hash.clone(),
+                created_at: updated_request.created_at,
+                expires_at: updated_request.expires_at,
+            }),
+            None => warn!(
+                "Settlement reconciler not running, payment {} relies on webhooks",
+                updated_request.id
+            ),
+        }
 
-        Ok(provider.generate_payment_details(&invoice))
+        Ok(provider.generate_payment_details(&invoice.bolt11))
     }
 
     /// Create a Coinbase payment
@@ -264,33 +423,67 @@ impl PaymentService {
     }
 
     /// Process a successful payment and update user credits
+    ///
+    /// Payments that arrive after the request expired are recorded as late payments
+    /// and credited or flagged for refund according to the late payment policy.
     async fn process_successful_payment(
         &self,
         payment_request: &mut PaymentRequest,
     ) -> Result<(), PaymentError> {
-        // Only process if payment is still pending
-        if payment_request.status != PaymentStatus::Pending {
-            debug!(
-                "Payment {} already processed (status: {:?})",
-                payment_request.id, payment_request.status
+        let now = Utc::now();
+        let is_late = match payment_request.status {
+            PaymentStatus::Pending => now > payment_request.expires_at,
+            PaymentStatus::Expired => true,
+            // Held payments arrived in time, they were only waiting for review
+            PaymentStatus::OnHold => false,
+            PaymentStatus::Paid | PaymentStatus::RefundDue => {
+                debug!(
+                    "Payment {} already processed (status: {:?})",
+                    payment_request.id, payment_request.status
+                );
+                return Ok(());
+            }
+        };
+
+        if is_late {
+            payment_request.late_payment_at = Some(now);
+            if self.config.late_payment_policy == LatePaymentPolicy::Refund {
+                if !self
+                    .set_payment_status(payment_request, PaymentStatus::RefundDue)
+                    .await?
+                {
+                    debug!("Payment {} already processed", payment_request.id);
+                    return Ok(());
+                }
+                self.publish_status_change(payment_request).await;
+
+                warn!(
+                    "Late payment {} for user {} flagged for refund",
+                    payment_request.id, payment_request.user_id
+                );
+                return Ok(());
+            }
+
+            info!(
+                "Crediting late payment {} for user {}",
+                payment_request.id, payment_request.user_id
             );
-            return Ok(());
         }
 
-        // Update payment status
-        payment_request.status = PaymentStatus::Paid;
-
-        // Store the updated payment request
-        self.storage
-            .store_payment_request(payment_request)
-            .await
-            .map_err(PaymentError::from)?;
+        // Only the task that marks the payment as paid credits it, so a payment
+        // settled by a webhook and the reconciler at once is credited once. The
+        // credits are written together with the status, so a failed write leaves
+        // the payment to be settled again by the next check or redelivery.
+        if !self
+            .set_payment_status(payment_request, PaymentStatus::Paid)
+            .await?
+        {
+            debug!("Payment {} already processed", payment_request.id);
+            return Ok(());
+        }
 
-        // Update user credits
-        self.storage
-            .update_user_credits(&payment_request.user_id, payment_request.credits as i32)
-            .await
-            .map_err(PaymentError::from)?;
+        // Announce the settlement once the credits are available
+        self.publish_status_change(payment_request).await;
 
         info!(
             "Payment {} processed successfully for user {}",
@@ -300,103 +493,229 @@ impl PaymentService {
         Ok(())
     }
 
-    /// Start polling for payment status
-    pub async fn start_payment_polling(
+    /// Move a payment request to a new status and store it
+    ///
+    /// Moving to `Paid` also credits the user with the purchased credits.
+    /// Returns `false` without storing anything if the stored request had already
+    /// moved to a status `status` can't follow, e.g. because another task settled it.
+    async fn set_payment_status(
+        &self,
+        payment_request: &mut PaymentRequest,
+        status: PaymentStatus,
+    ) -> Result<bool, PaymentError> {
+        let previous = (payment_request.status, payment_request.settled_at);
+        payment_request.status = status;
+        if status == PaymentStatus::Paid {
+            payment_request.settled_at = Some(Utc::now());
+        }
+
+        let from = status.reachable_from();
+        let moved = if status == PaymentStatus::Paid {
+            self.storage
+                .settle_payment_request(payment_request, from)
+                .await
+        } else {
+            self.storage
+                .transition_payment_request(payment_request, from)
+                .await
+        };
+        if !matches!(moved, Ok(true)) {
+            (payment_request.status, payment_request.settled_at) = previous;
+        }
+        moved.map_err(PaymentError::from)
+    }
+
+    /// Notify subscribers that a payment request changed status
+    async fn publish_status_change(&self, payment_request: &PaymentRequest) {
+        self.events
+            .publish(Event::PaymentStatusChanged {
+                user_id: payment_request.user_id.clone(),
+                payment_id: payment_request.id.clone(),
+                status: payment_request.status,
+            })
+            .await;
+    }
+
+    /// Mark a pending payment request as expired and cancel it on the backend
+    ///
+    /// Does nothing if the request isn't pending or hasn't reached its expiry yet.
+    pub async fn expire_payment_request(
         &self,
-        payment_hash: String,
-        timeout_minutes: Option<u64>,
+        payment_request: &mut PaymentRequest,
     ) -> Result<(), PaymentError> {
-        let provider = self
-            .lightning_provider
-            .as_ref()
-            .ok_or_else(|| PaymentError::InvalidPaymentMethod(PaymentMethod::Lightning))?;
+        if payment_request.status != PaymentStatus::Pending
+            || Utc::now() <= payment_request.expires_at
+        {
+            return Ok(());
+        }
 
-        let timeout = timeout_minutes.unwrap_or(30);
-        let timeout_duration = Duration::minutes(timeout as i64);
-        let start_time = Utc::now();
-        let poll_interval = time::Duration::from_millis(500); // Poll every 500ms
+        if !self
+            .set_payment_status(payment_request, PaymentStatus::Expired)
+            .await?
+        {
+            // Settled in the meantime
+            return Ok(());
+        }
+        self.publish_status_change(payment_request).await;
+        info!("Payment request {} expired", payment_request.id);
 
-        info!("Starting payment polling for hash: {}", payment_hash);
+        let Some(external_id) = payment_request.external_id.as_deref() else {
+            return Ok(());
+        };
 
-        loop {
-            // Check if we've exceeded the timeout
-            if Utc::now() - start_time > timeout_duration {
-                warn!("Payment polling timed out for hash: {}", payment_hash);
-                return Ok(());
+        // Cancellation is best effort: a late payment is still handled by policy
+        let cancelled = match payment_request.method {
+            PaymentMethod::Lightning => match &self.lightning_provider {
+                Some(provider) => provider
+                    .cancel_invoice(external_id)
+                    .await
+                    .map_err(PaymentError::from),
+                None => Ok(false),
+            },
+            PaymentMethod::Coinbase => match &self.coinbase_provider {
+                Some(provider) => provider
+                    .cancel_charge(external_id)
+                    .await
+                    .map(|_| true)
+                    .map_err(PaymentError::from),
+                None => Ok(false),
+            },
+        };
+
+        match cancelled {
+            Ok(true) => debug!("Cancelled {} on the payment backend", external_id),
+            Ok(false) => {}
+            Err(e) => warn!(
+                "Failed to cancel {} on the payment backend: {}",
+                external_id, e
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Expire all pending payment requests that are past their expiry
+    pub async fn expire_stale_payments(&self) -> Result<usize, PaymentError> {
+        let now = Utc::now();
+        let pending = self
+            .storage
+            .list_pending_payment_requests()
+            .await
+            .map_err(PaymentError::from)?;
+
+        let mut expired = 0;
+        for mut payment_request in pending {
+            if now <= payment_request.expires_at {
+                continue;
             }
+            // One failing request mustn't keep the rest from expiring
+            match self.expire_payment_request(&mut payment_request).await {
+                Ok(()) => expired += 1,
+                Err(e) => error!(
+                    "Failed to expire payment request {}: {}",
+                    payment_request.id, e
+                ),
+            }
+        }
 
-            // Check payment status
-            match provider.check_invoice(&payment_hash).await {
-                Ok(true) => {
-                    // Get the payment request
-                    match self
-                        .storage
-                        .get_payment_request_by_external_id(&payment_hash)
-                        .await
-                    {
-                        Ok(mut payment_request) => {
-                            // Process the successful payment
-                            match self.process_successful_payment(&mut payment_request).await {
-                                Ok(_) => {
-                                    info!("Payment confirmed and processed: {}", payment_hash);
-                                    return Ok(());
-                                }
-                                Err(PaymentError::AlreadyProcessed(_)) => {
-                                    debug!(
-                                        "Payment already processed, stopping polling: {}",
-                                        payment_hash
-                                    );
-                                    return Ok(());
-                                }
-                                Err(PaymentError::PaymentExpired(_)) => {
-                                    debug!("Payment expired, stopping polling: {}", payment_hash);
-                                    return Ok(());
-                                }
-                                Err(e) => {
-                                    error!("Error processing payment: {}", e);
-                                    // Continue polling in case it's a temporary error
-                                }
-                            }
-                        }
-                        Err(StorageError::PaymentRequestNotFound) => {
-                            debug!("Payment request not found for hash: {}", payment_hash);
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            error!("Error retrieving payment request: {}", e);
-                            // Continue polling in case it's a temporary error
-                        }
-                    }
-                }
-                Ok(false) => {
-                    debug!("Payment not yet received: {}", payment_hash);
+        Ok(expired)
+    }
+
+    /// Start a background task that periodically expires stale payment requests
+    ///
+    /// It also purges expired webhook claims and idempotency keys from storage.
+    pub fn start_expiry_sweeper(&self) {
+        let service = self.clone();
+        let interval = time::Duration::from_secs(self.config.expiry_sweep_interval_secs.max(1));
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match service.expire_stale_payments().await {
+                    Ok(0) => {}
+                    Ok(expired) => info!("Expired {} stale payment requests", expired),
+                    Err(e) => error!("Error expiring stale payment requests: {}", e),
                 }
-                Err(e) => {
-                    error!("Error checking payment status: {}", e);
-                    // Continue polling in case it's a temporary error
+                match service.storage.purge_expired().await {
+                    Ok(0) => {}
+                    Ok(purged) => debug!("Purged {} expired storage entries", purged),
+                    Err(e) => error!("Error purging expired storage entries: {}", e),
                 }
             }
+        });
+    }
+
+    /// Check a pending Lightning payment once and credit it if it has settled
+    pub async fn reconcile_lightning_payment(
+        &self,
+        payment_hash: &str,
+    ) -> Result<SettlementCheck, PaymentError> {
+        let provider = self
+            .lightning_provider
+            .as_ref()
+            .ok_or_else(|| PaymentError::InvalidPaymentMethod(PaymentMethod::Lightning))?;
+
+        let mut payment_request = match self
+            .storage
+            .get_payment_request_by_external_id(payment_hash)
+            .await
+        {
+            Ok(request) => request,
+            Err(StorageError::PaymentRequestNotFound) => {
+                debug!("Payment request not found for hash: {}", payment_hash);
+                return Ok(SettlementCheck::Terminal);
+            }
+            Err(e) => return Err(PaymentError::from(e)),
+        };
+
+        // Nothing left to do once the payment has been settled. Expired requests
+        // are still checked so a late payment can be handled.
+        if matches!(
+            payment_request.status,
+            PaymentStatus::Paid | PaymentStatus::RefundDue
+        ) {
+            debug!(
+                "Payment {} is already settled (status: {:?})",
+                payment_hash, payment_request.status
+            );
+            return Ok(SettlementCheck::Terminal);
+        }
 
-            // Wait before next poll
-            time::sleep(poll_interval).await;
+        let invoice = provider.check_invoice(payment_hash).await?;
+        if invoice.paid {
+            record_lightning_settlement(&mut payment_request, &invoice);
+            self.process_successful_payment(&mut payment_request)
+                .await?;
+            info!("Payment confirmed and processed: {}", payment_hash);
+            return Ok(SettlementCheck::Terminal);
         }
+
+        if Utc::now() > payment_request.expires_at {
+            debug!("Payment expired, stopping reconciliation: {}", payment_hash);
+            self.expire_payment_request(&mut payment_request).await?;
+            return Ok(SettlementCheck::Terminal);
+        }
+
+        debug!("Payment not yet received: {}", payment_hash);
+        Ok(SettlementCheck::Pending)
     }
 
     /// Process a Lightning webhook
+    ///
+    /// `token` is the secret from the per-invoice webhook URL.
     pub async fn process_lightning_webhook(
         &self,
         body: &[u8],
-        signature: &str,
+        token: &str,
     ) -> Result<Option<String>, PaymentError> {
         let provider = self
             .lightning_provider
             .as_ref()
             .ok_or_else(|| PaymentError::InvalidPaymentMethod(PaymentMethod::Lightning))?;
 
-        // Verify and parse the webhook
-        let event = provider
-            .verify_webhook(body, signature)
-            .map_err(PaymentError::from)?;
+        // Parse the webhook
+        let event = provider.parse_webhook(body).map_err(PaymentError::from)?;
 
         // Get the payment request
         let mut payment_request = match self
@@ -412,31 +731,71 @@ impl PaymentService {
             Err(e) => return Err(PaymentError::from(e)),
         };
 
-        // Check if already processed
-        if payment_request.status == PaymentStatus::Paid {
-            debug!("Payment already processed: {}", event.payment_hash);
+        // Verify the token LNBits received with this invoice
+        provider
+            .verify_webhook_token(token, payment_request.webhook_token.as_deref())
+            .map_err(PaymentError::from)?;
+
+        // Reject replays of old events and process each payment only once
+        self.check_event_age(event.time(), &event.payment_hash)?;
+        if !self
+            .storage
+            .claim_webhook_event(
+                LIGHTNING_WEBHOOK_SOURCE,
+                &event.payment_hash,
+                self.config.webhook_event_retention_secs,
+            )
+            .await
+            .map_err(PaymentError::from)?
+        {
+            debug!("Duplicate Lightning webhook: {}", event.payment_hash);
             return Ok(None);
         }
 
-        // Check if expired
-        if Utc::now() > payment_request.expires_at {
-            debug!("Payment expired: {}", event.payment_hash);
+        let result = self
+            .settle_lightning_webhook(provider, &event.payment_hash, &mut payment_request)
+            .await;
+
+        // Let a later delivery through if this one didn't settle the payment
+        if !matches!(result, Ok(Some(_))) {
+            self.storage
+                .release_webhook_event(LIGHTNING_WEBHOOK_SOURCE, &event.payment_hash)
+                .await
+                .map_err(PaymentError::from)?;
+        }
+
+        result
+    }
+
+    /// Settle the payment a verified Lightning webhook refers to
+    async fn settle_lightning_webhook(
+        &self,
+        provider: &LightningProvider,
+        payment_hash: &str,
+        payment_request: &mut PaymentRequest,
+    ) -> Result<Option<String>, PaymentError> {
+        // Check if already processed (late payments are handled by policy)
+        if matches!(
+            payment_request.status,
+            PaymentStatus::Paid | PaymentStatus::RefundDue
+        ) {
+            debug!("Payment already processed: {}", payment_hash);
             return Ok(None);
         }
 
         // Verify payment is settled
-        if !provider
-            .check_invoice(&event.payment_hash)
+        let invoice = provider
+            .check_invoice(payment_hash)
             .await
-            .map_err(PaymentError::from)?
-        {
-            debug!("Payment not yet settled: {}", event.payment_hash);
+            .map_err(PaymentError::from)?;
+        if !invoice.paid {
+            debug!("Payment not yet settled: {}", payment_hash);
             return Ok(None);
         }
+        record_lightning_settlement(payment_request, &invoice);
 
         // Process the successful payment
-        self.process_successful_payment(&mut payment_request)
-            .await?;
+        self.process_successful_payment(payment_request).await?;
 
         Ok(Some(payment_request.user_id.clone()))
     }
@@ -457,8 +816,44 @@ impl PaymentService {
             .verify_webhook(body, signature)
             .map_err(PaymentError::from)?;
 
+        // Reject replays of old events and process each event only once
+        let event_id = provider.get_event_id(&event);
+        self.check_event_age(provider.get_event_time(&event), event_id)?;
+        if !self
+            .storage
+            .claim_webhook_event(
+                COINBASE_WEBHOOK_SOURCE,
+                event_id,
+                self.config.webhook_event_retention_secs,
+            )
+            .await
+            .map_err(PaymentError::from)?
+        {
+            debug!("Duplicate Coinbase webhook event: {}", event_id);
+            return Ok(None);
+        }
+
+        let result = self.handle_coinbase_event(provider, &event).await;
+
+        // Let a redelivery through if processing failed
+        if result.is_err() {
+            self.storage
+                .release_webhook_event(COINBASE_WEBHOOK_SOURCE, event_id)
+                .await
+                .map_err(PaymentError::from)?;
+        }
+
+        result
+    }
+
+    /// Apply a verified Coinbase webhook event to its payment request
+    async fn handle_coinbase_event(
+        &self,
+        provider: &CoinbaseProvider,
+        event: &CoinbaseWebhookEvent,
+    ) -> Result<Option<String>, PaymentError> {
         // Get the charge ID from the event
-        let charge_id = provider.get_charge_id(&event);
+        let charge_id = provider.get_charge_id(event);
 
         // Get the payment request
         let mut payment_request = match self
@@ -474,22 +869,56 @@ impl PaymentService {
             Err(e) => return Err(PaymentError::from(e)),
         };
 
-        // Check if already processed
-        if payment_request.status == PaymentStatus::Paid {
+        // Check if already processed (late payments are handled by policy)
+        if matches!(
+            payment_request.status,
+            PaymentStatus::Paid | PaymentStatus::RefundDue
+        ) {
             debug!("Payment already processed: {}", charge_id);
             return Ok(None);
         }
 
-        // Check if expired
-        if Utc::now() > payment_request.expires_at {
-            debug!("Payment expired: {}", charge_id);
-            return Ok(None);
-        }
-
-        // Check if payment is completed
-        if !provider.is_payment_completed(&event) {
-            debug!("Payment not completed: {}", charge_id);
-            return Ok(None);
+        // Work out what the event means for this payment
+        let (amount_paid, amount_due, paid_in_full) = match provider.charge_outcome(event) {
+            ChargeOutcome::Pending => {
+                debug!(
+                    "Payment not completed: {} ({:?})",
+                    charge_id,
+                    provider.get_event_type(event)
+                );
+                return Ok(None);
+            }
+            ChargeOutcome::Failed => {
+                debug!("Charge failed without payment: {}", charge_id);
+                self.expire_payment_request(&mut payment_request).await?;
+                return Ok(None);
+            }
+            ChargeOutcome::Paid {
+                amount_paid,
+                amount_due,
+                paid_in_full,
+            } => (amount_paid, amount_due, paid_in_full),
+        };
+        payment_request.amount_received = Some(amount_paid);
+
+        // Resolved charges, and confirmed ones without listed payments, count as
+        // paid in full
+        if !paid_in_full {
+            let amount_due = amount_due.or_else(|| {
+                self.config
+                    .offers
+                    .iter()
+                    .find(|o| o.id == payment_request.offer_id)
+                    .map(|o| o.amount)
+            });
+
+            if let Some(amount_due) = amount_due
+                && !self
+                    .apply_mispayment_policy(&mut payment_request, amount_paid, amount_due)
+                    .await?
+            {
+                return Ok(None);
+            }
         }
 
         // Process the successful payment
@@ -498,4 +927,79 @@ impl PaymentService {
 
         Ok(Some(payment_request.user_id.clone()))
     }
+
+    /// Reject webhook events older than the configured maximum age
+    fn check_event_age(
+        &self,
+        event_time: Option<DateTime<Utc>>,
+        event_id: &str,
+    ) -> Result<(), PaymentError> {
+        let Some(event_time) = event_time else {
+            return Ok(());
+        };
+
+        let max_age = Duration::seconds(self.config.webhook_max_event_age_secs as i64);
+        if Utc::now() - event_time > max_age {
+            warn!(
+                "Rejecting stale webhook event {} from {}",
+                event_id, event_time
+            );
+            return Err(PaymentError::StaleEvent(event_id.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Adjust the credits of a payment whose amount doesn't match what was asked
+    ///
+    /// Returns `false` if the payment was put on hold and must not be credited.
+    async fn apply_mispayment_policy(
+        &self,
+        payment_request: &mut PaymentRequest,
+        amount_paid: f64,
+        amount_due: f64,
+    ) -> Result<bool, PaymentError> {
+        // Differences below a cent are rounding noise
+        if (amount_paid - amount_due).abs() < 0.01 || amount_due <= 0.0 {
+            return Ok(true);
+        }
+
+        info!(
+            "Payment {} paid {:.2} of {:.2}, applying {:?} policy",
+            payment_request.id, amount_paid, amount_due, self.config.coinbase_mispayment_policy
+        );
+
+        match self.config.coinbase_mispayment_policy {
+            MispaymentPolicy::Full => Ok(true),
+            MispaymentPolicy::ProRata => {
+                let credits =
+                    (payment_request.credits as f64 * amount_paid / amount_due).floor() as u32;
+                payment_request.credits = credits;
+                Ok(true)
+            }
+            MispaymentPolicy::Hold => {
+                if payment_request.status != PaymentStatus::OnHold {
+                    if !self
+                        .set_payment_status(payment_request, PaymentStatus::OnHold)
+                        .await?
+                    {
+                        // Settled in the meantime
+                        return Ok(false);
+                    }
+                    self.publish_status_change(payment_request).await;
+                }
+                warn!(
+                    "Payment {} for user {} held for review",
+                    payment_request.id, payment_request.user_id
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Record what a settled Lightning invoice actually received
+fn record_lightning_settlement(payment_request: &mut PaymentRequest, invoice: &InvoiceStatus) {
+    payment_request.amount_received_msat = Some(invoice.amount_msat);
+    payment_request.fee_msat = Some(invoice.fee_msat);
 }
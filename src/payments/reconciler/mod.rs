@@ -0,0 +1,495 @@
+use crate::config::Config;
+use crate::payments::{PaymentError, PaymentService};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+/// How often the reconciler looks for payments that are due for a check
+const TICK_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+/// A Lightning payment the reconciler should keep checking until it is settled
+#[derive(Debug, Clone)]
+pub struct PendingSettlement {
+    /// Payment hash of the invoice
+    pub payment_hash: String,
+    /// When the invoice was created
+    pub created_at: DateTime<Utc>,
+    /// When the payment request expires
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Result of a single settlement check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettlementCheck {
+    /// The invoice is still open and should be checked again later
+    Pending,
+    /// The payment reached a terminal state (paid, expired or unknown)
+    Terminal,
+}
+
+/// Checks tracked payments for the reconciler
+#[async_trait]
+pub trait SettlementChecker: Clone + Send + Sync + 'static {
+    /// Check a pending payment once, crediting it if it has settled
+    async fn check_settlement(&self, payment_hash: &str) -> Result<SettlementCheck, PaymentError>;
+}
+
+#[async_trait]
+impl SettlementChecker for PaymentService {
+    async fn check_settlement(&self, payment_hash: &str) -> Result<SettlementCheck, PaymentError> {
+        self.reconcile_lightning_payment(payment_hash).await
+    }
+}
+
+/// Tuning knobs for the reconciler
+#[derive(Debug, Clone)]
+pub struct ReconcilerOptions {
+    /// Maximum number of checks started per tick
+    pub batch_size: usize,
+    /// Maximum number of checks running at the same time
+    pub max_concurrency: usize,
+    /// Delay between checks for a freshly created invoice
+    pub min_interval: chrono::Duration,
+    /// Upper bound for the delay between checks
+    pub max_interval: chrono::Duration,
+}
+
+impl ReconcilerOptions {
+    /// Build reconciler options from the application configuration
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            batch_size: config.settlement_batch_size.max(1),
+            max_concurrency: config.settlement_max_concurrency.max(1),
+            min_interval: chrono::Duration::seconds(config.settlement_min_interval_secs as i64),
+            max_interval: chrono::Duration::seconds(config.settlement_max_interval_secs as i64),
+        }
+    }
+
+    /// Delay before the next check, doubling every two minutes of invoice age
+    ///
+    /// While a push notification channel is connected, polling is only a safety net
+    /// and the maximum interval is used straight away.
+    fn check_interval(&self, age: chrono::Duration, push_connected: bool) -> chrono::Duration {
+        if push_connected {
+            return self.max_interval;
+        }
+
+        let doublings = (age.num_minutes().max(0) / 2).min(16) as u32;
+        let interval = self.min_interval * 2i32.pow(doublings);
+        interval.min(self.max_interval)
+    }
+}
+
+/// Messages accepted by the reconciler task
+#[derive(Debug)]
+enum ReconcilerMessage {
+    /// Start tracking a pending payment
+    Track(PendingSettlement),
+    /// A push channel reported the payment as settled; check it right away
+    ///
+    /// The check credits the payment through the same atomic status transition as
+    /// webhooks, so a settlement reported by both is only credited once.
+    Settled(String),
+}
+
+/// Handle used to register pending payments with the background reconciler
+#[derive(Debug, Clone)]
+pub struct SettlementReconciler {
+    sender: mpsc::UnboundedSender<ReconcilerMessage>,
+}
+
+impl SettlementReconciler {
+    /// Start the reconciler task and return a handle to it
+    ///
+    /// `push_connected` is raised while a push channel (such as the LNBits payment
+    /// stream) is delivering settlements, which lets polling back off.
+    pub fn spawn(
+        service: impl SettlementChecker,
+        options: ReconcilerOptions,
+        push_connected: Arc<AtomicBool>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(service, receiver, options, push_connected));
+        Self { sender }
+    }
+
+    /// Start tracking a pending payment
+    pub fn track(&self, pending: PendingSettlement) {
+        self.send(ReconcilerMessage::Track(pending));
+    }
+
+    /// Report a payment hash that a push channel saw settle
+    pub fn notify_settled(&self, payment_hash: String) {
+        self.send(ReconcilerMessage::Settled(payment_hash));
+    }
+
+    fn send(&self, message: ReconcilerMessage) {
+        if self.sender.send(message).is_err() {
+            error!("Settlement reconciler is not running, payment will not be tracked");
+        }
+    }
+}
+
+/// Bookkeeping for a payment the reconciler is tracking
+struct TrackedPayment {
+    pending: PendingSettlement,
+    next_check: Instant,
+    in_flight: bool,
+    /// A settlement was reported while a check was in flight, so check again once
+    /// it finishes rather than trusting its possibly stale result
+    recheck: bool,
+}
+
+/// Main reconciler loop
+async fn run(
+    service: impl SettlementChecker,
+    mut receiver: mpsc::UnboundedReceiver<ReconcilerMessage>,
+    options: ReconcilerOptions,
+    push_connected: Arc<AtomicBool>,
+) {
+    let mut tracked: HashMap<String, TrackedPayment> = HashMap::new();
+    let mut checks: JoinSet<Result<SettlementCheck, PaymentError>> = JoinSet::new();
+    // Payment hash each running check is for, so a check that panicked or was
+    // cancelled still clears its payment
+    let mut running: HashMap<task::Id, String> = HashMap::new();
+    let mut ticker = time::interval(TICK_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    info!(
+        "Settlement reconciler started (batch size {}, concurrency {})",
+        options.batch_size, options.max_concurrency
+    );
+
+    loop {
+        tokio::select! {
+            received = receiver.recv() => {
+                match received {
+                    Some(ReconcilerMessage::Track(pending)) => {
+                        debug!("Tracking payment {}", pending.payment_hash);
+                        let next_check = after(options.min_interval);
+                        tracked
+                            .entry(pending.payment_hash.clone())
+                            .or_insert(TrackedPayment {
+                                pending,
+                                next_check,
+                                in_flight: false,
+                                recheck: false,
+                            });
+                    }
+                    Some(ReconcilerMessage::Settled(payment_hash)) => {
+                        // Unknown hashes (e.g. from before a restart) get a single check
+                        let now = Instant::now();
+                        let payment = tracked
+                            .entry(payment_hash.clone())
+                            .or_insert_with(|| TrackedPayment {
+                                pending: PendingSettlement {
+                                    payment_hash,
+                                    created_at: Utc::now(),
+                                    expires_at: Utc::now(),
+                                },
+                                next_check: now,
+                                in_flight: false,
+                                recheck: false,
+                            });
+                        payment.next_check = now;
+                        payment.recheck = payment.in_flight;
+                    }
+                    None => {
+                        info!("Settlement reconciler stopped");
+                        return;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let now = Instant::now();
+                let available = options.max_concurrency.saturating_sub(checks.len());
+                let due: Vec<String> = tracked
+                    .iter()
+                    .filter(|(_, payment)| !payment.in_flight && payment.next_check <= now)
+                    .map(|(hash, _)| hash.clone())
+                    .take(options.batch_size.min(available))
+                    .collect();
+
+                for payment_hash in due {
+                    if let Some(payment) = tracked.get_mut(&payment_hash) {
+                        payment.in_flight = true;
+                    }
+                    let service = service.clone();
+                    let check_hash = payment_hash.clone();
+                    let handle = checks.spawn(async move {
+                        service.check_settlement(&check_hash).await
+                    });
+                    running.insert(handle.id(), payment_hash);
+                }
+            }
+            Some(joined) = checks.join_next_with_id(), if !checks.is_empty() => {
+                // A failed task is retried with backoff like a failed check
+                let (id, result) = match joined {
+                    Ok((id, result)) => (id, result.map_err(|e| e.to_string())),
+                    Err(e) => {
+                        error!("Settlement check task failed: {}", e);
+                        (e.id(), Err(e.to_string()))
+                    }
+                };
+                let Some(payment_hash) = running.remove(&id) else {
+                    continue;
+                };
+
+                let Some(payment) = tracked.get_mut(&payment_hash) else {
+                    continue;
+                };
+                payment.in_flight = false;
+                let recheck = std::mem::take(&mut payment.recheck);
+
+                match result {
+                    Ok(SettlementCheck::Terminal) => {
+                        debug!("Stopped tracking payment {}", payment_hash);
+                        tracked.remove(&payment_hash);
+                    }
+                    Ok(SettlementCheck::Pending) if recheck => {
+                        payment.next_check = Instant::now();
+                    }
+                    Ok(SettlementCheck::Pending) => {
+                        let age = Utc::now() - payment.pending.created_at;
+                        let push = push_connected.load(Ordering::Relaxed);
+                        payment.next_check = after(options.check_interval(age, push));
+                    }
+                    Err(e) => {
+                        warn!("Error checking payment {}: {}", payment_hash, e);
+                        let now = Utc::now();
+                        if now > payment.pending.expires_at + options.max_interval {
+                            warn!("Giving up on expired payment {}", payment_hash);
+                            tracked.remove(&payment_hash);
+                            continue;
+                        }
+                        let age = now - payment.pending.created_at;
+                        let push = push_connected.load(Ordering::Relaxed);
+                        payment.next_check = after(options.check_interval(age, push));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// When a check `interval` from now is due
+fn after(interval: chrono::Duration) -> Instant {
+    Instant::now() + interval.to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Checker answering every check with `result` after `duration`
+    #[derive(Clone)]
+    struct StubChecker {
+        result: SettlementCheck,
+        duration: time::Duration,
+        /// When each check started
+        checks: Arc<Mutex<Vec<Instant>>>,
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl StubChecker {
+        fn new(result: SettlementCheck, duration: time::Duration) -> Self {
+            Self {
+                result,
+                duration,
+                checks: Arc::default(),
+                running: Arc::default(),
+                max_running: Arc::default(),
+            }
+        }
+
+        fn checks(&self) -> Vec<Instant> {
+            self.checks.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl SettlementChecker for StubChecker {
+        async fn check_settlement(
+            &self,
+            _payment_hash: &str,
+        ) -> Result<SettlementCheck, PaymentError> {
+            self.checks.lock().unwrap().push(Instant::now());
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            time::sleep(self.duration).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(self.result)
+        }
+    }
+
+    fn options(batch_size: usize, max_concurrency: usize) -> ReconcilerOptions {
+        ReconcilerOptions {
+            batch_size,
+            max_concurrency,
+            min_interval: chrono::Duration::seconds(10),
+            max_interval: chrono::Duration::seconds(60),
+        }
+    }
+
+    fn pending(payment_hash: &str, age: chrono::Duration) -> PendingSettlement {
+        PendingSettlement {
+            payment_hash: payment_hash.to_string(),
+            created_at: Utc::now() - age,
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        }
+    }
+
+    /// Track payments of the given age and let the reconciler run for `duration`
+    ///
+    /// The reconciler stops once the returned handle is dropped.
+    async fn run_for(
+        checker: &StubChecker,
+        options: ReconcilerOptions,
+        push_connected: bool,
+        payments: usize,
+        age: chrono::Duration,
+        duration: time::Duration,
+    ) -> SettlementReconciler {
+        let push_connected = Arc::new(AtomicBool::new(push_connected));
+        let reconciler = SettlementReconciler::spawn(checker.clone(), options, push_connected);
+        for i in 0..payments {
+            reconciler.track(pending(&format!("hash{i}"), age));
+        }
+        time::sleep(duration).await;
+        reconciler
+    }
+
+    #[test]
+    fn check_interval_doubles_every_two_minutes_up_to_the_maximum() {
+        let options = options(1, 1);
+        let interval = |minutes| {
+            options
+                .check_interval(chrono::Duration::minutes(minutes), false)
+                .num_seconds()
+        };
+
+        assert_eq!(interval(0), 10);
+        assert_eq!(interval(1), 10);
+        assert_eq!(interval(2), 20);
+        assert_eq!(interval(4), 40);
+        assert_eq!(interval(6), 60);
+        assert_eq!(interval(600), 60);
+        assert_eq!(
+            options
+                .check_interval(chrono::Duration::zero(), true)
+                .num_seconds(),
+            60
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn checks_are_started_a_batch_per_tick() {
+        let checker = StubChecker::new(SettlementCheck::Pending, time::Duration::ZERO);
+        let start = Instant::now();
+
+        run_for(
+            &checker,
+            options(2, 10),
+            false,
+            5,
+            chrono::Duration::zero(),
+            time::Duration::from_millis(11_250),
+        )
+        .await;
+
+        // Due after the minimum interval, then two per 500ms tick
+        let offsets: Vec<_> = checker
+            .checks()
+            .iter()
+            .map(|check| (*check - start).as_millis())
+            .collect();
+        assert_eq!(offsets, [10_000, 10_000, 10_500, 10_500, 11_000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_checks_are_capped() {
+        let checker = StubChecker::new(SettlementCheck::Pending, time::Duration::from_secs(5));
+
+        let _reconciler = run_for(
+            &checker,
+            options(10, 3),
+            false,
+            5,
+            chrono::Duration::zero(),
+            time::Duration::from_secs(12),
+        )
+        .await;
+        assert_eq!(checker.checks().len(), 3);
+
+        time::sleep(time::Duration::from_secs(5)).await;
+        assert_eq!(checker.checks().len(), 5);
+        assert_eq!(checker.max_running.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn older_invoices_are_checked_less_often() {
+        let checker = StubChecker::new(SettlementCheck::Pending, time::Duration::ZERO);
+
+        run_for(
+            &checker,
+            options(10, 10),
+            false,
+            1,
+            chrono::Duration::minutes(4),
+            time::Duration::from_secs(55),
+        )
+        .await;
+
+        let checks = checker.checks();
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[1] - checks[0], time::Duration::from_secs(40));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_connected_push_channel_backs_polling_off() {
+        let checker = StubChecker::new(SettlementCheck::Pending, time::Duration::ZERO);
+
+        let _reconciler = run_for(
+            &checker,
+            options(10, 10),
+            true,
+            1,
+            chrono::Duration::zero(),
+            time::Duration::from_secs(65),
+        )
+        .await;
+
+        // Without the push channel it would have been checked every 10s
+        assert_eq!(checker.checks().len(), 1);
+        time::sleep(time::Duration::from_secs(10)).await;
+        let checks = checker.checks();
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[1] - checks[0], time::Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn terminal_payments_are_no_longer_checked() {
+        let checker = StubChecker::new(SettlementCheck::Terminal, time::Duration::ZERO);
+
+        run_for(
+            &checker,
+            options(10, 10),
+            false,
+            2,
+            chrono::Duration::zero(),
+            time::Duration::from_secs(600),
+        )
+        .await;
+
+        assert_eq!(checker.checks().len(), 2);
+    }
+}
@@ -0,0 +1,278 @@
+//! Live feeds over WebSocket, charged per message or per minute
+//!
+//! A server-side ticker (or each message) spends credits atomically from the
+//! subscriber's balance. Subscribers are warned once their balance runs low and
+//! disconnected when the next charge can't be covered. Charges for a session that
+//! never starts, or a message that can't be sent, are refunded.
+
+use super::{PaymentChallenge, rejection};
+use crate::config::{FeedBilling, FeedPricing};
+use crate::models::{CreditReason, PaymentRequiredResponse, User};
+use crate::storage::{Storage, StorageError};
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    response::Response,
+};
+use serde::Serialize;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::time::{self, Duration, Instant};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info};
+
+/// Close code telling a subscriber they ran out of credits
+pub const PAYMENT_REQUIRED_CLOSE_CODE: u16 = 4402;
+
+/// Length of a billed minute
+const MINUTE: Duration = Duration::from_secs(60);
+
+type Messages = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+/// Frame warning a subscriber to top up before they are disconnected
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "balance_warning")]
+struct BalanceWarning {
+    /// Credits left
+    credits: u32,
+    /// Offers the subscriber can buy more credits with
+    payment_required: PaymentRequiredResponse,
+}
+
+/// Serves WebSocket feeds charged according to a [`FeedPricing`]
+#[derive(Clone)]
+pub struct MeteredFeed {
+    pricing: FeedPricing,
+    storage: Arc<dyn Storage>,
+    challenge: Arc<dyn PaymentChallenge>,
+}
+
+impl MeteredFeed {
+    /// Create a feed charging according to `pricing` from the balances in `storage`
+    pub fn new(
+        pricing: FeedPricing,
+        storage: Arc<dyn Storage>,
+        challenge: impl PaymentChallenge,
+    ) -> Self {
+        Self {
+            pricing,
+            storage,
+            challenge: Arc::new(challenge),
+        }
+    }
+
+    /// Upgrade a request to a session relaying `messages` to the user
+    ///
+    /// Per-minute feeds charge the first minute before upgrading, and per-message
+    /// feeds check the first message is affordable, so callers who can't pay get a
+    /// 402 instead of a socket. The first minute is refunded if the upgrade fails.
+    pub async fn serve<S>(
+        &self,
+        upgrade: WebSocketUpgrade,
+        user_id: String,
+        request_id: Option<String>,
+        route: &str,
+        messages: S,
+    ) -> Response
+    where
+        S: Stream<Item = String> + Send + 'static,
+    {
+        let session = Session {
+            feed: self.clone(),
+            user_id,
+            request_id,
+            route: route.to_string(),
+            warned: false,
+        };
+
+        let user = match self.pricing.billing {
+            FeedBilling::Minute => session.charge().await,
+            FeedBilling::Message => match self.storage.get_user(&session.user_id).await {
+                Ok(user) if user.credits < self.pricing.credits => {
+                    Err(StorageError::InsufficientCredits {
+                        balance: user.credits,
+                    })
+                }
+                result => result,
+            },
+        };
+        let credits = match user {
+            Ok(user) => user.credits,
+            Err(e) => {
+                return rejection(self.challenge.as_ref(), &session.user_id, &session.route, e);
+            }
+        };
+
+        let charged_upfront = self.pricing.billing == FeedBilling::Minute;
+        let unstarted = session.clone();
+        upgrade
+            .on_failed_upgrade(move |e| {
+                info!(
+                    "User {} failed to connect to {}: {}",
+                    unstarted.user_id, unstarted.route, e
+                );
+                if charged_upfront {
+                    tokio::spawn(async move { unstarted.refund().await });
+                }
+            })
+            .on_upgrade(move |socket| session.run(socket, Box::pin(messages), credits))
+    }
+}
+
+/// One subscriber's connection to a feed
+#[derive(Clone)]
+struct Session {
+    feed: MeteredFeed,
+    user_id: String,
+    request_id: Option<String>,
+    route: String,
+    /// Whether the subscriber was warned since their balance last ran low
+    warned: bool,
+}
+
+impl Session {
+    /// Spend the price of a message or minute
+    async fn charge(&self) -> Result<User, StorageError> {
+        self.feed
+            .storage
+            .spend_user_credits(
+                &self.user_id,
+                self.feed.pricing.credits,
+                self.request_id.as_deref(),
+                Some(&self.route),
+            )
+            .await
+    }
+
+    /// Give back the price of a message or minute that wasn't delivered
+    async fn refund(&self) {
+        let credits = self.feed.pricing.credits;
+        match self
+            .feed
+            .storage
+            .update_user_credits(
+                &self.user_id,
+                i32::try_from(credits).unwrap_or(i32::MAX),
+                CreditReason::Refund,
+                self.request_id.as_deref(),
+                Some(&self.route),
+            )
+            .await
+        {
+            Ok(_) => info!(
+                "Refunded {} credits to user {} for {}",
+                credits, self.user_id, self.route
+            ),
+            Err(e) => error!(
+                "Failed to refund {} credits to user {} for {}: {}",
+                credits, self.user_id, self.route, e
+            ),
+        }
+    }
+
+    async fn run(mut self, mut socket: WebSocket, mut messages: Messages, credits: u32) {
+        info!("User {} subscribed to {}", self.user_id, self.route);
+        let per_minute = self.feed.pricing.billing == FeedBilling::Minute;
+        let mut ticker = time::interval_at(Instant::now() + MINUTE, MINUTE);
+
+        let close = if self.warn_if_low(&mut socket, credits).await.is_err() {
+            None
+        } else {
+            loop {
+                tokio::select! {
+                    received = socket.recv() => match received {
+                        // Pings are answered while receiving, anything else is ignored
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                        Some(Ok(_)) => {}
+                    },
+                    _ = ticker.tick(), if per_minute => match self.charge().await {
+                        Ok(user) => {
+                            if self.warn_if_low(&mut socket, user.credits).await.is_err() {
+                                break None;
+                            }
+                        }
+                        Err(e) => break Some(self.close_frame(e)),
+                    },
+                    message = messages.next() => {
+                        let Some(message) = message else {
+                            break Some(CloseFrame {
+                                code: close_code::NORMAL,
+                                reason: "Feed ended".into(),
+                            });
+                        };
+
+                        let credits = if per_minute {
+                            None
+                        } else {
+                            match self.charge().await {
+                                Ok(user) => Some(user.credits),
+                                Err(e) => break Some(self.close_frame(e)),
+                            }
+                        };
+                        if socket.send(Message::Text(message.into())).await.is_err() {
+                            if credits.is_some() {
+                                self.refund().await;
+                            }
+                            break None;
+                        }
+                        if let Some(credits) = credits
+                            && self.warn_if_low(&mut socket, credits).await.is_err()
+                        {
+                            break None;
+                        }
+                    }
+                }
+            }
+        };
+
+        if let Some(close) = close {
+            let _ = socket.send(Message::Close(Some(close))).await;
+        }
+        info!("User {} left {}", self.user_id, self.route);
+    }
+
+    /// Warn the subscriber once their balance runs low, and again after a top-up
+    async fn warn_if_low(
+        &mut self,
+        socket: &mut WebSocket,
+        credits: u32,
+    ) -> Result<(), axum::Error> {
+        if credits > self.feed.pricing.warn_credits {
+            self.warned = false;
+            return Ok(());
+        }
+        if self.warned {
+            return Ok(());
+        }
+        self.warned = true;
+
+        let warning = BalanceWarning {
+            credits,
+            payment_required: self.feed.challenge.payment_required(&self.user_id),
+        };
+        let warning = serde_json::to_string(&warning).map_err(axum::Error::new)?;
+        socket.send(Message::Text(warning.into())).await
+    }
+
+    /// Close frame for a charge that failed
+    fn close_frame(&self, error: StorageError) -> CloseFrame {
+        match error {
+            StorageError::InsufficientCredits { .. } => {
+                info!("User {} ran out of credits on {}", self.user_id, self.route);
+                CloseFrame {
+                    code: PAYMENT_REQUIRED_CLOSE_CODE,
+                    reason: "Payment required".into(),
+                }
+            }
+            e => {
+                error!(
+                    "Failed to charge user {} for {}: {}",
+                    self.user_id, self.route, e
+                );
+                CloseFrame {
+                    code: close_code::ERROR,
+                    reason: "Failed to charge credits".into(),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Charging for response bodies as they stream
+//!
+//! Credits are held ahead of the body, together with the price of the request,
+//! and once the body ends the hold is released and what was used is spent in a
+//! single ledger entry. When a hold can't be covered the body is cut off at the
+//! last byte paid for. Bytes are charged as the server takes them from the body to
+//! write to the client, so frames still buffered when the client goes away are free.
+
+use super::CREDITS_EXHAUSTED_TRAILER;
+use crate::config::{MeterUnit, Metering};
+use crate::storage::{Storage, StorageError};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, header},
+    response::Response,
+};
+use http_body::Frame;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+use tokio_stream::StreamExt;
+use tracing::{error, info};
+
+/// Body frames buffered between the route and the client
+const FRAME_BUFFER: usize = 16;
+
+type FrameSender = mpsc::Sender<Result<Frame<Bytes>, io::Error>>;
+
+/// Why a metered body ended early
+enum MeterError {
+    /// The user ran out of credits
+    Exhausted,
+    /// Credits couldn't be held
+    Storage(StorageError),
+    /// The route's body failed
+    Body(axum::Error),
+}
+
+impl From<StorageError> for MeterError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::InsufficientCredits { .. } => MeterError::Exhausted,
+            e => MeterError::Storage(e),
+        }
+    }
+}
+
+/// Credits held and used for one metered response
+pub(super) struct Meter {
+    storage: Arc<dyn Storage>,
+    user_id: String,
+    request_id: Option<String>,
+    route: String,
+    metering: Metering,
+    /// Price of the request itself, held with the first metered credit
+    price: u64,
+    /// Credits held for the response so far, including its price
+    held: u64,
+    /// Metered credits covering what has been sent
+    used: u64,
+}
+
+impl Meter {
+    pub(super) fn new(
+        storage: Arc<dyn Storage>,
+        user_id: String,
+        request_id: Option<String>,
+        route: String,
+        metering: Metering,
+        price: u32,
+    ) -> Self {
+        Self {
+            storage,
+            user_id,
+            request_id,
+            route,
+            metering,
+            price: price as u64,
+            held: 0,
+            used: 0,
+        }
+    }
+
+    /// Hold enough credits to cover the price and `needed` metered credits
+    ///
+    /// Credits are held `hold_credits` at a time so most chunks don't touch storage,
+    /// falling back to exactly what is needed when the user has less left. Fails
+    /// with [`StorageError::InsufficientCredits`] if the user can't afford them.
+    pub(super) async fn hold(&mut self, needed: u64) -> Result<(), StorageError> {
+        let needed = self.price + needed;
+        if needed <= self.held {
+            return Ok(());
+        }
+
+        // The price isn't part of a batch, it's held on top of the first one
+        let missing = needed - self.held;
+        let unheld_price = self.price.saturating_sub(self.held);
+        let batch = missing.max(unheld_price + self.metering.hold_credits as u64);
+        match self.hold_more(batch).await {
+            Err(StorageError::InsufficientCredits { .. }) if batch > missing => {
+                self.hold_more(missing).await
+            }
+            result => result,
+        }
+    }
+
+    /// Add credits to the hold
+    async fn hold_more(&mut self, amount: u64) -> Result<(), StorageError> {
+        let amount = u32::try_from(amount).unwrap_or(u32::MAX);
+        self.storage
+            .hold_user_credits(
+                &self.user_id,
+                amount,
+                self.request_id.as_deref(),
+                Some(&self.route),
+            )
+            .await?;
+        self.held += amount as u64;
+        Ok(())
+    }
+
+    /// Metered credits held, on top of the price
+    fn metered_held(&self) -> u64 {
+        self.held.saturating_sub(self.price)
+    }
+
+    /// Spend the price and the metered credits used, releasing the rest of the hold
+    pub(super) async fn settle(self) {
+        info!(
+            "User {} used {} metered credits on {}",
+            self.user_id, self.used, self.route
+        );
+        let spent = self.price + self.used;
+        self.finish(spent).await;
+    }
+
+    /// Release the whole hold without charging, for a response that failed
+    pub(super) async fn release(self) {
+        self.finish(0).await;
+    }
+
+    async fn finish(self, spent: u64) {
+        if self.held == 0 {
+            return;
+        }
+
+        let held = u32::try_from(self.held).unwrap_or(u32::MAX);
+        let spent = u32::try_from(spent.min(self.held)).unwrap_or(u32::MAX);
+        if let Err(e) = self
+            .storage
+            .settle_held_credits(
+                &self.user_id,
+                held,
+                spent,
+                self.request_id.as_deref(),
+                Some(&self.route),
+            )
+            .await
+        {
+            error!(
+                "Failed to settle {} held credits of user {} for {}: {}",
+                held, self.user_id, self.route, e
+            );
+        }
+    }
+
+    /// Meter the body of a response, settling once it ends
+    ///
+    /// A body cut off for lack of credits ends cleanly after the last byte paid
+    /// for, with an `X-Credits-Exhausted` trailer for clients that accept trailers.
+    pub(super) fn meter(self, response: Response, trailers: bool) -> Response {
+        let (mut parts, body) = response.into_parts();
+        // The length can't be promised when the body may be cut off
+        parts.headers.remove(header::CONTENT_LENGTH);
+        if trailers {
+            parts.headers.insert(
+                header::TRAILER,
+                HeaderValue::from_static(CREDITS_EXHAUSTED_TRAILER),
+            );
+        }
+
+        let (tx, rx) = mpsc::channel(FRAME_BUFFER);
+        let (dropped_tx, dropped) = oneshot::channel();
+        let delivered = Arc::new(AtomicU64::new(0));
+        tokio::spawn(self.run(body, tx, trailers, delivered.clone(), dropped));
+
+        Response::from_parts(
+            parts,
+            Body::new(MeteredBody {
+                frames: rx,
+                delivered,
+                _dropped: dropped_tx,
+            }),
+        )
+    }
+
+    async fn run(
+        mut self,
+        body: Body,
+        tx: FrameSender,
+        trailers: bool,
+        delivered: Arc<AtomicU64>,
+        dropped: oneshot::Receiver<()>,
+    ) {
+        let result = match self.metering.unit {
+            MeterUnit::Bytes => self.meter_bytes(body, &tx).await,
+            MeterUnit::Seconds => self.meter_seconds(body, &tx).await,
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(MeterError::Exhausted) => {
+                info!(
+                    "User {} ran out of credits streaming {}",
+                    self.user_id, self.route
+                );
+                if trailers {
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert(CREDITS_EXHAUSTED_TRAILER, HeaderValue::from_static("true"));
+                    let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+                }
+            }
+            Err(MeterError::Storage(e)) => {
+                error!(
+                    "Failed to hold credits for user {} on {}: {}",
+                    self.user_id, self.route, e
+                );
+                let _ = tx.send(Err(io::Error::other(e))).await;
+            }
+            Err(MeterError::Body(e)) => {
+                let _ = tx.send(Err(io::Error::other(e))).await;
+            }
+        }
+
+        // Bytes are charged once the server has taken them, which is only known
+        // for sure when it drops the body
+        if self.metering.unit == MeterUnit::Bytes {
+            drop(tx);
+            let _ = dropped.await;
+            let delivered = delivered.load(Ordering::SeqCst);
+            self.used = delivered
+                .div_ceil(self.metering.per_credit.get())
+                .min(self.metered_held());
+        }
+
+        self.settle().await;
+    }
+
+    /// Hold a credit per `per_credit` bytes of the body, as it is produced
+    ///
+    /// What is used is worked out from the bytes delivered once the body is done.
+    async fn meter_bytes(&mut self, body: Body, tx: &FrameSender) -> Result<(), MeterError> {
+        let per_credit = self.metering.per_credit.get();
+        let mut body = body.into_data_stream();
+        let mut sent: u64 = 0;
+
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk.map_err(MeterError::Body)?;
+            let needed = (sent + chunk.len() as u64).div_ceil(per_credit);
+
+            match self.hold(needed).await.map_err(MeterError::from) {
+                Ok(()) => {}
+                Err(MeterError::Exhausted) => {
+                    // Send what the credits already held cover
+                    let covered = (self.metered_held() * per_credit).saturating_sub(sent);
+                    chunk.truncate(covered as usize);
+                    if !chunk.is_empty() {
+                        let _ = tx.send(Ok(Frame::data(chunk))).await;
+                    }
+                    return Err(MeterError::Exhausted);
+                }
+                Err(e) => return Err(e),
+            }
+
+            sent += chunk.len() as u64;
+            if tx.send(Ok(Frame::data(chunk))).await.is_err() {
+                // The client went away
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Charge a credit per `per_credit` seconds started while streaming
+    async fn meter_seconds(&mut self, body: Body, tx: &FrameSender) -> Result<(), MeterError> {
+        let period = Duration::from_secs(self.metering.per_credit.get());
+        let mut ticker = time::interval_at(Instant::now() + period, period);
+        let mut body = body.into_data_stream();
+
+        // The first period was held before the route was called
+        let mut periods = 1;
+        self.used = periods;
+
+        loop {
+            tokio::select! {
+                chunk = body.next() => match chunk {
+                    Some(Ok(chunk)) => {
+                        if tx.send(Ok(Frame::data(chunk))).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(e)) => return Err(MeterError::Body(e)),
+                    None => return Ok(()),
+                },
+                // Stop charging as soon as the client goes away, even while idle
+                _ = tx.closed() => return Ok(()),
+                _ = ticker.tick() => {
+                    periods += 1;
+                    self.hold(periods).await?;
+                    self.used = periods;
+                }
+            }
+        }
+    }
+}
+
+/// Body relaying the frames of the metering task
+///
+/// Counts the bytes the server takes from it, and lets the task know when the
+/// server is done with it by being dropped.
+struct MeteredBody {
+    frames: mpsc::Receiver<Result<Frame<Bytes>, io::Error>>,
+    delivered: Arc<AtomicU64>,
+    _dropped: oneshot::Sender<()>,
+}
+
+impl http_body::Body for MeteredBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let poll = self.frames.poll_recv(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.delivered
+                .fetch_add(data.len() as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+}
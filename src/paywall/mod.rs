@@ -0,0 +1,322 @@
+//! Tower layer charging credits for requests to axum routes
+//!
+//! [`L402Layer`] can wrap any router: requests to priced routes are charged from
+//! the caller's credits, and callers without enough credits get the standard 402
+//! challenge quoting the offers they can buy. Routes can also be metered, charging
+//! for their response bodies by size or streaming time.
+
+mod feed;
+mod metering;
+
+pub use feed::MeteredFeed;
+
+use crate::api::auth::UserId;
+use crate::config::{Metering, PricingTable};
+use crate::models::{CreditReason, PaymentRequiredResponse};
+use crate::payments::PaymentService;
+use crate::storage::{Storage, StorageError};
+use axum::{
+    Json,
+    extract::{FromRequestParts, MatchedPath, Request},
+    http::{HeaderMap, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use metering::Meter;
+use serde_json::json;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::{error, info};
+
+/// Header carrying the ID assigned to each request
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header telling the caller how many credits they have left
+pub const CREDITS_REMAINING_HEADER: &str = "x-credits-remaining";
+
+/// Trailer ending a metered body that was cut off for lack of credits
+pub const CREDITS_EXHAUSTED_TRAILER: &str = "x-credits-exhausted";
+
+/// Decides what a request costs
+pub trait Pricing: Send + Sync + 'static {
+    /// Credits charged for a request to a route, `None` if the route is free
+    ///
+    /// `route` is the path as registered with the router (e.g. `/items/{id}`).
+    fn price(&self, method: &Method, route: &str) -> Option<u32>;
+
+    /// Credits charged for a request, `None` if it is free
+    ///
+    /// Defaults to [`Pricing::price`] for the request's method; override it to
+    /// price on anything else in the request, such as its host.
+    fn price_request(&self, request: &Request, route: &str) -> Option<u32> {
+        self.price(request.method(), route)
+    }
+
+    /// How the response to a request is metered on top of its price, `None` if it isn't
+    fn metering(&self, _request: &Request, _route: &str) -> Option<Metering> {
+        None
+    }
+}
+
+impl Pricing for PricingTable {
+    fn price(&self, method: &Method, route: &str) -> Option<u32> {
+        self.route_price(method.as_str(), route)
+    }
+
+    fn metering(&self, request: &Request, route: &str) -> Option<Metering> {
+        self.route_metering(request.method().as_str(), route)
+    }
+}
+
+impl<F> Pricing for F
+where
+    F: Fn(&Method, &str) -> Option<u32> + Send + Sync + 'static,
+{
+    fn price(&self, method: &Method, route: &str) -> Option<u32> {
+        self(method, route)
+    }
+}
+
+/// Builds the 402 challenge for a user who is out of credits
+pub trait PaymentChallenge: Send + Sync + 'static {
+    /// Quote the available offers to a user
+    fn payment_required(&self, user_id: &str) -> PaymentRequiredResponse;
+}
+
+impl PaymentChallenge for PaymentService {
+    fn payment_required(&self, user_id: &str) -> PaymentRequiredResponse {
+        PaymentService::payment_required(self, user_id)
+    }
+}
+
+/// Layer charging credits for requests, per a [`Pricing`]
+///
+/// Callers are identified by their `Authorization: Bearer <user_id>` header.
+/// Credits are spent before the request reaches the route, so concurrent requests
+/// can't overdraw a balance, and refunded if the route doesn't respond with a
+/// success. Add it with `Router::route_layer` so the matched route is known;
+/// otherwise prices are looked up by request path.
+#[derive(Clone)]
+pub struct L402Layer {
+    paywall: Arc<Paywall>,
+}
+
+impl L402Layer {
+    /// Create a layer charging according to `pricing` from the balances in `storage`
+    pub fn new(
+        pricing: impl Pricing,
+        storage: Arc<dyn Storage>,
+        challenge: impl PaymentChallenge,
+    ) -> Self {
+        Self {
+            paywall: Arc::new(Paywall {
+                pricing: Box::new(pricing),
+                storage,
+                challenge: Box::new(challenge),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for L402Layer {
+    type Service = L402Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        L402Service {
+            inner,
+            paywall: self.paywall.clone(),
+        }
+    }
+}
+
+/// Service produced by [`L402Layer`]
+#[derive(Clone)]
+pub struct L402Service<S> {
+    inner: S,
+    paywall: Arc<Paywall>,
+}
+
+impl<S> Service<Request> for L402Service<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Use the service that was polled ready, leaving a fresh clone in its place
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        let paywall = self.paywall.clone();
+
+        Box::pin(async move { Ok(paywall.handle(request, inner).await) })
+    }
+}
+
+/// What the layer charges with
+struct Paywall {
+    pricing: Box<dyn Pricing>,
+    storage: Arc<dyn Storage>,
+    challenge: Box<dyn PaymentChallenge>,
+}
+
+impl Paywall {
+    /// Charge for a request and pass it on, or answer it with a 402
+    async fn handle<S>(&self, request: Request, mut inner: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible>,
+    {
+        let route = match request.extensions().get::<MatchedPath>() {
+            Some(route) => route.as_str().to_string(),
+            None => request.uri().path().to_string(),
+        };
+
+        let cost = self.pricing.price_request(&request, &route);
+        let metering = self.pricing.metering(&request, &route);
+        if cost.is_none() && metering.is_none() {
+            let Ok(response) = inner.call(request).await;
+            return response;
+        }
+        let cost = cost.unwrap_or(0);
+        let trailers = accepts_trailers(request.headers());
+
+        let (mut parts, body) = request.into_parts();
+        let user_id = match UserId::from_request_parts(&mut parts, &()).await {
+            Ok(UserId(user_id)) => user_id,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        // Metered routes hold their price with the first metered credit up front,
+        // so callers who can't afford any of the body get a 402 rather than an
+        // empty response, and pay for both in one spend once the body ends
+        if let Some(metering) = metering {
+            let mut meter = Meter::new(
+                self.storage.clone(),
+                user_id.clone(),
+                request_id,
+                route.clone(),
+                metering,
+                cost,
+            );
+            if let Err(e) = meter.hold(1).await {
+                return rejection(self.challenge.as_ref(), &user_id, &route, e);
+            }
+
+            let Ok(response) = inner.call(Request::from_parts(parts, body)).await;
+
+            // Only successful requests are paid for
+            if !response.status().is_success() {
+                meter.release().await;
+                return response;
+            }
+            return meter.meter(response, trailers);
+        }
+
+        let charged = if cost > 0 {
+            self.storage
+                .spend_user_credits(&user_id, cost, request_id.as_deref(), Some(&route))
+                .await
+                .inspect(|_| info!("User {} spent {} credits on {}", user_id, cost, route))
+        } else {
+            self.storage.get_user(&user_id).await
+        };
+        if let Err(e) = charged {
+            return rejection(self.challenge.as_ref(), &user_id, &route, e);
+        }
+
+        let Ok(response) = inner.call(Request::from_parts(parts, body)).await;
+
+        // Only successful requests are paid for
+        if !response.status().is_success() {
+            self.refund(&user_id, cost, request_id.as_deref(), &route)
+                .await;
+        }
+        response
+    }
+
+    /// Give back the price of a request that didn't succeed
+    async fn refund(&self, user_id: &str, cost: u32, request_id: Option<&str>, route: &str) {
+        if cost == 0 {
+            return;
+        }
+
+        match self
+            .storage
+            .update_user_credits(
+                user_id,
+                cost as i32,
+                CreditReason::Refund,
+                request_id,
+                Some(route),
+            )
+            .await
+        {
+            Ok(_) => info!(
+                "Refunded {} credits to user {} for {}",
+                cost, user_id, route
+            ),
+            Err(e) => error!(
+                "Failed to refund {} credits to user {} for {}: {}",
+                cost, user_id, route, e
+            ),
+        }
+    }
+}
+
+/// Response for a request that couldn't be charged
+fn rejection(
+    challenge: &dyn PaymentChallenge,
+    user_id: &str,
+    route: &str,
+    error: StorageError,
+) -> Response {
+    match error {
+        StorageError::InsufficientCredits { balance } => {
+            info!("User {} is out of credits", user_id);
+            let payment_required = challenge.payment_required(user_id);
+            (
+                StatusCode::PAYMENT_REQUIRED,
+                [(CREDITS_REMAINING_HEADER, balance.to_string())],
+                Json(payment_required),
+            )
+                .into_response()
+        }
+        StorageError::UserNotFound => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "User not found"})),
+        )
+            .into_response(),
+        e => {
+            error!("Failed to charge user {} for {}: {}", user_id, route, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Failed to charge credits"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Whether the client asked for trailers (`TE: trailers`)
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("trailers"))
+}
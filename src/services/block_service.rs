@@ -1,8 +1,16 @@
 use crate::models::BlockData;
 use anyhow::Result;
 use reqwest::Client;
+use std::sync::Arc;
 use thiserror::Error;
-use tracing::{error, info};
+use tokio::sync::watch;
+use tokio::time;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, warn};
+
+/// How often the latest block is polled while anyone is watching it
+const FEED_POLL_INTERVAL_SECS: u64 = 30;
 
 /// Errors that can occur when fetching block data
 #[derive(Debug, Error)]
@@ -25,19 +33,60 @@ pub enum BlockDataError {
 #[derive(Clone)]
 pub struct BlockService {
     client: Client,
+    latest: Arc<watch::Sender<Option<BlockData>>>,
+}
+
+impl Default for BlockService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockService {
     /// Create a new block service instance
-    ///
-    /// The _storage parameter is kept for compatibility with the previous API
-    /// but is no longer used since caching was removed
-    pub fn new(_storage: crate::storage::RedisStorage) -> Self {
+    pub fn new() -> Self {
         Self {
             client: Client::new(),
+            latest: Arc::new(watch::channel(None).0),
         }
     }
 
+    /// Stream of the latest block, starting with the last one seen, then each new one
+    pub fn watch_blocks(&self) -> impl Stream<Item = BlockData> + Send + 'static {
+        WatchStream::new(self.latest.subscribe()).filter_map(|block| block)
+    }
+
+    /// Start a background task that polls the latest block while anyone is watching
+    pub fn start_feed(&self) {
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(time::Duration::from_secs(FEED_POLL_INTERVAL_SECS));
+            loop {
+                ticker.tick().await;
+                if service.latest.receiver_count() == 0 {
+                    continue;
+                }
+
+                match service.get_latest_block().await {
+                    Ok(block) => {
+                        service.latest.send_if_modified(|latest| {
+                            if latest
+                                .as_ref()
+                                .is_some_and(|latest| latest.hash == block.hash)
+                            {
+                                return false;
+                            }
+                            *latest = Some(block);
+                            true
+                        });
+                    }
+                    Err(e) => warn!("Error polling latest block for the feed: {}", e),
+                }
+            }
+        });
+    }
+
     /// Fetch the latest Bitcoin block hash
     pub async fn get_latest_block(&self) -> Result<BlockData, BlockDataError> {
         // Previously we cached this data, but that functionality has been removed
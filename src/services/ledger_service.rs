@@ -0,0 +1,206 @@
+use crate::models::{LedgerEntry, User};
+use crate::storage::{LedgerQuery, Storage, StorageError};
+use std::sync::Arc;
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// How many times to read a user whose credits keep changing while being verified
+const MAX_VERIFY_ATTEMPTS: usize = 3;
+
+/// A user whose stored balance disagrees with their ledger
+#[derive(Debug, Clone)]
+pub struct BalanceMismatch {
+    /// The affected user
+    pub user_id: String,
+    /// Balance stored on the user
+    pub balance: u32,
+    /// Balance recomputed from the ledger entries
+    pub ledger_balance: i64,
+    /// First entry whose recorded balance doesn't follow from the ones before it
+    pub broken_entry_id: Option<u64>,
+}
+
+/// Service checking that balances can be derived from the credit ledger
+#[derive(Clone)]
+pub struct LedgerService {
+    storage: Arc<dyn Storage>,
+}
+
+impl LedgerService {
+    /// Create a new ledger service instance
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    /// Recompute one user's balance from their ledger and compare it to the stored one
+    ///
+    /// Returns `None` if the two agree.
+    pub async fn verify_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<BalanceMismatch>, StorageError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            // Balances and entries are written together, so reading the user on both
+            // sides of the entries shows whether a change slipped in between
+            let before = self.storage.get_user(user_id).await?;
+            let entries = self
+                .storage
+                .list_ledger_entries(user_id, &LedgerQuery::default())
+                .await?;
+            let after = self.storage.get_user(user_id).await?;
+
+            if before.last_credit_update_at == after.last_credit_update_at
+                || attempt == MAX_VERIFY_ATTEMPTS
+            {
+                return Ok(check_balance(&after, &entries));
+            }
+        }
+    }
+
+    /// Verify every user's balance against the ledger
+    pub async fn verify_balances(&self) -> Result<Vec<BalanceMismatch>, StorageError> {
+        let user_ids = self.storage.list_user_ids().await?;
+
+        let mut mismatches = Vec::new();
+        for user_id in user_ids {
+            match self.verify_user(&user_id).await {
+                Ok(Some(mismatch)) => mismatches.push(mismatch),
+                Ok(None) => {}
+                // The user may have been removed since the IDs were listed
+                Err(StorageError::UserNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Start a background task that periodically verifies all balances
+    pub fn start_verifier(&self, interval_secs: u64) {
+        let service = self.clone();
+        let interval = time::Duration::from_secs(interval_secs.max(1));
+
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match service.verify_balances().await {
+                    Ok(mismatches) if mismatches.is_empty() => {
+                        info!("All balances match the credit ledger")
+                    }
+                    Ok(mismatches) => {
+                        for mismatch in &mismatches {
+                            error!(
+                                "Balance of user {} is {} but the ledger gives {} (first broken entry: {:?})",
+                                mismatch.user_id,
+                                mismatch.balance,
+                                mismatch.ledger_balance,
+                                mismatch.broken_entry_id
+                            );
+                        }
+                        warn!(
+                            "{} balances disagree with the credit ledger",
+                            mismatches.len()
+                        );
+                    }
+                    Err(e) => error!("Error verifying balances against the ledger: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Replay a user's ledger entries and compare the result to their balance
+fn check_balance(user: &User, entries: &[LedgerEntry]) -> Option<BalanceMismatch> {
+    // Users created on Redis before the ledger existed have no entries until
+    // their next credit change opens it with their balance; they aren't migrated
+    // yet rather than out of balance
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mut ledger_balance: i64 = 0;
+    let mut broken_entry_id = None;
+
+    for entry in entries {
+        ledger_balance += entry.amount;
+        if broken_entry_id.is_none() && entry.balance as i64 != ledger_balance {
+            broken_entry_id = Some(entry.id);
+        }
+    }
+
+    if ledger_balance == user.credits as i64 && broken_entry_id.is_none() {
+        return None;
+    }
+
+    Some(BalanceMismatch {
+        user_id: user.id.clone(),
+        balance: user.credits,
+        ledger_balance,
+        broken_entry_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreditReason;
+    use chrono::Utc;
+
+    fn entry(id: u64, amount: i64, balance: u32) -> LedgerEntry {
+        LedgerEntry {
+            id,
+            user_id: "user".to_string(),
+            amount,
+            balance,
+            reason: if amount < 0 {
+                CreditReason::Spend
+            } else {
+                CreditReason::Grant
+            },
+            reference: None,
+            route: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn user(credits: u32) -> User {
+        User {
+            id: "user".to_string(),
+            ..User::new(credits)
+        }
+    }
+
+    #[test]
+    fn users_without_entries_are_not_checked() {
+        assert!(check_balance(&user(5), &[]).is_none());
+    }
+
+    #[test]
+    fn consistent_ledgers_match() {
+        let entries = [entry(1, 3, 3), entry(2, -1, 2), entry(3, 5, 7)];
+        assert!(check_balance(&user(7), &entries).is_none());
+    }
+
+    #[test]
+    fn balances_off_the_ledger_are_reported() {
+        let entries = [entry(1, 3, 3), entry(2, -1, 2)];
+
+        let mismatch = check_balance(&user(4), &entries).unwrap();
+        assert_eq!(mismatch.balance, 4);
+        assert_eq!(mismatch.ledger_balance, 2);
+        assert_eq!(mismatch.broken_entry_id, None);
+    }
+
+    #[test]
+    fn the_first_broken_entry_is_reported() {
+        let entries = [entry(1, 3, 3), entry(2, -1, 5), entry(3, -1, 1)];
+
+        let mismatch = check_balance(&user(1), &entries).unwrap();
+        assert_eq!(mismatch.ledger_balance, 1);
+        assert_eq!(mismatch.broken_entry_id, Some(2));
+    }
+}
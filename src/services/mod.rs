@@ -1,3 +1,5 @@
 pub mod block_service;
+pub mod ledger_service;
 
 pub use block_service::BlockService;
+pub use ledger_service::LedgerService;
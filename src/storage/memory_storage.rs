@@ -0,0 +1,488 @@
+use super::{
+    LedgerQuery, Overdraft, Storage, StorageError, payment_request_ttl, publish_balance, settlement,
+};
+use crate::events::EventBus;
+use crate::models::{
+    CreditReason, IdempotencyRecord, LedgerEntry, PaymentRequest, PaymentStatus, User,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::info;
+
+/// A stored value that is dropped once it expires
+#[derive(Debug, Clone)]
+struct Expiring<T> {
+    value: T,
+    expires_at: DateTime<Utc>,
+}
+
+impl<T> Expiring<T> {
+    fn new(value: T, ttl_secs: u64) -> Self {
+        Self {
+            value,
+            expires_at: Utc::now() + Duration::seconds(ttl_secs as i64),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        Utc::now() < self.expires_at
+    }
+}
+
+/// Read a live entry, dropping it if it has expired
+fn live<'a, T>(map: &'a mut HashMap<String, Expiring<T>>, key: &str) -> Option<&'a T> {
+    if map.get(key).is_some_and(|entry| !entry.is_live()) {
+        map.remove(key);
+    }
+    map.get(key).map(|entry| &entry.value)
+}
+
+/// Drop the expired entries of a map, returning how many there were
+fn purge<T>(map: &mut HashMap<String, Expiring<T>>) -> u64 {
+    let before = map.len();
+    map.retain(|_, entry| entry.is_live());
+    (before - map.len()) as u64
+}
+
+#[derive(Debug, Default)]
+struct State {
+    users: HashMap<String, User>,
+    ledger: HashMap<String, Vec<LedgerEntry>>,
+    ledger_seq: u64,
+    payment_requests: HashMap<String, Expiring<PaymentRequest>>,
+    external_ids: HashMap<String, Expiring<String>>,
+    pending_payments: HashSet<String>,
+    webhook_events: HashMap<String, Expiring<()>>,
+    idempotency: HashMap<String, Expiring<IdempotencyRecord>>,
+}
+
+impl State {
+    /// Append an entry to a user's ledger
+    fn record(
+        &mut self,
+        user: &User,
+        amount: i64,
+        reason: CreditReason,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) {
+        self.ledger_seq += 1;
+        let entry = LedgerEntry {
+            id: self.ledger_seq,
+            user_id: user.id.clone(),
+            amount,
+            balance: user.credits,
+            reason,
+            reference: reference.map(str::to_string),
+            route: route.map(str::to_string),
+            created_at: user.last_credit_update_at,
+        };
+        self.ledger.entry(user.id.clone()).or_default().push(entry);
+    }
+
+    /// Apply credit changes in order and record each in the ledger
+    ///
+    /// If one of them is rejected none are applied.
+    fn change_credits(
+        &mut self,
+        user_id: &str,
+        changes: &[(i64, CreditReason)],
+        reference: Option<&str>,
+        route: Option<&str>,
+        overdraft: Overdraft,
+    ) -> Result<User, StorageError> {
+        let user = self
+            .users
+            .get_mut(user_id)
+            .ok_or(StorageError::UserNotFound)?;
+
+        // Work out every balance first, so a rejected change leaves nothing applied
+        let mut balances = Vec::with_capacity(changes.len());
+        let mut credits = user.credits as i64;
+        for (delta, reason) in changes {
+            let previous = credits;
+            credits = overdraft.apply(previous, *delta)?;
+            balances.push((credits - previous, credits, *reason));
+        }
+
+        user.last_credit_update_at = Utc::now();
+        let mut user = user.clone();
+        for (amount, credits, reason) in balances {
+            user.credits = u32::try_from(credits).unwrap_or(u32::MAX);
+            self.record(&user, amount, reason, reference, route);
+        }
+        self.users.insert(user.id.clone(), user.clone());
+
+        info!(
+            "Updated credits for user {}: delta={}, new balance={}",
+            user_id,
+            changes.iter().map(|(delta, _)| delta).sum::<i64>(),
+            user.credits
+        );
+
+        Ok(user)
+    }
+
+    /// Whether a payment request is stored with one of the given statuses
+    fn payment_status_is(&mut self, request_id: &str, from: &[PaymentStatus]) -> bool {
+        live(&mut self.payment_requests, request_id)
+            .is_some_and(|stored| from.contains(&stored.status))
+    }
+
+    /// Store a payment request and keep its indexes in sync
+    fn store_payment_request(&mut self, request: &PaymentRequest) {
+        let ttl = payment_request_ttl(request);
+
+        self.payment_requests
+            .insert(request.id.clone(), Expiring::new(request.clone(), ttl));
+
+        if let Some(ext_id) = &request.external_id {
+            self.external_ids
+                .insert(ext_id.clone(), Expiring::new(request.id.clone(), ttl));
+        }
+
+        if request.status == PaymentStatus::Pending {
+            self.pending_payments.insert(request.id.clone());
+        } else {
+            self.pending_payments.remove(&request.id);
+        }
+    }
+}
+
+/// Storage implementation keeping everything in process memory
+///
+/// Nothing survives a restart and nothing is shared between instances, so this is
+/// meant for tests and single-node demos.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    state: Arc<Mutex<State>>,
+    events: Option<EventBus>,
+}
+
+impl MemoryStorage {
+    /// URL scheme selecting this backend
+    pub const URL_SCHEME: &'static str = "memory://";
+
+    /// Create an empty in-memory storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish balance changes to the given event bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Apply credit changes in order and record each in the ledger
+    ///
+    /// If one of them is rejected none are applied.
+    async fn change_credits(
+        &self,
+        user_id: &str,
+        changes: &[(i64, CreditReason)],
+        reference: Option<&str>,
+        route: Option<&str>,
+        overdraft: Overdraft,
+    ) -> Result<User, StorageError> {
+        let user = self
+            .state()
+            .change_credits(user_id, changes, reference, route, overdraft)?;
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(user)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while holding the lock can't leave the maps half-updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn check_connection(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn create_user(&self, user: &User) -> Result<(), StorageError> {
+        let mut state = self.state();
+        state.users.insert(user.id.clone(), user.clone());
+        state.record(user, user.credits as i64, CreditReason::Grant, None, None);
+        info!("Created new user with ID: {}", user.id);
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: &str) -> Result<User, StorageError> {
+        self.state()
+            .users
+            .get(user_id)
+            .cloned()
+            .ok_or(StorageError::UserNotFound)
+    }
+
+    async fn update_user_credits(
+        &self,
+        user_id: &str,
+        delta: i32,
+        reason: CreditReason,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(delta as i64, reason)],
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    async fn spend_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Spend)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn hold_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Hold)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn settle_held_credits(
+        &self,
+        user_id: &str,
+        held: u32,
+        used: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &settlement(held, used),
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    async fn list_ledger_entries(
+        &self,
+        user_id: &str,
+        query: &LedgerQuery,
+    ) -> Result<Vec<LedgerEntry>, StorageError> {
+        Ok(self
+            .state()
+            .ledger
+            .get(user_id)
+            .map(|entries| query.apply(entries))
+            .unwrap_or_default())
+    }
+
+    async fn list_user_ids(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self.state().users.keys().cloned().collect())
+    }
+
+    async fn store_payment_request(&self, request: &PaymentRequest) -> Result<(), StorageError> {
+        self.state().store_payment_request(request);
+
+        info!(
+            "Stored payment request: id={}, method={:?}, offer={}",
+            request.id, request.method, request.offer_id
+        );
+        Ok(())
+    }
+
+    async fn transition_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        let mut state = self.state();
+        if !state.payment_status_is(&request.id, from) {
+            return Ok(false);
+        }
+
+        state.store_payment_request(request);
+        info!(
+            "Payment request {} moved to {:?}",
+            request.id, request.status
+        );
+        Ok(true)
+    }
+
+    async fn settle_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        let user = {
+            let mut state = self.state();
+            if !state.payment_status_is(&request.id, from) {
+                return Ok(false);
+            }
+
+            let user = state.change_credits(
+                &request.user_id,
+                &[(request.credits as i64, CreditReason::Purchase)],
+                Some(&request.id),
+                None,
+                Overdraft::Clamp,
+            )?;
+            state.store_payment_request(request);
+            user
+        };
+        info!(
+            "Payment request {} moved to {:?}",
+            request.id, request.status
+        );
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(true)
+    }
+
+    async fn get_payment_request(&self, request_id: &str) -> Result<PaymentRequest, StorageError> {
+        live(&mut self.state().payment_requests, request_id)
+            .cloned()
+            .ok_or(StorageError::PaymentRequestNotFound)
+    }
+
+    async fn get_payment_request_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> Result<PaymentRequest, StorageError> {
+        let mut state = self.state();
+        let request_id = live(&mut state.external_ids, external_id)
+            .cloned()
+            .ok_or(StorageError::PaymentRequestNotFound)?;
+
+        live(&mut state.payment_requests, &request_id)
+            .cloned()
+            .ok_or(StorageError::PaymentRequestNotFound)
+    }
+
+    async fn list_pending_payment_requests(&self) -> Result<Vec<PaymentRequest>, StorageError> {
+        let mut state = self.state();
+        let request_ids: Vec<String> = state.pending_payments.iter().cloned().collect();
+
+        let mut requests = Vec::with_capacity(request_ids.len());
+        for request_id in request_ids {
+            match live(&mut state.payment_requests, &request_id) {
+                Some(request) if request.status == PaymentStatus::Pending => {
+                    requests.push(request.clone())
+                }
+                _ => {
+                    state.pending_payments.remove(&request_id);
+                }
+            }
+        }
+
+        Ok(requests)
+    }
+
+    async fn claim_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+        retention_secs: u64,
+    ) -> Result<bool, StorageError> {
+        let key = format!("{}:{}", source, event_id);
+        let mut state = self.state();
+
+        if live(&mut state.webhook_events, &key).is_some() {
+            return Ok(false);
+        }
+        state
+            .webhook_events
+            .insert(key, Expiring::new((), retention_secs));
+        Ok(true)
+    }
+
+    async fn release_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+    ) -> Result<(), StorageError> {
+        let key = format!("{}:{}", source, event_id);
+        self.state().webhook_events.remove(&key);
+        Ok(())
+    }
+
+    async fn begin_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let mut state = self.state();
+
+        if let Some(existing) = live(&mut state.idempotency, key) {
+            return Ok(Some(existing.clone()));
+        }
+        state
+            .idempotency
+            .insert(key.to_string(), Expiring::new(record.clone(), ttl_secs));
+        Ok(None)
+    }
+
+    async fn complete_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<(), StorageError> {
+        self.state()
+            .idempotency
+            .insert(key.to_string(), Expiring::new(record.clone(), ttl_secs));
+        Ok(())
+    }
+
+    async fn abandon_idempotent_request(&self, key: &str) -> Result<(), StorageError> {
+        self.state().idempotency.remove(key);
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> Result<u64, StorageError> {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        let purged = purge(&mut state.webhook_events)
+            + purge(&mut state.idempotency)
+            + purge(&mut state.payment_requests)
+            + purge(&mut state.external_ids);
+        let requests = &state.payment_requests;
+        state
+            .pending_payments
+            .retain(|id| requests.contains_key(id));
+        Ok(purged)
+    }
+}
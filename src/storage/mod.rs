@@ -1,10 +1,23 @@
-use crate::models::{PaymentRequest, User};
+use crate::events::{Event, EventBus};
+use crate::models::{
+    CreditReason, IdempotencyRecord, LedgerEntry, PaymentRequest, PaymentStatus, User,
+};
 use anyhow::Result;
-use chrono::Utc;
-use deadpool_redis::{Config as RedisConfig, Pool, Runtime};
-use redis::{AsyncCommands, RedisError};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::RedisError;
+use std::sync::Arc;
 use thiserror::Error;
-use tracing::{debug, error, info};
+
+mod memory_storage;
+mod postgres_storage;
+mod redis_storage;
+mod sqlite_storage;
+
+pub use memory_storage::MemoryStorage;
+pub use postgres_storage::PostgresStorage;
+pub use redis_storage::RedisStorage;
+pub use sqlite_storage::SqliteStorage;
 
 /// Errors that can occur when interacting with storage
 #[derive(Debug, Error)]
@@ -21,6 +34,13 @@ pub enum StorageError {
     #[error("User not found")]
     UserNotFound,
 
+    /// The user's balance is too low for the requested spend
+    #[error("Insufficient credits")]
+    InsufficientCredits {
+        /// The balance the spend was refused against
+        balance: u32,
+    },
+
     /// Payment request not found
     #[error("Payment request not found")]
     PaymentRequestNotFound,
@@ -28,6 +48,14 @@ pub enum StorageError {
     /// Serialization/deserialization error
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
+
+    /// SQL database error
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    /// Database schema migration error
+    #[error("Database migration error: {0}")]
+    MigrationError(#[from] sqlx::migrate::MigrateError),
 }
 
 impl From<deadpool_redis::PoolError> for StorageError {
@@ -36,185 +64,292 @@ impl From<deadpool_redis::PoolError> for StorageError {
     }
 }
 
-/// Storage implementation using Redis
-#[derive(Clone)]
-pub struct RedisStorage {
-    pool: Pool,
+/// How long payment requests are kept after they expire, so late payments
+/// can still be matched and settled payments can be looked up
+const PAYMENT_REQUEST_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Most expired rows deleted from a table in one purge
+const PURGE_BATCH_SIZE: i64 = 1000;
+
+/// Filter and page through a user's ledger entries
+///
+/// The default query matches every entry.
+#[derive(Debug, Clone, Default)]
+pub struct LedgerQuery {
+    /// Only entries with one of these reasons (all if empty)
+    pub reasons: Vec<CreditReason>,
+    /// Only entries created at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only entries created before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Only entries after this entry ID, to continue from a previous page
+    pub after: Option<u64>,
+    /// Maximum number of entries to return
+    pub limit: Option<usize>,
 }
 
-impl std::fmt::Debug for RedisStorage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("RedisStorage")
-            .field("pool", &"RedisPool".to_string())
-            .finish()
+impl LedgerQuery {
+    /// Whether an entry matches the filters (not the limit)
+    fn matches(&self, entry: &LedgerEntry) -> bool {
+        (self.reasons.is_empty() || self.reasons.contains(&entry.reason))
+            && self.from.is_none_or(|from| entry.created_at >= from)
+            && self.to.is_none_or(|to| entry.created_at < to)
+            && self.after.is_none_or(|after| entry.id > after)
+    }
+
+    /// Apply the query to entries held in memory, oldest first
+    fn apply<'a>(&self, entries: impl IntoIterator<Item = &'a LedgerEntry>) -> Vec<LedgerEntry> {
+        entries
+            .into_iter()
+            .filter(|entry| self.matches(entry))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
     }
 }
 
-/// Prefixes for Redis keys
-const USER_KEY_PREFIX: &str = "user:";
-const PAYMENT_REQ_KEY_PREFIX: &str = "payment:";
-const EXTERNAL_ID_KEY_PREFIX: &str = "external_payment:";
+/// Storage for users, payment requests and the bookkeeping around them
+///
+/// Implementations must be safe to share between tasks; the server holds one
+/// behind an `Arc<dyn Storage>`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Check if the storage backend is reachable
+    async fn check_connection(&self) -> Result<(), StorageError>;
 
-impl RedisStorage {
-    /// Create a new Redis storage instance
-    pub fn new(redis_url: &str) -> Result<Self> {
-        let cfg = RedisConfig::from_url(redis_url);
-        let pool = cfg.create_pool(Some(Runtime::Tokio1))?;
+    /// Create a new user, recording the initial credits as a grant in the ledger
+    async fn create_user(&self, user: &User) -> Result<(), StorageError>;
 
-        Ok(Self { pool })
-    }
+    /// Get a user by ID
+    async fn get_user(&self, user_id: &str) -> Result<User, StorageError>;
+
+    /// Add `delta` to a user's credits (never going below zero) and return the updated user
+    ///
+    /// The change actually applied is appended to the user's ledger atomically with
+    /// the new balance, so the two can't disagree. `route` is the API route the
+    /// credits were spent on or refunded for.
+    async fn update_user_credits(
+        &self,
+        user_id: &str,
+        delta: i32,
+        reason: CreditReason,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError>;
+
+    /// Remove `amount` credits from a user for a paid request, recording a spend
+    ///
+    /// Fails with [`StorageError::InsufficientCredits`] without changing anything if
+    /// the balance is too low, so concurrent requests can't overdraw it.
+    async fn spend_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError>;
+
+    /// Hold `amount` credits for a response still being sent, recording a hold
+    ///
+    /// Fails like [`Storage::spend_user_credits`] if the balance is too low.
+    async fn hold_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError>;
+
+    /// Release the `held` credits of a finished response and spend the `used` ones
+    ///
+    /// Both are recorded atomically, so the response shows up in the ledger as a
+    /// single spend of what it used, however many holds it took.
+    async fn settle_held_credits(
+        &self,
+        user_id: &str,
+        held: u32,
+        used: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError>;
+
+    /// List a user's ledger entries matching a query, oldest first
+    async fn list_ledger_entries(
+        &self,
+        user_id: &str,
+        query: &LedgerQuery,
+    ) -> Result<Vec<LedgerEntry>, StorageError>;
 
-    /// Check if the Redis connection is working
-    pub async fn check_connection(&self) -> Result<(), StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let _: String = redis::cmd("PING")
-            .query_async(&mut conn)
-            .await
-            .map_err(StorageError::from)?;
-        Ok(())
-    }
+    /// List the IDs of all users
+    async fn list_user_ids(&self) -> Result<Vec<String>, StorageError>;
 
-    /// Create a new user with initial credits
-    pub async fn create_user(&self, user: &User) -> Result<(), StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let key = format!("{}{}", USER_KEY_PREFIX, user.id);
-        let user_json = serde_json::to_string(user).map_err(StorageError::from)?;
+    /// Store a payment request, indexing it by external ID and pending status
+    async fn store_payment_request(&self, request: &PaymentRequest) -> Result<(), StorageError>;
 
-        let _: () = conn.set(key, user_json).await.map_err(StorageError::from)?;
-        info!("Created new user with ID: {}", user.id);
-        Ok(())
-    }
+    /// Store a payment request with a new status, if its stored status is one of `from`
+    ///
+    /// The check and the write are atomic, so when several tasks race to settle or
+    /// expire the same payment only one of them wins. Returns `false`, leaving the
+    /// stored request unchanged, if its status had already moved on.
+    async fn transition_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError>;
+
+    /// Store a paid payment request, if its stored status is one of `from`, and
+    /// credit its user with a purchase
+    ///
+    /// The status change and the ledger entry are written atomically, so a payment
+    /// is never left paid without its credits. Returns `false` like
+    /// [`Storage::transition_payment_request`], crediting nothing.
+    async fn settle_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError>;
 
-    /// Get a user by ID
-    pub async fn get_user(&self, user_id: &str) -> Result<User, StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let key = format!("{}{}", USER_KEY_PREFIX, user_id);
+    /// Get a payment request by ID
+    async fn get_payment_request(&self, request_id: &str) -> Result<PaymentRequest, StorageError>;
 
-        let user_json: String = conn.get(key).await.map_err(|e| {
-            debug!("Error fetching user {}: {:?}", user_id, e);
-            StorageError::UserNotFound
-        })?;
+    /// Get a payment request by external ID (e.g., Lightning invoice ID or Coinbase charge ID)
+    async fn get_payment_request_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> Result<PaymentRequest, StorageError>;
 
-        let user: User = serde_json::from_str(&user_json).map_err(StorageError::from)?;
-        Ok(user)
-    }
+    /// List all payment requests that are still pending
+    async fn list_pending_payment_requests(&self) -> Result<Vec<PaymentRequest>, StorageError>;
 
-    /// Update a user's credits
-    pub async fn update_user_credits(
+    /// Claim a webhook event for processing
+    ///
+    /// Returns `false` if the event was already claimed within the retention period.
+    async fn claim_webhook_event(
         &self,
-        user_id: &str,
-        delta: i32,
-    ) -> Result<User, StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let key = format!("{}{}", USER_KEY_PREFIX, user_id);
-
-        // Get the current user
-        let user_json: String = conn
-            .get(&key)
-            .await
-            .map_err(|_| StorageError::UserNotFound)?;
-        let mut user: User = serde_json::from_str(&user_json).map_err(StorageError::from)?;
-
-        // Update credits (ensuring it doesn't go below 0)
-        if delta < 0 && user.credits < delta.unsigned_abs() {
-            user.credits = 0;
-        } else if delta < 0 {
-            user.credits -= delta.unsigned_abs();
-        } else {
-            user.credits += delta as u32;
-        }
+        source: &str,
+        event_id: &str,
+        retention_secs: u64,
+    ) -> Result<bool, StorageError>;
+
+    /// Release a webhook event claim so a redelivery can be processed
+    async fn release_webhook_event(&self, source: &str, event_id: &str)
+    -> Result<(), StorageError>;
+
+    /// Reserve an idempotency key for a new request
+    ///
+    /// Returns `None` if the key was free and is now reserved by `record`, or the
+    /// existing record if the key was already used.
+    async fn begin_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<Option<IdempotencyRecord>, StorageError>;
 
-        // Update the last credit update timestamp
-        user.last_credit_update_at = Utc::now();
-
-        // Save the updated user
-        let updated_user_json = serde_json::to_string(&user).map_err(StorageError::from)?;
-        let _: () = conn
-            .set(key, updated_user_json)
-            .await
-            .map_err(StorageError::from)?;
-
-        info!(
-            "Updated credits for user {}: delta={}, new balance={}",
-            user_id, delta, user.credits
-        );
-        Ok(user)
+    /// Save the completed record for an idempotency key
+    async fn complete_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<(), StorageError>;
+
+    /// Release an idempotency key whose request failed, so it can be retried
+    async fn abandon_idempotent_request(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Delete expired webhook claims, idempotency keys and other entries kept
+    /// past their expiry, returning how many were deleted
+    ///
+    /// Lookups already ignore expired entries, this only reclaims their space. It
+    /// is run by the expiry sweeper, a bounded batch at a time, so it never holds
+    /// up requests.
+    async fn purge_expired(&self) -> Result<u64, StorageError>;
+}
+
+/// Open the storage backend for a URL
+///
+/// `postgres://` selects PostgreSQL, `sqlite://` SQLite and `memory://` the in-memory
+/// backend; anything else is treated as a Redis URL. Balance changes are published
+/// to `events`.
+pub async fn open(url: &str, events: EventBus) -> Result<Arc<dyn Storage>> {
+    if url.starts_with(MemoryStorage::URL_SCHEME) {
+        return Ok(Arc::new(MemoryStorage::new().with_events(events)));
     }
 
-    /// Store a new payment request
-    pub async fn store_payment_request(
-        &self,
-        request: &PaymentRequest,
-    ) -> Result<(), StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let key = format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request.id);
-        let req_json = serde_json::to_string(request).map_err(StorageError::from)?;
-
-        // Calculate TTL (expiry time minus current time)
-        let now = Utc::now();
-        let expiry = request.expires_at;
-        let ttl = if expiry > now {
-            (expiry - now).num_seconds() as u64
-        } else {
-            // If already expired, set a short TTL
-            60
-        };
-
-        // Set with expiry
-        let _: () = conn
-            .set_ex(key, req_json, ttl)
-            .await
-            .map_err(StorageError::from)?;
-
-        // If there's an external ID, create a reference to the main payment request
-        if let Some(ext_id) = &request.external_id {
-            let ext_key = format!("{}{}", EXTERNAL_ID_KEY_PREFIX, ext_id);
-            let _: () = conn
-                .set_ex(ext_key, &request.id, ttl)
-                .await
-                .map_err(StorageError::from)?;
-        }
+    if PostgresStorage::URL_SCHEMES
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+    {
+        let storage = PostgresStorage::connect(url).await?;
+        return Ok(Arc::new(storage.with_events(events)));
+    }
 
-        info!(
-            "Stored payment request: id={}, method={:?}, offer={}",
-            request.id, request.method, request.offer_id
-        );
-        Ok(())
+    if url.starts_with(SqliteStorage::URL_SCHEME) {
+        let storage = SqliteStorage::connect(url).await?;
+        return Ok(Arc::new(storage.with_events(events)));
     }
 
-    /// Get a payment request by ID
-    pub async fn get_payment_request(
-        &self,
-        request_id: &str,
-    ) -> Result<PaymentRequest, StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let key = format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request_id);
-
-        let req_json: String = conn
-            .get(key)
-            .await
-            .map_err(|_| StorageError::PaymentRequestNotFound)?;
-        let request: PaymentRequest =
-            serde_json::from_str(&req_json).map_err(StorageError::from)?;
-
-        Ok(request)
+    Ok(Arc::new(RedisStorage::new(url)?.with_events(events)))
+}
+
+/// Whether a storage URL selects the Redis backend
+pub fn is_redis_url(url: &str) -> bool {
+    url.starts_with("redis://") || url.starts_with("rediss://")
+}
+
+/// Seconds to keep a payment request: until it expires, plus the retention period
+fn payment_request_ttl(request: &PaymentRequest) -> u64 {
+    let remaining = (request.expires_at - Utc::now()).num_seconds().max(0) as u64;
+    remaining + PAYMENT_REQUEST_RETENTION_SECS
+}
+
+/// What to do when a change would take a balance below zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overdraft {
+    /// Stop at zero
+    Clamp,
+    /// Fail with [`StorageError::InsufficientCredits`]
+    Reject,
+}
+
+impl Overdraft {
+    /// Balance after adding `delta`
+    fn apply(self, balance: i64, delta: i64) -> Result<i64, StorageError> {
+        let credits = balance + delta;
+        match self {
+            Overdraft::Clamp => Ok(credits.max(0)),
+            Overdraft::Reject if credits < 0 => Err(StorageError::InsufficientCredits {
+                balance: balance as u32,
+            }),
+            Overdraft::Reject => Ok(credits),
+        }
     }
+}
 
-    /// Get a payment request by external ID (e.g., Lightning invoice ID or Coinbase charge ID)
-    pub async fn get_payment_request_by_external_id(
-        &self,
-        external_id: &str,
-    ) -> Result<PaymentRequest, StorageError> {
-        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
-        let ext_key = format!("{}{}", EXTERNAL_ID_KEY_PREFIX, external_id);
-
-        // Get the payment request ID from the external ID reference
-        let request_id: String = conn
-            .get(ext_key)
-            .await
-            .map_err(|_| StorageError::PaymentRequestNotFound)?;
-
-        // Then get the actual payment request
-        self.get_payment_request(&request_id).await
+/// The ledger entries for settling held credits: the release, then the spend if any
+fn settlement(held: u32, used: u32) -> Vec<(i64, CreditReason)> {
+    let mut changes = vec![(held as i64, CreditReason::Release)];
+    if used > 0 {
+        changes.push((-(used as i64), CreditReason::Spend));
+    }
+    changes
+}
+
+/// Parse a ledger reason as stored by a backend
+fn parse_credit_reason(reason: &str) -> Result<CreditReason, StorageError> {
+    Ok(serde_json::from_value(serde_json::Value::String(
+        reason.to_string(),
+    ))?)
+}
+
+/// Announce a user's new balance
+async fn publish_balance(events: Option<&EventBus>, user: &User) {
+    if let Some(events) = events {
+        events
+            .publish(Event::BalanceChanged {
+                user_id: user.id.clone(),
+                credits: user.credits,
+            })
+            .await;
     }
 }
@@ -0,0 +1,606 @@
+use super::{
+    LedgerQuery, Overdraft, PURGE_BATCH_SIZE, Storage, StorageError, parse_credit_reason,
+    publish_balance, settlement,
+};
+use crate::events::EventBus;
+use crate::models::{
+    CreditReason, IdempotencyRecord, LedgerEntry, PaymentRequest, PaymentStatus, User,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgExecutor, PgPool, PgPoolOptions, Postgres};
+use sqlx::types::Json;
+use sqlx::{QueryBuilder, Transaction};
+use tracing::info;
+
+/// Schema migrations, applied on connect
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Maximum number of pooled database connections
+const MAX_CONNECTIONS: u32 = 10;
+
+/// Columns selected for a user
+type UserRow = (String, i64, DateTime<Utc>, DateTime<Utc>);
+
+fn user_from_row((id, credits, created_at, last_credit_update_at): UserRow) -> User {
+    User {
+        id,
+        credits: u32::try_from(credits).unwrap_or(u32::MAX),
+        created_at,
+        last_credit_update_at,
+    }
+}
+
+/// Columns selected for a ledger entry
+type LedgerRow = (
+    i64,
+    String,
+    i64,
+    i64,
+    String,
+    Option<String>,
+    Option<String>,
+    DateTime<Utc>,
+);
+
+fn ledger_entry_from_row(
+    (id, user_id, amount, balance, reason, reference, route, created_at): LedgerRow,
+) -> Result<LedgerEntry, StorageError> {
+    Ok(LedgerEntry {
+        id: id as u64,
+        user_id,
+        amount,
+        balance: u32::try_from(balance).unwrap_or(u32::MAX),
+        reason: parse_credit_reason(&reason)?,
+        reference,
+        route,
+        created_at,
+    })
+}
+
+/// Storage implementation using PostgreSQL
+///
+/// Payment requests are kept permanently, and every credit update is recorded in
+/// the `credit_ledger` table in the same transaction as the new balance.
+#[derive(Clone)]
+pub struct PostgresStorage {
+    pool: PgPool,
+    events: Option<EventBus>,
+}
+
+impl std::fmt::Debug for PostgresStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresStorage")
+            .field("pool", &"PgPool".to_string())
+            .finish()
+    }
+}
+
+impl PostgresStorage {
+    /// URL schemes selecting this backend
+    pub const URL_SCHEMES: [&'static str; 2] = ["postgres://", "postgresql://"];
+
+    /// Connect to the database and bring its schema up to date
+    pub async fn connect(database_url: &str) -> Result<Self, StorageError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect(database_url)
+            .await?;
+
+        MIGRATOR.run(&pool).await?;
+        info!("PostgreSQL schema is up to date");
+
+        Ok(Self { pool, events: None })
+    }
+
+    /// Publish balance changes to the given event bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Apply credit changes in order and record each in the ledger, in one transaction
+    ///
+    /// If one of them is rejected none are applied.
+    async fn change_credits(
+        &self,
+        user_id: &str,
+        changes: &[(i64, CreditReason)],
+        reference: Option<&str>,
+        route: Option<&str>,
+        overdraft: Overdraft,
+    ) -> Result<User, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let user =
+            apply_credit_changes(&mut tx, user_id, changes, reference, route, overdraft).await?;
+        tx.commit().await?;
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(user)
+    }
+}
+
+/// Apply credit changes in order within a transaction and record each in the ledger
+async fn apply_credit_changes(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: &str,
+    changes: &[(i64, CreditReason)],
+    reference: Option<&str>,
+    route: Option<&str>,
+    overdraft: Overdraft,
+) -> Result<User, StorageError> {
+    // Lock the row so concurrent updates apply one after the other
+    let current: Option<(i64,)> =
+        sqlx::query_as("SELECT credits FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    let (current,) = current.ok_or(StorageError::UserNotFound)?;
+
+    let mut balances = Vec::with_capacity(changes.len());
+    let mut credits = current;
+    for (delta, reason) in changes {
+        let previous = credits;
+        credits = overdraft.apply(previous, *delta)?;
+        balances.push((credits - previous, credits, *reason));
+    }
+    let now = Utc::now();
+
+    let row: UserRow = sqlx::query_as(
+        "UPDATE users SET credits = $2, last_credit_update_at = $3 WHERE id = $1
+         RETURNING id, credits, created_at, last_credit_update_at",
+    )
+    .bind(user_id)
+    .bind(credits)
+    .bind(now)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    for (amount, balance, reason) in balances {
+        sqlx::query(
+            "INSERT INTO credit_ledger (user_id, delta, balance, reason, reference, route, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(user_id)
+        .bind(amount)
+        .bind(balance)
+        .bind(reason.as_str())
+        .bind(reference)
+        .bind(route)
+        .bind(now)
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    let user = user_from_row(row);
+    info!(
+        "Updated credits for user {}: delta={}, new balance={}",
+        user_id,
+        credits - current,
+        user.credits
+    );
+    Ok(user)
+}
+
+/// Store a payment request with a new status, if its stored status is one of `from`
+async fn update_payment_request<'e>(
+    executor: impl PgExecutor<'e>,
+    request: &PaymentRequest,
+    from: &[PaymentStatus],
+) -> Result<bool, StorageError> {
+    let from: Vec<&str> = from.iter().map(PaymentStatus::as_str).collect();
+
+    // The status check and the write happen in one statement
+    let moved: Option<(String,)> = sqlx::query_as(
+        "UPDATE payment_requests
+         SET external_id = $2, status = $3, expires_at = $4, data = $5
+         WHERE id = $1 AND status = ANY($6)
+         RETURNING id",
+    )
+    .bind(&request.id)
+    .bind(&request.external_id)
+    .bind(request.status.as_str())
+    .bind(request.expires_at)
+    .bind(Json(request))
+    .bind(from)
+    .fetch_optional(executor)
+    .await?;
+
+    if moved.is_some() {
+        info!(
+            "Payment request {} moved to {:?}",
+            request.id, request.status
+        );
+    }
+    Ok(moved.is_some())
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn check_connection(&self) -> Result<(), StorageError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn create_user(&self, user: &User) -> Result<(), StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO users (id, credits, created_at, last_credit_update_at)
+             VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&user.id)
+        .bind(user.credits as i64)
+        .bind(user.created_at)
+        .bind(user.last_credit_update_at)
+        .execute(&mut *tx)
+        .await?;
+
+        // The initial credits open the user's ledger
+        sqlx::query(
+            "INSERT INTO credit_ledger (user_id, delta, balance, reason, created_at)
+             VALUES ($1, $2, $2, $3, $4)",
+        )
+        .bind(&user.id)
+        .bind(user.credits as i64)
+        .bind(CreditReason::Grant.as_str())
+        .bind(user.created_at)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        info!("Created new user with ID: {}", user.id);
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: &str) -> Result<User, StorageError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            "SELECT id, credits, created_at, last_credit_update_at FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(user_from_row).ok_or(StorageError::UserNotFound)
+    }
+
+    async fn update_user_credits(
+        &self,
+        user_id: &str,
+        delta: i32,
+        reason: CreditReason,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(delta as i64, reason)],
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    async fn spend_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Spend)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn hold_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Hold)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn settle_held_credits(
+        &self,
+        user_id: &str,
+        held: u32,
+        used: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &settlement(held, used),
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    async fn list_ledger_entries(
+        &self,
+        user_id: &str,
+        query: &LedgerQuery,
+    ) -> Result<Vec<LedgerEntry>, StorageError> {
+        let mut sql = QueryBuilder::<Postgres>::new(
+            "SELECT id, user_id, delta, balance, reason, reference, route, created_at
+             FROM credit_ledger WHERE user_id = ",
+        );
+        sql.push_bind(user_id);
+
+        if !query.reasons.is_empty() {
+            sql.push(" AND reason IN (");
+            let mut reasons = sql.separated(", ");
+            for reason in &query.reasons {
+                reasons.push_bind(reason.as_str());
+            }
+            sql.push(")");
+        }
+        if let Some(from) = query.from {
+            sql.push(" AND created_at >= ").push_bind(from);
+        }
+        if let Some(to) = query.to {
+            sql.push(" AND created_at < ").push_bind(to);
+        }
+        if let Some(after) = query.after {
+            sql.push(" AND id > ").push_bind(after as i64);
+        }
+        sql.push(" ORDER BY id");
+        if let Some(limit) = query.limit {
+            sql.push(" LIMIT ").push_bind(limit as i64);
+        }
+
+        let rows: Vec<LedgerRow> = sql.build_query_as().fetch_all(&self.pool).await?;
+
+        rows.into_iter().map(ledger_entry_from_row).collect()
+    }
+
+    async fn list_user_ids(&self) -> Result<Vec<String>, StorageError> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT id FROM users")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn store_payment_request(&self, request: &PaymentRequest) -> Result<(), StorageError> {
+        sqlx::query(
+            "INSERT INTO payment_requests (id, user_id, external_id, status, created_at, expires_at, data)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (id) DO UPDATE SET
+                 external_id = EXCLUDED.external_id,
+                 status = EXCLUDED.status,
+                 expires_at = EXCLUDED.expires_at,
+                 data = EXCLUDED.data",
+        )
+        .bind(&request.id)
+        .bind(&request.user_id)
+        .bind(&request.external_id)
+        .bind(request.status.as_str())
+        .bind(request.created_at)
+        .bind(request.expires_at)
+        .bind(Json(request))
+        .execute(&self.pool)
+        .await?;
+
+        info!(
+            "Stored payment request: id={}, method={:?}, offer={}",
+            request.id, request.method, request.offer_id
+        );
+        Ok(())
+    }
+
+    async fn transition_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        update_payment_request(&self.pool, request, from).await
+    }
+
+    async fn settle_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        if !update_payment_request(&mut *tx, request, from).await? {
+            return Ok(false);
+        }
+        let user = apply_credit_changes(
+            &mut tx,
+            &request.user_id,
+            &[(request.credits as i64, CreditReason::Purchase)],
+            Some(&request.id),
+            None,
+            Overdraft::Clamp,
+        )
+        .await?;
+        tx.commit().await?;
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(true)
+    }
+
+    async fn get_payment_request(&self, request_id: &str) -> Result<PaymentRequest, StorageError> {
+        let row: Option<(Json<PaymentRequest>,)> =
+            sqlx::query_as("SELECT data FROM payment_requests WHERE id = $1")
+                .bind(request_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        row.map(|(Json(request),)| request)
+            .ok_or(StorageError::PaymentRequestNotFound)
+    }
+
+    async fn get_payment_request_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> Result<PaymentRequest, StorageError> {
+        let row: Option<(Json<PaymentRequest>,)> =
+            sqlx::query_as("SELECT data FROM payment_requests WHERE external_id = $1")
+                .bind(external_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        row.map(|(Json(request),)| request)
+            .ok_or(StorageError::PaymentRequestNotFound)
+    }
+
+    async fn list_pending_payment_requests(&self) -> Result<Vec<PaymentRequest>, StorageError> {
+        let rows: Vec<(Json<PaymentRequest>,)> =
+            sqlx::query_as("SELECT data FROM payment_requests WHERE status = $1")
+                .bind(PaymentStatus::Pending.as_str())
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(Json(request),)| request).collect())
+    }
+
+    async fn claim_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+        retention_secs: u64,
+    ) -> Result<bool, StorageError> {
+        let key = format!("{}:{}", source, event_id);
+        let now = Utc::now();
+
+        // Only a missing or expired claim can be taken
+        let claimed: Option<(String,)> = sqlx::query_as(
+            "INSERT INTO webhook_events (key, expires_at) VALUES ($1, $2)
+             ON CONFLICT (key) DO UPDATE SET expires_at = EXCLUDED.expires_at
+             WHERE webhook_events.expires_at <= $3
+             RETURNING key",
+        )
+        .bind(&key)
+        .bind(now + Duration::seconds(retention_secs as i64))
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(claimed.is_some())
+    }
+
+    async fn release_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+    ) -> Result<(), StorageError> {
+        let key = format!("{}:{}", source, event_id);
+
+        sqlx::query("DELETE FROM webhook_events WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn begin_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        // The reservation or the read can lose a race with a request releasing the
+        // key, so try again until one of them sees a row
+        loop {
+            let now = Utc::now();
+            let reserved: Option<(String,)> = sqlx::query_as(
+                "INSERT INTO idempotency_keys (key, record, expires_at) VALUES ($1, $2, $3)
+                 ON CONFLICT (key) DO UPDATE SET
+                     record = EXCLUDED.record,
+                     expires_at = EXCLUDED.expires_at
+                 WHERE idempotency_keys.expires_at <= $4
+                 RETURNING key",
+            )
+            .bind(key)
+            .bind(Json(record))
+            .bind(now + Duration::seconds(ttl_secs as i64))
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+            if reserved.is_some() {
+                return Ok(None);
+            }
+
+            let existing: Option<(Json<IdempotencyRecord>,)> = sqlx::query_as(
+                "SELECT record FROM idempotency_keys WHERE key = $1 AND expires_at > $2",
+            )
+            .bind(key)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+            if let Some((Json(existing),)) = existing {
+                return Ok(Some(existing));
+            }
+        }
+    }
+
+    async fn complete_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<(), StorageError> {
+        sqlx::query(
+            "INSERT INTO idempotency_keys (key, record, expires_at) VALUES ($1, $2, $3)
+             ON CONFLICT (key) DO UPDATE SET
+                 record = EXCLUDED.record,
+                 expires_at = EXCLUDED.expires_at",
+        )
+        .bind(key)
+        .bind(Json(record))
+        .bind(Utc::now() + Duration::seconds(ttl_secs as i64))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn abandon_idempotent_request(&self, key: &str) -> Result<(), StorageError> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> Result<u64, StorageError> {
+        let now = Utc::now();
+        let mut purged = 0;
+
+        for table in ["webhook_events", "idempotency_keys"] {
+            let deleted = sqlx::query(&format!(
+                "DELETE FROM {table} WHERE key IN
+                     (SELECT key FROM {table} WHERE expires_at <= $1 LIMIT $2)"
+            ))
+            .bind(now)
+            .bind(PURGE_BATCH_SIZE)
+            .execute(&self.pool)
+            .await?;
+            purged += deleted.rows_affected();
+        }
+
+        Ok(purged)
+    }
+}
@@ -0,0 +1,818 @@
+use super::{
+    LedgerQuery, Overdraft, Storage, StorageError, payment_request_ttl, publish_balance, settlement,
+};
+use crate::events::EventBus;
+use crate::models::{
+    CreditReason, IdempotencyRecord, LedgerEntry, PaymentRequest, PaymentStatus, User,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use deadpool_redis::{Config as RedisConfig, Pool, Runtime};
+use once_cell::sync::Lazy;
+use redis::{AsyncCommands, Script};
+use tracing::{debug, info};
+
+/// Storage implementation using Redis
+#[derive(Clone)]
+pub struct RedisStorage {
+    pool: Pool,
+    events: Option<EventBus>,
+}
+
+impl std::fmt::Debug for RedisStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStorage")
+            .field("pool", &"RedisPool".to_string())
+            .finish()
+    }
+}
+
+/// Prefixes for Redis keys
+const USER_KEY_PREFIX: &str = "user:";
+const PAYMENT_REQ_KEY_PREFIX: &str = "payment:";
+const EXTERNAL_ID_KEY_PREFIX: &str = "external_payment:";
+
+/// Set of payment request IDs that are still pending
+const PENDING_PAYMENTS_KEY: &str = "pending_payments";
+
+/// Prefix for processed webhook event IDs
+const WEBHOOK_EVENT_KEY_PREFIX: &str = "webhook_event:";
+
+/// Prefix for idempotency records
+const IDEMPOTENCY_KEY_PREFIX: &str = "idempotency:";
+
+/// Prefix for per-user ledger lists, oldest entry first
+const LEDGER_KEY_PREFIX: &str = "ledger:";
+
+/// Ledger entries read from Redis at a time while paging through a ledger
+const LEDGER_READ_CHUNK: isize = 100;
+
+/// Error code returned by the credits script when a spend is rejected
+const INSUFFICIENT_CREDITS_CODE: &str = "INSUFFICIENT_CREDITS";
+
+/// Error code returned by the settlement script when the user doesn't exist
+const USER_NOT_FOUND_CODE: &str = "USER_NOT_FOUND";
+
+/// Counter handing out ledger entry IDs
+const LEDGER_SEQ_KEY: &str = "ledger_seq";
+
+/// Lua helpers appending an entry to the ledger list in `KEYS[2]`, numbered from
+/// the counter in `KEYS[3]`, and opening the ledger of a user who has none
+const APPEND_ENTRY_LUA: &str = r#"
+local function append_entry(user_id, amount, balance, reason, reference, route, created_at)
+    local entry = {
+        id = redis.call('INCR', KEYS[3]),
+        user_id = user_id,
+        amount = amount,
+        balance = balance,
+        reason = reason,
+        reference = cjson.null,
+        route = cjson.null,
+        created_at = created_at,
+    }
+    if reference ~= '' then
+        entry.reference = reference
+    end
+    if route ~= '' then
+        entry.route = route
+    end
+    redis.call('RPUSH', KEYS[2], cjson.encode(entry))
+end
+
+-- Users created before the ledger existed start it with their balance at the time
+local function open_ledger(user)
+    if redis.call('EXISTS', KEYS[2]) == 0 and user.credits > 0 then
+        append_entry(user.id, user.credits, user.credits, 'adjustment', 'opening_balance', '', user.last_credit_update_at)
+    end
+end
+"#;
+
+/// Store a new user (`ARGV[1]`) and record their initial credits as a grant
+const CREATE_USER_LUA: &str = r#"
+local user = cjson.decode(ARGV[1])
+redis.call('SET', KEYS[1], ARGV[1])
+append_entry(user.id, user.credits, user.credits, 'grant', '', '', user.created_at)
+"#;
+
+/// Apply credit changes in order and record each, returning the updated user or
+/// nil if the user doesn't exist
+///
+/// ARGV: timestamp, reference and route (empty for none), the overdraft handling
+/// (`clamp` stops at zero, `reject` fails with `INSUFFICIENT_CREDITS <balance>`
+/// without changing anything), then a delta and reason per change
+const UPDATE_CREDITS_LUA: &str = r#"
+local user_json = redis.call('GET', KEYS[1])
+if not user_json then
+    return false
+end
+
+local user = cjson.decode(user_json)
+local previous = user.credits
+
+-- Work out every balance first, so a rejected change leaves nothing applied
+local changes = {}
+local credits = previous
+for i = 5, #ARGV, 2 do
+    local balance = credits + tonumber(ARGV[i])
+    if balance < 0 then
+        if ARGV[4] == 'reject' then
+            return redis.error_reply('INSUFFICIENT_CREDITS ' .. credits)
+        end
+        balance = 0
+    end
+    table.insert(changes, {balance - credits, balance, ARGV[i + 1]})
+    credits = balance
+end
+
+open_ledger(user)
+
+user.credits = credits
+user.last_credit_update_at = ARGV[1]
+
+local updated = cjson.encode(user)
+redis.call('SET', KEYS[1], updated)
+for _, change in ipairs(changes) do
+    append_entry(user.id, change[1], change[2], change[3], ARGV[2], ARGV[3], ARGV[1])
+end
+return updated
+"#;
+
+/// Store a payment request (`ARGV[1]`, kept for `ARGV[2]` seconds) if its stored
+/// status is one of `ARGV[4..]`, returning 1 if it was stored and 0 if not
+///
+/// KEYS: the request, the pending index and, if it has one, its external ID
+/// reference. `ARGV[3]` is the request ID.
+const TRANSITION_PAYMENT_LUA: &str = r#"
+local stored = redis.call('GET', KEYS[1])
+if not stored then
+    return 0
+end
+
+local status = cjson.decode(stored).status
+local allowed = false
+for i = 4, #ARGV do
+    if ARGV[i] == status then
+        allowed = true
+    end
+end
+if not allowed then
+    return 0
+end
+
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+if KEYS[3] then
+    redis.call('SET', KEYS[3], ARGV[3], 'EX', ARGV[2])
+end
+if cjson.decode(ARGV[1]).status == 'pending' then
+    redis.call('SADD', KEYS[2], ARGV[3])
+else
+    redis.call('SREM', KEYS[2], ARGV[3])
+end
+return 1
+"#;
+
+/// Store a paid payment request (`ARGV[1]`, kept for `ARGV[2]` seconds) if its
+/// stored status is one of `ARGV[6..]`, and credit its user with `ARGV[5]` credits
+/// as a purchase at time `ARGV[4]`
+///
+/// Returns the updated user, nil if the status had moved on, or a
+/// `USER_NOT_FOUND` error without changing anything.
+/// KEYS: the user, their ledger, the ledger counter, the request, the pending index
+/// and, if it has one, its external ID reference. `ARGV[3]` is the request ID.
+const SETTLE_PAYMENT_LUA: &str = r#"
+local stored = redis.call('GET', KEYS[4])
+if not stored then
+    return false
+end
+
+local status = cjson.decode(stored).status
+local allowed = false
+for i = 6, #ARGV do
+    if ARGV[i] == status then
+        allowed = true
+    end
+end
+if not allowed then
+    return false
+end
+
+local user_json = redis.call('GET', KEYS[1])
+if not user_json then
+    return redis.error_reply('USER_NOT_FOUND')
+end
+
+local user = cjson.decode(user_json)
+open_ledger(user)
+
+local amount = tonumber(ARGV[5])
+user.credits = user.credits + amount
+user.last_credit_update_at = ARGV[4]
+
+local updated = cjson.encode(user)
+redis.call('SET', KEYS[1], updated)
+append_entry(user.id, amount, user.credits, 'purchase', ARGV[3], '', ARGV[4])
+
+redis.call('SET', KEYS[4], ARGV[1], 'EX', ARGV[2])
+if KEYS[6] then
+    redis.call('SET', KEYS[6], ARGV[3], 'EX', ARGV[2])
+end
+redis.call('SREM', KEYS[5], ARGV[3])
+return updated
+"#;
+
+/// Find the position of the first entry in the ledger list `KEYS[1]` with an ID
+/// above `ARGV[1]`, by binary search since entries are appended in ID order
+const LEDGER_POSITION_LUA: &str = r#"
+local after = tonumber(ARGV[1])
+local low = 0
+local high = redis.call('LLEN', KEYS[1])
+while low < high do
+    local mid = math.floor((low + high) / 2)
+    if cjson.decode(redis.call('LINDEX', KEYS[1], mid)).id <= after then
+        low = mid + 1
+    else
+        high = mid
+    end
+end
+return low
+"#;
+
+/// Reserve the idempotency key `KEYS[1]` for the record `ARGV[1]` for `ARGV[2]`
+/// seconds, returning nil if it was free or the record already holding it
+const BEGIN_IDEMPOTENT_LUA: &str = r#"
+local existing = redis.call('GET', KEYS[1])
+if existing then
+    return existing
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+return false
+"#;
+
+static CREATE_USER_SCRIPT: Lazy<Script> =
+    Lazy::new(|| Script::new(&format!("{}{}", APPEND_ENTRY_LUA, CREATE_USER_LUA)));
+
+static UPDATE_CREDITS_SCRIPT: Lazy<Script> =
+    Lazy::new(|| Script::new(&format!("{}{}", APPEND_ENTRY_LUA, UPDATE_CREDITS_LUA)));
+
+static SETTLE_PAYMENT_SCRIPT: Lazy<Script> =
+    Lazy::new(|| Script::new(&format!("{}{}", APPEND_ENTRY_LUA, SETTLE_PAYMENT_LUA)));
+
+static TRANSITION_PAYMENT_SCRIPT: Lazy<Script> = Lazy::new(|| Script::new(TRANSITION_PAYMENT_LUA));
+
+static LEDGER_POSITION_SCRIPT: Lazy<Script> = Lazy::new(|| Script::new(LEDGER_POSITION_LUA));
+
+static BEGIN_IDEMPOTENT_SCRIPT: Lazy<Script> = Lazy::new(|| Script::new(BEGIN_IDEMPOTENT_LUA));
+
+impl RedisStorage {
+    /// Create a new Redis storage instance
+    pub fn new(redis_url: &str) -> Result<Self> {
+        let cfg = RedisConfig::from_url(redis_url);
+        let pool = cfg.create_pool(Some(Runtime::Tokio1))?;
+
+        Ok(Self { pool, events: None })
+    }
+
+    /// Publish balance changes to the given event bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Apply credit changes in order and record each in the ledger
+    ///
+    /// If one of them is rejected none are applied.
+    async fn change_credits(
+        &self,
+        user_id: &str,
+        changes: &[(i64, CreditReason)],
+        reference: Option<&str>,
+        route: Option<&str>,
+        overdraft: Overdraft,
+    ) -> Result<User, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+
+        // Read, update and record in one script so concurrent updates can't interleave
+        let mut invocation = UPDATE_CREDITS_SCRIPT.prepare_invoke();
+        invocation
+            .key(format!("{}{}", USER_KEY_PREFIX, user_id))
+            .key(format!("{}{}", LEDGER_KEY_PREFIX, user_id))
+            .key(LEDGER_SEQ_KEY)
+            .arg(Utc::now().to_rfc3339())
+            .arg(reference.unwrap_or_default())
+            .arg(route.unwrap_or_default())
+            .arg(match overdraft {
+                Overdraft::Clamp => "clamp",
+                Overdraft::Reject => "reject",
+            });
+        for (delta, reason) in changes {
+            invocation.arg(delta).arg(reason.as_str());
+        }
+
+        let user_json: Option<String> =
+            invocation
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| match e.code() {
+                    Some(INSUFFICIENT_CREDITS_CODE) => StorageError::InsufficientCredits {
+                        balance: e
+                            .detail()
+                            .and_then(|balance| balance.trim().parse().ok())
+                            .unwrap_or_default(),
+                    },
+                    _ => StorageError::from(e),
+                })?;
+
+        let user_json = user_json.ok_or(StorageError::UserNotFound)?;
+        let user: User = serde_json::from_str(&user_json).map_err(StorageError::from)?;
+
+        info!(
+            "Updated credits for user {}: delta={}, new balance={}",
+            user_id,
+            changes.iter().map(|(delta, _)| delta).sum::<i64>(),
+            user.credits
+        );
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    /// Check if the Redis connection is working
+    async fn check_connection(&self) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let _: String = redis::cmd("PING")
+            .query_async(&mut conn)
+            .await
+            .map_err(StorageError::from)?;
+        Ok(())
+    }
+
+    /// Create a new user with initial credits
+    async fn create_user(&self, user: &User) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let user_json = serde_json::to_string(user).map_err(StorageError::from)?;
+
+        let _: () = CREATE_USER_SCRIPT
+            .key(format!("{}{}", USER_KEY_PREFIX, user.id))
+            .key(format!("{}{}", LEDGER_KEY_PREFIX, user.id))
+            .key(LEDGER_SEQ_KEY)
+            .arg(user_json)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(StorageError::from)?;
+        info!("Created new user with ID: {}", user.id);
+        Ok(())
+    }
+
+    /// Get a user by ID
+    async fn get_user(&self, user_id: &str) -> Result<User, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", USER_KEY_PREFIX, user_id);
+
+        let user_json: String = conn.get(key).await.map_err(|e| {
+            debug!("Error fetching user {}: {:?}", user_id, e);
+            StorageError::UserNotFound
+        })?;
+
+        let user: User = serde_json::from_str(&user_json).map_err(StorageError::from)?;
+        Ok(user)
+    }
+
+    /// Update a user's credits, recording the change in their ledger
+    async fn update_user_credits(
+        &self,
+        user_id: &str,
+        delta: i32,
+        reason: CreditReason,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(delta as i64, reason)],
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    /// Spend credits on a paid request, failing if the balance is too low
+    async fn spend_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Spend)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn hold_user_credits(
+        &self,
+        user_id: &str,
+        amount: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &[(-(amount as i64), CreditReason::Hold)],
+            reference,
+            route,
+            Overdraft::Reject,
+        )
+        .await
+    }
+
+    async fn settle_held_credits(
+        &self,
+        user_id: &str,
+        held: u32,
+        used: u32,
+        reference: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<User, StorageError> {
+        self.change_credits(
+            user_id,
+            &settlement(held, used),
+            reference,
+            route,
+            Overdraft::Clamp,
+        )
+        .await
+    }
+
+    /// List a user's ledger entries matching a query, oldest first
+    async fn list_ledger_entries(
+        &self,
+        user_id: &str,
+        query: &LedgerQuery,
+    ) -> Result<Vec<LedgerEntry>, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", LEDGER_KEY_PREFIX, user_id);
+
+        // Continue a previous page from where it stopped rather than the start
+        let mut start: isize = match query.after {
+            Some(after) => LEDGER_POSITION_SCRIPT
+                .key(&key)
+                .arg(after)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(StorageError::from)?,
+            None => 0,
+        };
+
+        let limit = query.limit.unwrap_or(usize::MAX);
+        let mut matching = Vec::new();
+        while matching.len() < limit {
+            let chunk: Vec<String> = conn
+                .lrange(&key, start, start + LEDGER_READ_CHUNK - 1)
+                .await
+                .map_err(StorageError::from)?;
+            if chunk.is_empty() {
+                break;
+            }
+            start += chunk.len() as isize;
+
+            for entry in &chunk {
+                let entry: LedgerEntry = serde_json::from_str(entry)?;
+                // Entries are in time order, so nothing later can match
+                if query.to.is_some_and(|to| entry.created_at >= to) {
+                    return Ok(matching);
+                }
+                if query.matches(&entry) {
+                    matching.push(entry);
+                    if matching.len() == limit {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(matching)
+    }
+
+    /// List the IDs of all users
+    async fn list_user_ids(&self) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let mut keys = conn
+            .scan_match::<_, String>(format!("{}*", USER_KEY_PREFIX))
+            .await
+            .map_err(StorageError::from)?;
+
+        let mut user_ids = Vec::new();
+        while let Some(key) = keys.next_item().await {
+            if let Some(user_id) = key.strip_prefix(USER_KEY_PREFIX) {
+                user_ids.push(user_id.to_string());
+            }
+        }
+        Ok(user_ids)
+    }
+
+    /// Store a new payment request
+    async fn store_payment_request(&self, request: &PaymentRequest) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request.id);
+        let req_json = serde_json::to_string(request).map_err(StorageError::from)?;
+
+        // Keep the request until its expiry plus the retention period
+        let ttl = payment_request_ttl(request);
+
+        // Set with expiry
+        let _: () = conn
+            .set_ex(key, req_json, ttl)
+            .await
+            .map_err(StorageError::from)?;
+
+        // If there's an external ID, create a reference to the main payment request
+        if let Some(ext_id) = &request.external_id {
+            let ext_key = format!("{}{}", EXTERNAL_ID_KEY_PREFIX, ext_id);
+            let _: () = conn
+                .set_ex(ext_key, &request.id, ttl)
+                .await
+                .map_err(StorageError::from)?;
+        }
+
+        // Keep the pending index in sync so tracking can resume after a restart
+        if request.status == PaymentStatus::Pending {
+            let _: () = conn
+                .sadd(PENDING_PAYMENTS_KEY, &request.id)
+                .await
+                .map_err(StorageError::from)?;
+        } else {
+            let _: () = conn
+                .srem(PENDING_PAYMENTS_KEY, &request.id)
+                .await
+                .map_err(StorageError::from)?;
+        }
+
+        info!(
+            "Stored payment request: id={}, method={:?}, offer={}",
+            request.id, request.method, request.offer_id
+        );
+        Ok(())
+    }
+
+    /// Store a payment request with a new status, if its stored status is one of `from`
+    async fn transition_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let req_json = serde_json::to_string(request).map_err(StorageError::from)?;
+
+        // Check and write in one script so concurrent settlements can't both win
+        let mut invocation = TRANSITION_PAYMENT_SCRIPT.prepare_invoke();
+        invocation
+            .key(format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request.id))
+            .key(PENDING_PAYMENTS_KEY);
+        if let Some(ext_id) = &request.external_id {
+            invocation.key(format!("{}{}", EXTERNAL_ID_KEY_PREFIX, ext_id));
+        }
+        invocation
+            .arg(req_json)
+            .arg(payment_request_ttl(request))
+            .arg(&request.id);
+        for status in from {
+            invocation.arg(status.as_str());
+        }
+
+        let moved: i64 = invocation
+            .invoke_async(&mut conn)
+            .await
+            .map_err(StorageError::from)?;
+
+        if moved == 1 {
+            info!(
+                "Payment request {} moved to {:?}",
+                request.id, request.status
+            );
+        }
+        Ok(moved == 1)
+    }
+
+    /// Store a paid payment request and credit its user in one script
+    async fn settle_payment_request(
+        &self,
+        request: &PaymentRequest,
+        from: &[PaymentStatus],
+    ) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let req_json = serde_json::to_string(request).map_err(StorageError::from)?;
+
+        let mut invocation = SETTLE_PAYMENT_SCRIPT.prepare_invoke();
+        invocation
+            .key(format!("{}{}", USER_KEY_PREFIX, request.user_id))
+            .key(format!("{}{}", LEDGER_KEY_PREFIX, request.user_id))
+            .key(LEDGER_SEQ_KEY)
+            .key(format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request.id))
+            .key(PENDING_PAYMENTS_KEY);
+        if let Some(ext_id) = &request.external_id {
+            invocation.key(format!("{}{}", EXTERNAL_ID_KEY_PREFIX, ext_id));
+        }
+        invocation
+            .arg(req_json)
+            .arg(payment_request_ttl(request))
+            .arg(&request.id)
+            .arg(Utc::now().to_rfc3339())
+            .arg(request.credits);
+        for status in from {
+            invocation.arg(status.as_str());
+        }
+
+        let user_json: Option<String> =
+            invocation
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| match e.code() {
+                    Some(USER_NOT_FOUND_CODE) => StorageError::UserNotFound,
+                    _ => StorageError::from(e),
+                })?;
+        let Some(user_json) = user_json else {
+            return Ok(false);
+        };
+        let user: User = serde_json::from_str(&user_json).map_err(StorageError::from)?;
+
+        info!(
+            "Payment request {} moved to {:?}, new balance for user {}: {}",
+            request.id, request.status, user.id, user.credits
+        );
+
+        publish_balance(self.events.as_ref(), &user).await;
+
+        Ok(true)
+    }
+
+    /// Get a payment request by ID
+    async fn get_payment_request(&self, request_id: &str) -> Result<PaymentRequest, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", PAYMENT_REQ_KEY_PREFIX, request_id);
+
+        let req_json: String = conn
+            .get(key)
+            .await
+            .map_err(|_| StorageError::PaymentRequestNotFound)?;
+        let request: PaymentRequest =
+            serde_json::from_str(&req_json).map_err(StorageError::from)?;
+
+        Ok(request)
+    }
+
+    /// Get a payment request by external ID (e.g., Lightning invoice ID or Coinbase charge ID)
+    async fn get_payment_request_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> Result<PaymentRequest, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let ext_key = format!("{}{}", EXTERNAL_ID_KEY_PREFIX, external_id);
+
+        // Get the payment request ID from the external ID reference
+        let request_id: String = conn
+            .get(ext_key)
+            .await
+            .map_err(|_| StorageError::PaymentRequestNotFound)?;
+
+        // Then get the actual payment request
+        self.get_payment_request(&request_id).await
+    }
+
+    /// List all payment requests that are still pending
+    ///
+    /// Entries whose payment request has already been evicted are pruned from the index.
+    async fn list_pending_payment_requests(&self) -> Result<Vec<PaymentRequest>, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let request_ids: Vec<String> = conn
+            .smembers(PENDING_PAYMENTS_KEY)
+            .await
+            .map_err(StorageError::from)?;
+
+        let mut requests = Vec::with_capacity(request_ids.len());
+        for request_id in request_ids {
+            match self.get_payment_request(&request_id).await {
+                Ok(request) if request.status == PaymentStatus::Pending => requests.push(request),
+                Ok(_) | Err(StorageError::PaymentRequestNotFound) => {
+                    debug!("Pruning payment {} from pending index", request_id);
+                    let _: () = conn
+                        .srem(PENDING_PAYMENTS_KEY, &request_id)
+                        .await
+                        .map_err(StorageError::from)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(requests)
+    }
+
+    /// Claim a webhook event for processing
+    ///
+    /// Returns `false` if the event was already claimed within the retention period.
+    async fn claim_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+        retention_secs: u64,
+    ) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}:{}", WEBHOOK_EVENT_KEY_PREFIX, source, event_id);
+
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(Utc::now().to_rfc3339())
+            .arg("NX")
+            .arg("EX")
+            .arg(retention_secs)
+            .query_async(&mut conn)
+            .await
+            .map_err(StorageError::from)?;
+
+        Ok(claimed.is_some())
+    }
+
+    /// Release a webhook event claim so a redelivery can be processed
+    async fn release_webhook_event(
+        &self,
+        source: &str,
+        event_id: &str,
+    ) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}:{}", WEBHOOK_EVENT_KEY_PREFIX, source, event_id);
+
+        let _: () = conn.del(key).await.map_err(StorageError::from)?;
+        Ok(())
+    }
+
+    /// Reserve an idempotency key for a new request
+    ///
+    /// Returns `None` if the key was free and is now reserved by `record`, or the
+    /// existing record if the key was already used.
+    async fn begin_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<Option<IdempotencyRecord>, StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", IDEMPOTENCY_KEY_PREFIX, key);
+        let record_json = serde_json::to_string(record).map_err(StorageError::from)?;
+
+        // Reserve or read in one script, so a key expiring in between can't be
+        // reported as reserved without being written
+        let existing: Option<String> = BEGIN_IDEMPOTENT_SCRIPT
+            .key(key)
+            .arg(record_json)
+            .arg(ttl_secs)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(StorageError::from)?;
+
+        existing
+            .map(|json| serde_json::from_str(&json).map_err(StorageError::from))
+            .transpose()
+    }
+
+    /// Save the completed record for an idempotency key
+    async fn complete_idempotent_request(
+        &self,
+        key: &str,
+        record: &IdempotencyRecord,
+        ttl_secs: u64,
+    ) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", IDEMPOTENCY_KEY_PREFIX, key);
+        let record_json = serde_json::to_string(record).map_err(StorageError::from)?;
+
+        let _: () = conn
+            .set_ex(key, record_json, ttl_secs)
+            .await
+            .map_err(StorageError::from)?;
+        Ok(())
+    }
+
+    /// Release an idempotency key whose request failed, so it can be retried
+    async fn abandon_idempotent_request(&self, key: &str) -> Result<(), StorageError> {
+        let mut conn = self.pool.get().await.map_err(StorageError::from)?;
+        let key = format!("{}{}", IDEMPOTENCY_KEY_PREFIX, key);
+
+        let _: () = conn.del(key).await.map_err(StorageError::from)?;
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> Result<u64, StorageError> {
+        // Redis drops keys itself once their TTL runs out
+        Ok(0)
+    }
+}
@@ -1,10 +1,11 @@
 use chrono::{DateTime, Duration, Utc};
 use once_cell::sync::Lazy;
+use rand::RngCore;
 use reqwest::Client;
 use serde::Deserialize;
 use std::sync::Mutex;
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::debug;
 
 #[derive(Debug, Error)]
 #[allow(clippy::enum_variant_names)]
@@ -51,8 +52,17 @@ static PRICE_CACHE: Lazy<Mutex<PriceCache>> = Lazy::new(|| {
 
 static HTTP_CLIENT: Lazy<Client> = Lazy::new(Client::new);
 
+/// A USD amount converted to satoshis, with the exchange rate used
+#[derive(Debug, Clone, Copy)]
+pub struct SatsConversion {
+    /// Converted amount, rounded up to the nearest satoshi
+    pub amount_sats: u64,
+    /// BTC/USD rate the conversion was based on
+    pub btc_usd_rate: f64,
+}
+
 /// Convert USD amount to satoshis using current market rate from Kraken
-pub async fn convert_usd_to_sats(amount_usd: f64) -> Result<u64, ConversionError> {
+pub async fn convert_usd_to_sats(amount_usd: f64) -> Result<SatsConversion, ConversionError> {
     let cache_duration = Duration::seconds(600); // 10 minutes
 
     // We need to check if update is needed and get the current value in separate blocks
@@ -124,5 +134,29 @@ pub async fn convert_usd_to_sats(amount_usd: f64) -> Result<u64, ConversionError
     let amount_sats = (amount_usd * sats_per_usd).ceil() as u64;
     debug!("Converted ${} USD to {} sats", amount_usd, amount_sats);
 
-    Ok(amount_sats)
+    Ok(SatsConversion {
+        amount_sats,
+        btc_usd_rate: 100_000_000.0 / sats_per_usd,
+    })
+}
+
+/// Generate a random, URL-safe secret token (256 bits, hex encoded)
+pub fn generate_secret_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Constant-time comparison of two byte slices
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut result = 0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        result |= x ^ y;
+    }
+
+    result == 0
 }
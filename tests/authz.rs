@@ -0,0 +1,103 @@
+//! Requests to other services authorized and charged through `/authz`
+
+mod common;
+
+use l402_server_example_rs::api::create_router;
+use l402_server_example_rs::config::Config;
+use l402_server_example_rs::models::User;
+use l402_server_example_rs::payments::PaymentService;
+use l402_server_example_rs::services::BlockService;
+use l402_server_example_rs::storage::Storage;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// The API router pricing `GET /reports/{id}` at 2 credits
+async fn spawn_api(storage: Arc<dyn Storage>) -> String {
+    let mut config = Config::from_env();
+    config.pricing =
+        serde_json::from_str(r#"[{"method":"GET","route":"/reports/{id}","credits":2}]"#).unwrap();
+    config.gateway_services = Vec::new();
+    let config = Arc::new(config);
+    let router = create_router(
+        config.clone(),
+        storage.clone(),
+        PaymentService::new_without_providers(config.clone(), storage),
+        BlockService::new(),
+    );
+    common::spawn(router).await
+}
+
+#[tokio::test]
+async fn authorized_requests_are_charged_until_the_credits_run_out() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_api(storage.clone()).await;
+        let user = User::new(3);
+        storage.create_user(&user).await.unwrap();
+        let client = reqwest::Client::new();
+
+        // Envoy appends the original path
+        let allowed = client
+            .get(format!("{base}/authz/reports/7"))
+            .bearer_auth(&user.id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(allowed.status(), StatusCode::OK, "{name}");
+        assert_eq!(allowed.headers()["x-credits-remaining"], "1", "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+
+        // nginx passes it in headers
+        let refused = client
+            .get(format!("{base}/authz"))
+            .bearer_auth(&user.id)
+            .header("x-original-method", "GET")
+            .header("x-original-uri", "/reports/8?format=csv")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(refused.status(), StatusCode::PAYMENT_REQUIRED, "{name}");
+        assert_eq!(refused.headers()["x-credits-remaining"], "1", "{name}");
+        let challenge: Value = refused.json().await.unwrap();
+        assert!(challenge["offers"].is_array(), "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+
+        let free = client
+            .post(format!("{base}/authz/reports/8"))
+            .bearer_auth(&user.id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(free.status(), StatusCode::OK, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+    }
+}
+
+#[tokio::test]
+async fn the_challenge_can_be_sent_in_headers() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_api(storage.clone()).await;
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+
+        let response = reqwest::Client::new()
+            .get(format!("{base}/authz"))
+            .bearer_auth(&user.id)
+            .header("x-original-method", "GET")
+            .header("x-original-uri", "/reports/7")
+            .header("x-authz-challenge", "headers")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{name}");
+        assert_eq!(response.headers()["x-credits-remaining"], "1", "{name}");
+        let challenge = response.headers()["www-authenticate"].to_str().unwrap();
+        assert!(
+            challenge.starts_with("L402 payment_context_token="),
+            "{name}"
+        );
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+    }
+}
@@ -0,0 +1,137 @@
+//! Helpers shared by the integration tests
+//!
+//! Tests run against the memory and SQLite backends, plus PostgreSQL when
+//! `DATABASE_URL` points at a Postgres database and Redis when `REDIS_URL` is set.
+//! Those databases are shared between runs, so tests only look at the users, keys
+//! and payment requests they create themselves.
+
+#![allow(dead_code)]
+
+use axum::Router;
+use chrono::{Duration, Utc};
+use l402_server_example_rs::models::PaymentRequiredResponse;
+use l402_server_example_rs::paywall::PaymentChallenge;
+use l402_server_example_rs::storage::{
+    MemoryStorage, PostgresStorage, RedisStorage, SqliteStorage, Storage,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+/// Payment challenge quoting no offers
+pub struct TestChallenge;
+
+impl PaymentChallenge for TestChallenge {
+    fn payment_required(&self, _user_id: &str) -> PaymentRequiredResponse {
+        PaymentRequiredResponse {
+            expiry: Utc::now() + Duration::minutes(30),
+            offers: Vec::new(),
+            payment_context_token: "test-token".to_string(),
+            payment_request_url: "http://localhost/l402/payment-request".to_string(),
+        }
+    }
+}
+
+/// A storage backend under test
+pub struct Backend {
+    /// Name of the backend, for assertion messages
+    pub name: &'static str,
+    pub storage: Arc<dyn Storage>,
+    /// Directory holding the SQLite database, removed with the backend
+    _dir: Option<TempDir>,
+}
+
+/// SQLite database in a fresh temporary directory, removed when dropped
+pub struct SqliteDatabase {
+    dir: TempDir,
+}
+
+impl SqliteDatabase {
+    pub fn new() -> Self {
+        Self {
+            dir: TempDir::new().expect("temporary directory is created"),
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join("l402.db")
+    }
+
+    pub fn url(&self) -> String {
+        format!("sqlite://{}", self.path().display())
+    }
+
+    pub async fn storage(&self) -> SqliteStorage {
+        SqliteStorage::connect(&self.url())
+            .await
+            .expect("SQLite storage opens")
+    }
+}
+
+/// Postgres database the tests may use, from `DATABASE_URL`
+pub fn postgres_url() -> Option<String> {
+    std::env::var("DATABASE_URL").ok().filter(|url| {
+        PostgresStorage::URL_SCHEMES
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+    })
+}
+
+/// Redis server the tests may use, from `REDIS_URL`
+pub fn redis_url() -> Option<String> {
+    std::env::var("REDIS_URL")
+        .ok()
+        .filter(|url| url.starts_with("redis://") || url.starts_with("rediss://"))
+}
+
+/// Every backend available to the tests
+pub async fn backends() -> Vec<Backend> {
+    let sqlite = SqliteDatabase::new();
+    let mut backends = vec![
+        Backend {
+            name: "memory",
+            storage: Arc::new(MemoryStorage::new()),
+            _dir: None,
+        },
+        Backend {
+            name: "sqlite",
+            storage: Arc::new(sqlite.storage().await),
+            _dir: Some(sqlite.dir),
+        },
+    ];
+
+    if let Some(url) = postgres_url() {
+        let storage = PostgresStorage::connect(&url)
+            .await
+            .expect("PostgreSQL storage connects");
+        backends.push(Backend {
+            name: "postgres",
+            storage: Arc::new(storage),
+            _dir: None,
+        });
+    }
+
+    if let Some(url) = redis_url() {
+        let storage = RedisStorage::new(&url).expect("Redis storage connects");
+        backends.push(Backend {
+            name: "redis",
+            storage: Arc::new(storage),
+            _dir: None,
+        });
+    }
+
+    backends
+}
+
+/// Serve a router on a local port, returning its base URL
+pub async fn spawn(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("local port is free");
+    let addr = listener.local_addr().expect("listener has an address");
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.expect("server runs");
+    });
+    format!("http://{}", addr)
+}
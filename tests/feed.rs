@@ -0,0 +1,95 @@
+//! WebSocket feeds charged per message, closed once the credits run out
+
+mod common;
+
+use axum::{
+    Router,
+    extract::{Path, State, WebSocketUpgrade},
+    response::Response,
+    routing::get,
+};
+use l402_server_example_rs::config::{FeedBilling, FeedPricing};
+use l402_server_example_rs::models::User;
+use l402_server_example_rs::paywall::MeteredFeed;
+use l402_server_example_rs::storage::Storage;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Close code of a subscriber who ran out of credits
+const PAYMENT_REQUIRED_CLOSE_CODE: u16 = 4402;
+
+/// A feed sending three messages for a credit each, then staying open
+async fn spawn_feed(storage: Arc<dyn Storage>) -> String {
+    async fn subscribe(
+        State(feed): State<MeteredFeed>,
+        Path(user_id): Path<String>,
+        upgrade: WebSocketUpgrade,
+    ) -> Response {
+        let messages = tokio_stream::iter(["first", "second", "third"])
+            .map(str::to_string)
+            .chain(tokio_stream::pending());
+        feed.serve(upgrade, user_id, None, "/feed", messages).await
+    }
+
+    let pricing = FeedPricing {
+        billing: FeedBilling::Message,
+        credits: 1,
+        warn_credits: 1,
+    };
+    let feed = MeteredFeed::new(pricing, storage, common::TestChallenge);
+    let router = Router::new()
+        .route("/feed/{user_id}", get(subscribe))
+        .with_state(feed);
+    common::spawn(router).await.replacen("http", "ws", 1)
+}
+
+#[tokio::test]
+async fn subscribers_are_disconnected_once_out_of_credits() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_feed(storage.clone()).await;
+        let user = User::new(2);
+        storage.create_user(&user).await.unwrap();
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("{base}/feed/{}", user.id))
+            .await
+            .unwrap();
+        let mut texts = Vec::new();
+        let close = loop {
+            match socket.next().await.expect("the feed closes").unwrap() {
+                Message::Text(text) => texts.push(text.to_string()),
+                Message::Close(frame) => break frame.expect("the close has a code"),
+                _ => {}
+            }
+        };
+
+        assert_eq!(texts.len(), 3, "{name}");
+        assert_eq!(texts[0], "first", "{name}");
+        let warning: Value = serde_json::from_str(&texts[1]).unwrap();
+        assert_eq!(warning["type"], "balance_warning", "{name}");
+        assert_eq!(warning["credits"], 1, "{name}");
+        assert_eq!(texts[2], "second", "{name}");
+        assert_eq!(u16::from(close.code), PAYMENT_REQUIRED_CLOSE_CODE, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+    }
+}
+
+#[tokio::test]
+async fn subscribers_who_cant_pay_are_refused_the_upgrade() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_feed(storage.clone()).await;
+        let user = User::new(0);
+        storage.create_user(&user).await.unwrap();
+
+        let error = tokio_tungstenite::connect_async(format!("{base}/feed/{}", user.id))
+            .await
+            .unwrap_err();
+        let tungstenite::Error::Http(response) = error else {
+            panic!("{name}: expected an HTTP error, got {error}");
+        };
+        assert_eq!(response.status().as_u16(), 402, "{name}");
+    }
+}
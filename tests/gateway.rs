@@ -0,0 +1,208 @@
+//! Requests forwarded through the paywalled gateway to a stub upstream
+
+mod common;
+
+use axum::{
+    Json, Router,
+    body::Body,
+    body::Bytes,
+    extract::State,
+    http::{self, HeaderMap, Request, Uri, header},
+    response::IntoResponse,
+    routing::any,
+};
+use l402_server_example_rs::config::{GatewayService, ServiceCaveats};
+use l402_server_example_rs::gateway::Gateway;
+use l402_server_example_rs::models::{CreditReason, User};
+use l402_server_example_rs::storage::{LedgerQuery, Storage};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Upstream echoing the path, headers and body of each request back as JSON
+///
+/// Requests to `/fail` get a 500. Returns its base URL and the number of requests
+/// it has received.
+async fn spawn_upstream() -> (String, Arc<AtomicUsize>) {
+    async fn echo(
+        State(hits): State<Arc<AtomicUsize>>,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> impl IntoResponse {
+        hits.fetch_add(1, Ordering::SeqCst);
+        if uri.path() == "/fail" {
+            return (
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(json!({})),
+            );
+        }
+
+        let received: HashMap<_, _> = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
+            .collect();
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert("x-upstream", "stub".parse().unwrap());
+        response_headers.insert("proxy-authenticate", "Basic".parse().unwrap());
+        (
+            http::StatusCode::OK,
+            response_headers,
+            Json(json!({
+                "path": uri.path(),
+                "headers": received,
+                "body": String::from_utf8_lossy(&body),
+            })),
+        )
+    }
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let router = Router::new().fallback(any(echo)).with_state(hits.clone());
+    (common::spawn(router).await, hits)
+}
+
+/// Gateway serving `/svc` from the upstream for 2 credits a request
+async fn spawn_gateway(storage: Arc<dyn Storage>, upstream: &str) -> String {
+    let gateway = Gateway::new(vec![GatewayService {
+        name: "stub".to_string(),
+        host: None,
+        path_prefix: "/svc".to_string(),
+        upstream_url: upstream.to_string(),
+        strip_prefix: true,
+        credits: 2,
+        caveats: ServiceCaveats::default(),
+        metering: None,
+    }]);
+    common::spawn(gateway.router(storage, common::TestChallenge)).await
+}
+
+#[tokio::test]
+async fn paid_requests_are_forwarded_with_their_body_and_headers() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let (upstream, hits) = spawn_upstream().await;
+        let base = spawn_gateway(storage.clone(), &upstream).await;
+        let user = User::new(3);
+        storage.create_user(&user).await.unwrap();
+
+        let chunks: Vec<Result<&'static str, std::io::Error>> =
+            vec![Ok("streamed "), Ok("request "), Ok("body")];
+        let response = reqwest::Client::new()
+            .post(format!("{base}/svc/items?page=2"))
+            .bearer_auth(&user.id)
+            .header("x-custom", "kept")
+            .header("keep-alive", "timeout=5")
+            .header("proxy-authorization", "Basic secret")
+            .body(reqwest::Body::wrap_stream(tokio_stream::iter(chunks)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{name}");
+        assert_eq!(response.headers()["x-upstream"], "stub", "{name}");
+        assert!(
+            response.headers().get("proxy-authenticate").is_none(),
+            "{name}"
+        );
+
+        let echoed: Value = response.json().await.unwrap();
+        assert_eq!(echoed["path"], "/items", "{name}");
+        assert_eq!(echoed["body"], "streamed request body", "{name}");
+        let headers = &echoed["headers"];
+        assert_eq!(headers["x-custom"], "kept", "{name}");
+        for stripped in ["keep-alive", "proxy-authorization", "authorization"] {
+            assert!(headers.get(stripped).is_none(), "{name}: {stripped}");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+    }
+}
+
+#[tokio::test]
+async fn unpaid_requests_never_reach_the_upstream() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let (upstream, hits) = spawn_upstream().await;
+        let base = spawn_gateway(storage.clone(), &upstream).await;
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+
+        let response = reqwest::Client::new()
+            .get(format!("{base}/svc/items"))
+            .bearer_auth(&user.id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED, "{name}");
+        assert_eq!(hits.load(Ordering::SeqCst), 0, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+    }
+}
+
+#[tokio::test]
+async fn upstream_errors_are_refunded() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let (upstream, hits) = spawn_upstream().await;
+        let base = spawn_gateway(storage.clone(), &upstream).await;
+        let user = User::new(2);
+        storage.create_user(&user).await.unwrap();
+
+        let response = reqwest::Client::new()
+            .get(format!("{base}/svc/fail"))
+            .bearer_auth(&user.id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "{name}"
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 2);
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        let reasons: Vec<_> = entries.iter().map(|entry| entry.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                CreditReason::Grant,
+                CreditReason::Spend,
+                CreditReason::Refund
+            ],
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn services_pinned_to_an_ipv6_host_match_with_or_without_a_port() {
+    let gateway = Gateway::new(vec![GatewayService {
+        name: "ipv6".to_string(),
+        host: Some("::1".to_string()),
+        path_prefix: "/svc".to_string(),
+        upstream_url: "http://upstream".to_string(),
+        strip_prefix: true,
+        credits: 1,
+        caveats: ServiceCaveats::default(),
+        metering: None,
+    }]);
+    let routed = |host: &str| {
+        let request = Request::get("/svc/items")
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        gateway.route(&request).map(|service| service.name.clone())
+    };
+
+    assert_eq!(routed("[::1]:8080").as_deref(), Some("ipv6"));
+    assert_eq!(routed("[::1]").as_deref(), Some("ipv6"));
+    assert_eq!(routed("[::2]:8080"), None);
+    assert_eq!(routed("localhost:8080"), None);
+}
@@ -0,0 +1,188 @@
+//! Idempotency keys, as stored and as replayed through the API router
+
+mod common;
+
+use l402_server_example_rs::api::create_router;
+use l402_server_example_rs::config::Config;
+use l402_server_example_rs::models::{IdempotencyRecord, StoredResponse, User};
+use l402_server_example_rs::payments::PaymentService;
+use l402_server_example_rs::services::BlockService;
+use reqwest::StatusCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::task::JoinSet;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+#[tokio::test]
+async fn idempotency_keys_are_reserved_completed_and_released() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let key = uuid::Uuid::new_v4().to_string();
+        let record = IdempotencyRecord::in_progress("fingerprint".to_string());
+        assert!(
+            storage
+                .begin_idempotent_request(&key, &record, 60)
+                .await
+                .unwrap()
+                .is_none(),
+            "{name}"
+        );
+
+        let existing = storage
+            .begin_idempotent_request(&key, &record, 60)
+            .await
+            .unwrap()
+            .expect("the key is reserved");
+        assert!(existing.response.is_none(), "{name}");
+
+        let completed = IdempotencyRecord {
+            response: Some(StoredResponse {
+                status: 200,
+                content_type: Some("application/octet-stream".to_string()),
+                body: vec![0, 159, 146, 150],
+            }),
+            ..record.clone()
+        };
+        storage
+            .complete_idempotent_request(&key, &completed, 60)
+            .await
+            .unwrap();
+        let replayed = storage
+            .begin_idempotent_request(&key, &record, 60)
+            .await
+            .unwrap()
+            .and_then(|record| record.response)
+            .expect("the response is stored");
+        assert_eq!(replayed.body, [0, 159, 146, 150], "{name}");
+
+        storage.abandon_idempotent_request(&key).await.unwrap();
+        assert!(
+            storage
+                .begin_idempotent_request(&key, &record, 60)
+                .await
+                .unwrap()
+                .is_none(),
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_key_is_never_held_by_two_requests_at_once() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let key = uuid::Uuid::new_v4().to_string();
+        let holders = Arc::new(AtomicUsize::new(0));
+
+        // Requests keep reserving the key and releasing it again
+        let mut requests = JoinSet::new();
+        for _ in 0..8 {
+            let (storage, key, holders) = (storage.clone(), key.clone(), holders.clone());
+            requests.spawn(async move {
+                let record = IdempotencyRecord::in_progress("fingerprint".to_string());
+                for _ in 0..20 {
+                    let existing = storage
+                        .begin_idempotent_request(&key, &record, 60)
+                        .await
+                        .unwrap();
+                    if existing.is_none() {
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+                        tokio::task::yield_now().await;
+                        holders.fetch_sub(1, Ordering::SeqCst);
+                        storage.abandon_idempotent_request(&key).await.unwrap();
+                    }
+                }
+            });
+        }
+        while let Some(request) = requests.join_next().await {
+            assert!(request.is_ok(), "{name}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn expired_keys_and_claims_are_purged() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let key = uuid::Uuid::new_v4().to_string();
+        let record = IdempotencyRecord::in_progress("fingerprint".to_string());
+        storage
+            .begin_idempotent_request(&key, &record, 0)
+            .await
+            .unwrap();
+        assert!(
+            storage.claim_webhook_event("test", &key, 0).await.unwrap(),
+            "{name}"
+        );
+
+        // Redis expires them itself
+        let purged = storage.purge_expired().await.unwrap();
+        assert!(purged >= 2 || name == "redis", "{name}");
+        assert!(
+            storage
+                .begin_idempotent_request(&key, &record, 60)
+                .await
+                .unwrap()
+                .is_none(),
+            "{name}"
+        );
+        assert!(
+            storage.claim_webhook_event("test", &key, 60).await.unwrap(),
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn retried_requests_are_replayed_without_another_charge() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let mut config = Config::from_env();
+        config.pricing =
+            serde_json::from_str(r#"[{"method":"GET","route":"/info","credits":1}]"#).unwrap();
+        config.gateway_services = Vec::new();
+        let config = Arc::new(config);
+        let router = create_router(
+            config.clone(),
+            storage.clone(),
+            PaymentService::new_without_providers(config.clone(), storage.clone()),
+            BlockService::new(),
+        );
+        let base = common::spawn(router).await;
+
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+        let client = reqwest::Client::new();
+        let get = |path: &str, key: &str| {
+            client
+                .get(format!("{base}{path}"))
+                .bearer_auth(&user.id)
+                .header(IDEMPOTENCY_KEY_HEADER, key)
+                .send()
+        };
+
+        let first = get("/info", "retry-1").await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK, "{name}");
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+        let first = first.text().await.unwrap();
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+
+        let replay = get("/info", "retry-1").await.unwrap();
+        assert_eq!(replay.status(), StatusCode::OK, "{name}");
+        assert_eq!(
+            replay.headers()[IDEMPOTENT_REPLAYED_HEADER],
+            "true",
+            "{name}"
+        );
+        assert_eq!(replay.text().await.unwrap(), first, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+
+        let fresh = get("/info", "retry-2").await.unwrap();
+        assert_eq!(fresh.status(), StatusCode::PAYMENT_REQUIRED, "{name}");
+
+        let reused = get("/usage", "retry-1").await.unwrap();
+        assert_eq!(reused.status(), StatusCode::CONFLICT, "{name}");
+    }
+}
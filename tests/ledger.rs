@@ -0,0 +1,187 @@
+//! The credit ledger recorded alongside every balance change
+
+mod common;
+
+use chrono::{Duration, Utc};
+use l402_server_example_rs::models::{CreditReason, User};
+use l402_server_example_rs::storage::{LedgerQuery, Storage};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::SqlitePoolOptions;
+
+#[tokio::test]
+async fn spends_and_refunds_are_recorded_with_the_balance() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(3);
+        storage.create_user(&user).await.unwrap();
+
+        let spent = storage
+            .spend_user_credits(&user.id, 2, Some("req-1"), Some("/block"))
+            .await
+            .unwrap();
+        assert_eq!(spent.credits, 1, "{name}");
+
+        let refunded = storage
+            .update_user_credits(
+                &user.id,
+                2,
+                CreditReason::Refund,
+                Some("req-1"),
+                Some("/block"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(refunded.credits, 3, "{name}");
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.reason, entry.amount, entry.balance))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (CreditReason::Grant, 3, 3),
+                (CreditReason::Spend, -2, 1),
+                (CreditReason::Refund, 2, 3),
+            ],
+            "{name}"
+        );
+        assert_eq!(entries[1].reference.as_deref(), Some("req-1"), "{name}");
+        assert_eq!(entries[1].route.as_deref(), Some("/block"), "{name}");
+    }
+}
+
+#[tokio::test]
+async fn credit_updates_stop_at_zero() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(2);
+        storage.create_user(&user).await.unwrap();
+
+        let updated = storage
+            .update_user_credits(&user.id, -5, CreditReason::Adjustment, None, None)
+            .await
+            .unwrap();
+        assert_eq!(updated.credits, 0, "{name}");
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(entries.last().unwrap().amount, -2, "{name}");
+    }
+}
+
+#[tokio::test]
+async fn sqlite_ledger_is_append_only() {
+    let database = common::SqliteDatabase::new();
+    let storage = database.storage().await;
+    let user = User::new(1);
+    storage.create_user(&user).await.unwrap();
+
+    let pool = SqlitePoolOptions::new()
+        .connect(&database.url())
+        .await
+        .unwrap();
+
+    let update = sqlx::query("UPDATE credit_ledger SET delta = 100 WHERE user_id = ?")
+        .bind(&user.id)
+        .execute(&pool)
+        .await;
+    assert!(update.is_err());
+
+    let delete = sqlx::query("DELETE FROM credit_ledger WHERE user_id = ?")
+        .bind(&user.id)
+        .execute(&pool)
+        .await;
+    assert!(delete.is_err());
+
+    let (entries,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM credit_ledger")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(entries, 1);
+}
+
+#[tokio::test]
+async fn postgres_ledger_is_append_only() {
+    let Some(url) = common::postgres_url() else {
+        return;
+    };
+    let storage = l402_server_example_rs::storage::PostgresStorage::connect(&url)
+        .await
+        .unwrap();
+    let user = User::new(1);
+    storage.create_user(&user).await.unwrap();
+
+    let pool = PgPoolOptions::new().connect(&url).await.unwrap();
+
+    let update = sqlx::query("UPDATE credit_ledger SET delta = 100 WHERE user_id = $1")
+        .bind(&user.id)
+        .execute(&pool)
+        .await;
+    assert!(update.is_err());
+
+    let delete = sqlx::query("DELETE FROM credit_ledger WHERE user_id = $1")
+        .bind(&user.id)
+        .execute(&pool)
+        .await;
+    assert!(delete.is_err());
+
+    let (entries,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM credit_ledger WHERE user_id = $1")
+            .bind(&user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(entries, 1);
+}
+
+#[tokio::test]
+async fn ledger_queries_filter_and_page() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(5);
+        storage.create_user(&user).await.unwrap();
+        for _ in 0..3 {
+            storage
+                .spend_user_credits(&user.id, 1, None, None)
+                .await
+                .unwrap();
+        }
+
+        let spends = LedgerQuery {
+            reasons: vec![CreditReason::Spend],
+            limit: Some(2),
+            ..LedgerQuery::default()
+        };
+        let page = storage
+            .list_ledger_entries(&user.id, &spends)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 2, "{name}");
+        assert!(page.iter().all(|entry| entry.reason == CreditReason::Spend));
+
+        let next = LedgerQuery {
+            after: Some(page[1].id),
+            ..spends.clone()
+        };
+        let next = storage.list_ledger_entries(&user.id, &next).await.unwrap();
+        assert_eq!(next.len(), 1, "{name}");
+        assert!(next[0].id > page[1].id, "{name}");
+
+        let future = LedgerQuery {
+            from: Some(Utc::now() + Duration::hours(1)),
+            ..LedgerQuery::default()
+        };
+        let future = storage
+            .list_ledger_entries(&user.id, &future)
+            .await
+            .unwrap();
+        assert!(future.is_empty(), "{name}");
+    }
+}
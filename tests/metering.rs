@@ -0,0 +1,158 @@
+//! Response bodies charged by the byte as they stream through the L402 layer
+
+mod common;
+
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    http::{HeaderMap, Request, StatusCode, header},
+    routing::get,
+};
+use http_body::Body as _;
+use l402_server_example_rs::config::PricingTable;
+use l402_server_example_rs::models::{CreditReason, User};
+use l402_server_example_rs::paywall::L402Layer;
+use l402_server_example_rs::storage::{LedgerQuery, Storage};
+use std::convert::Infallible;
+use std::future::poll_fn;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::Service;
+
+/// A router streaming `chunks` 1000 byte chunks, metered at a credit per KiB
+fn export_router(storage: Arc<dyn Storage>, chunks: usize) -> Router {
+    let pricing: PricingTable = serde_json::from_str(
+        r#"[{"method":"GET","route":"/export","credits":0,
+            "metering":{"unit":"bytes","per_credit":1024}}]"#,
+    )
+    .unwrap();
+    let export = move || async move {
+        let chunks = (0..chunks).map(|_| Ok::<_, Infallible>(Bytes::from(vec![b'x'; 1000])));
+        Body::from_stream(tokio_stream::iter(chunks))
+    };
+    Router::new()
+        .route("/export", get(export))
+        .route_layer(L402Layer::new(pricing, storage, common::TestChallenge))
+}
+
+/// The data and trailers of a body, or the error it failed with
+async fn read_body(body: Body) -> Result<(Vec<u8>, Option<HeaderMap>), axum::Error> {
+    let mut body = pin!(body);
+    let (mut data, mut trailers) = (Vec::new(), None);
+    while let Some(frame) = poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
+        match frame?.into_data() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+    Ok((data, trailers))
+}
+
+/// Amount of the spend recorded once the meter has settled, after the body ended
+async fn settled_spend(storage: &dyn Storage, user_id: &str) -> i64 {
+    for _ in 0..50 {
+        let entries = storage
+            .list_ledger_entries(user_id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        if let Some(entry) = entries
+            .last()
+            .filter(|entry| entry.reason == CreditReason::Spend)
+        {
+            return entry.amount;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the meter never settled");
+}
+
+#[tokio::test]
+async fn metered_bodies_end_at_the_last_byte_paid_for() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let mut router = export_router(storage.clone(), 3);
+        let user = User::new(2);
+        storage.create_user(&user).await.unwrap();
+
+        let request = Request::get("/export")
+            .header(header::AUTHORIZATION, format!("Bearer {}", user.id))
+            .header(header::TE, "trailers")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{name}");
+        assert_eq!(
+            response.headers()[header::TRAILER],
+            "x-credits-exhausted",
+            "{name}"
+        );
+
+        let (data, trailers) = read_body(response.into_body()).await.unwrap();
+        assert_eq!(data.len(), 2048, "{name}");
+        let trailers = trailers.expect("the body ends with trailers");
+        assert_eq!(trailers["x-credits-exhausted"], "true", "{name}");
+
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+        let spend = settled_spend(storage.as_ref(), &user.id).await;
+        assert_eq!(spend, -2, "{name}");
+    }
+}
+
+#[tokio::test]
+async fn metered_bodies_end_cleanly_for_clients_without_trailers() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let mut router = export_router(storage.clone(), 3);
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+
+        let request = Request::get("/export")
+            .header(header::AUTHORIZATION, format!("Bearer {}", user.id))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{name}");
+        assert!(response.headers().get(header::TRAILER).is_none(), "{name}");
+
+        let (data, trailers) = read_body(response.into_body()).await.unwrap();
+        assert_eq!(data.len(), 1024, "{name}");
+        assert!(trailers.is_none(), "{name}");
+        assert_eq!(
+            settled_spend(storage.as_ref(), &user.id).await,
+            -1,
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn clients_that_disconnect_pay_only_for_what_they_read() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let mut router = export_router(storage.clone(), 10);
+        let user = User::new(10);
+        storage.create_user(&user).await.unwrap();
+
+        let request = Request::get("/export")
+            .header(header::AUTHORIZATION, format!("Bearer {}", user.id))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        let mut body = Box::pin(response.into_body());
+        let frame = poll_fn(|cx| body.as_mut().poll_frame(cx)).await;
+        assert_eq!(frame.unwrap().unwrap().into_data().unwrap().len(), 1000);
+
+        // Give the meter time to buffer frames the client never reads
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(body);
+
+        assert_eq!(
+            settled_spend(storage.as_ref(), &user.id).await,
+            -1,
+            "{name}"
+        );
+        let credits = storage.get_user(&user.id).await.unwrap().credits;
+        assert_eq!(credits, 9, "{name}");
+    }
+}
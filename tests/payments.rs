@@ -0,0 +1,285 @@
+//! Lightning payments settled through a stub LNBits wallet
+
+mod common;
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::get,
+};
+use chrono::{Duration, Utc};
+use l402_server_example_rs::api::create_router;
+use l402_server_example_rs::config::Config;
+use l402_server_example_rs::models::{PaymentMethod, PaymentRequest, PaymentStatus, User};
+use l402_server_example_rs::payments::PaymentService;
+use l402_server_example_rs::services::BlockService;
+use l402_server_example_rs::storage::{MemoryStorage, Storage};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Credits bought by the payment requests of the tests
+const CREDITS: u32 = 10;
+
+/// Invoices the stub wallet reports as paid
+#[derive(Clone, Default)]
+struct PaidInvoices(Arc<Mutex<HashSet<String>>>);
+
+impl PaidInvoices {
+    fn pay(&self, payment_hash: &str) {
+        self.0.lock().unwrap().insert(payment_hash.to_string());
+    }
+}
+
+/// Stub LNBits wallet answering invoice status checks, returning its base URL
+async fn spawn_lnbits(paid: PaidInvoices) -> String {
+    async fn payment(
+        State(paid): State<PaidInvoices>,
+        Path(payment_hash): Path<String>,
+    ) -> Json<Value> {
+        let paid = paid.0.lock().unwrap().contains(&payment_hash);
+        Json(json!({
+            "paid": paid,
+            "details": {
+                "checking_id": payment_hash,
+                "payment_hash": payment_hash,
+                "wallet_id": "wallet",
+                "amount": 50_000,
+                "fee": 1_000,
+                "bolt11": "lnbc1stub",
+                "status": if paid { "success" } else { "pending" },
+                "time": "2024-01-01T00:00:00",
+                "created_at": "2024-01-01T00:00:00",
+                "updated_at": "2024-01-01T00:00:00",
+                "extra": {},
+            },
+        }))
+    }
+
+    let router = Router::new()
+        .route("/api/v1/payments/{payment_hash}", get(payment))
+        .with_state(paid);
+    common::spawn(router).await
+}
+
+/// Configuration for Lightning payments through the stub wallet
+fn config(lnbits_url: &str) -> Arc<Config> {
+    let mut config = Config::from_env();
+    config.lightning_enabled = true;
+    config.lnbits_url = Some(lnbits_url.to_string());
+    config.lnbits_admin_key = Some("admin-key".to_string());
+    config.lnbits_invoice_read_key = Some("invoice-read-key".to_string());
+    config.lnbits_webhook_url = Some("http://localhost/webhook/lightning".to_string());
+    config.lnbits_payment_stream = false;
+    config.coinbase_enabled = false;
+    config.settlement_min_interval_secs = 0;
+    Arc::new(config)
+}
+
+/// Payment service with its Lightning provider, as the server starts it
+fn payment_service(config: Arc<Config>, storage: Arc<dyn Storage>) -> PaymentService {
+    let mut service = PaymentService::new_without_providers(config, storage);
+    service.init_providers().unwrap();
+    service
+}
+
+/// The API router taking payments through the stub wallet, returning its base URL
+async fn spawn_api(config: Arc<Config>, storage: Arc<dyn Storage>) -> String {
+    let service = payment_service(config.clone(), storage.clone());
+    let router = create_router(config, storage, service, BlockService::new());
+    common::spawn(router).await
+}
+
+/// Deliver a Lightning webhook for an invoice with a URL token, if any
+async fn lightning_webhook(base: &str, payment_hash: &str, token: Option<&str>) -> StatusCode {
+    let mut url = format!("{base}/webhook/lightning");
+    if let Some(token) = token {
+        url = format!("{url}?token={token}");
+    }
+    reqwest::Client::new()
+        .post(url)
+        .json(&json!({"payment_hash": payment_hash, "time": Utc::now().timestamp()}))
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+/// A pending Lightning payment request for a fresh invoice, as stored at creation
+fn lightning_request(user_id: &str) -> PaymentRequest {
+    let mut request = PaymentRequest::new(
+        user_id.to_string(),
+        "offer1".to_string(),
+        CREDITS,
+        PaymentMethod::Lightning,
+        Utc::now() + Duration::minutes(30),
+    );
+    request.external_id = Some(uuid::Uuid::new_v4().to_string());
+    request.webhook_token = Some(uuid::Uuid::new_v4().to_string());
+    request
+}
+
+/// Wait for a payment request to reach a status
+async fn wait_for_status(
+    storage: &dyn Storage,
+    request_id: &str,
+    status: PaymentStatus,
+) -> PaymentRequest {
+    for _ in 0..50 {
+        let request = storage.get_payment_request(request_id).await.unwrap();
+        if request.status == status {
+            return request;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("payment request {request_id} never became {status:?}");
+}
+
+#[tokio::test]
+async fn pending_payments_are_tracked_again_after_a_restart() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let paid = PaidInvoices::default();
+        let config = config(&spawn_lnbits(paid.clone()).await);
+        let user = User::new(0);
+        storage.create_user(&user).await.unwrap();
+
+        // Created before the restart, and paid while the server was down
+        let request = lightning_request(&user.id);
+        storage.store_payment_request(&request).await.unwrap();
+        paid.pay(request.external_id.as_deref().unwrap());
+
+        let mut service = payment_service(config, storage.clone());
+        service.start_reconciler();
+        let resumed = service.resume_pending_payments().await.unwrap();
+        assert!(resumed >= 1, "{name}");
+
+        wait_for_status(storage.as_ref(), &request.id, PaymentStatus::Paid).await;
+        let credits = storage.get_user(&user.id).await.unwrap().credits;
+        assert_eq!(credits, CREDITS, "{name}");
+    }
+}
+
+#[tokio::test]
+async fn only_lightning_payments_with_an_invoice_are_resumed() {
+    // A fresh store, as the count covers every pending request
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let config = config(&spawn_lnbits(PaidInvoices::default()).await);
+    let user = User::new(0);
+    storage.create_user(&user).await.unwrap();
+
+    let mut coinbase = lightning_request(&user.id);
+    coinbase.method = PaymentMethod::Coinbase;
+    let mut without_invoice = lightning_request(&user.id);
+    without_invoice.external_id = None;
+    let mut expired = lightning_request(&user.id);
+    expired.status = PaymentStatus::Expired;
+    for request in [
+        lightning_request(&user.id),
+        coinbase,
+        without_invoice,
+        expired,
+    ] {
+        storage.store_payment_request(&request).await.unwrap();
+    }
+
+    let mut service = payment_service(config, storage);
+    assert_eq!(service.resume_pending_payments().await.unwrap(), 0);
+    service.start_reconciler();
+    assert_eq!(service.resume_pending_payments().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn webhooks_without_the_invoice_token_are_rejected() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let paid = PaidInvoices::default();
+        let config = config(&spawn_lnbits(paid.clone()).await);
+        let base = spawn_api(config, storage.clone()).await;
+        let user = User::new(0);
+        storage.create_user(&user).await.unwrap();
+
+        let (request, other) = (lightning_request(&user.id), lightning_request(&user.id));
+        for request in [&request, &other] {
+            storage.store_payment_request(request).await.unwrap();
+            paid.pay(request.external_id.as_deref().unwrap());
+        }
+        let payment_hash = request.external_id.as_deref().unwrap();
+        let token = request.webhook_token.as_deref().unwrap();
+        let tampered = format!("{}0", &token[..token.len() - 1]);
+
+        let rejected = [
+            None,
+            Some(""),
+            Some(tampered.as_str()),
+            other.webhook_token.as_deref(),
+        ];
+        for token in rejected {
+            let status = lightning_webhook(&base, payment_hash, token).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{name}: {token:?}");
+        }
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Pending, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+
+        let status = lightning_webhook(&base, payment_hash, Some(token)).await;
+        assert_eq!(status, StatusCode::OK, "{name}");
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Paid, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, CREDITS);
+    }
+}
+
+#[tokio::test]
+async fn webhooks_for_unpaid_invoices_credit_nothing() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let config = config(&spawn_lnbits(PaidInvoices::default()).await);
+        let base = spawn_api(config, storage.clone()).await;
+        let user = User::new(0);
+        storage.create_user(&user).await.unwrap();
+        let request = lightning_request(&user.id);
+        storage.store_payment_request(&request).await.unwrap();
+
+        // The token is right but LNBits doesn't know the invoice as paid
+        let status = lightning_webhook(
+            &base,
+            request.external_id.as_deref().unwrap(),
+            request.webhook_token.as_deref(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{name}");
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Pending, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 0);
+    }
+}
+
+#[tokio::test]
+async fn payment_requests_need_a_valid_payment_context() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let config = config(&spawn_lnbits(PaidInvoices::default()).await);
+    let base = spawn_api(config.clone(), storage.clone()).await;
+    let user = User::new(0);
+    storage.create_user(&user).await.unwrap();
+    let service = payment_service(config.clone(), storage);
+    let token = service.payment_required(&user.id).payment_context_token;
+    let offer_id = config.offers[0].id.clone();
+
+    let (payload, signature) = token.split_once('.').unwrap();
+    let tampered = format!("{payload}x.{signature}");
+    for token in ["", "not-a-token", tampered.as_str()] {
+        let response = reqwest::Client::new()
+            .post(format!("{base}/l402/payment-request"))
+            .json(&json!({
+                "offer_id": offer_id,
+                "payment_method": "lightning",
+                "payment_context_token": token,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{token}");
+    }
+}
@@ -0,0 +1,137 @@
+//! The L402 layer charging for routes of a router it doesn't know about
+
+mod common;
+
+use axum::{Router, http, routing::get};
+use l402_server_example_rs::models::{CreditReason, User};
+use l402_server_example_rs::paywall::{CREDITS_REMAINING_HEADER, L402Layer};
+use l402_server_example_rs::storage::{LedgerQuery, Storage};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Price of the routes of the test router
+fn price(_method: &http::Method, route: &str) -> Option<u32> {
+    match route {
+        "/reports/{id}" | "/broken" => Some(2),
+        _ => None,
+    }
+}
+
+/// A router with a paid route, a failing paid route and a free route, paywalled
+async fn spawn_router(storage: Arc<dyn Storage>) -> String {
+    let router = Router::new()
+        .route("/reports/{id}", get(|| async { "report" }))
+        .route(
+            "/broken",
+            get(|| async { http::StatusCode::INTERNAL_SERVER_ERROR }),
+        )
+        .route("/health", get(|| async { "ok" }))
+        .route_layer(L402Layer::new(price, storage, common::TestChallenge));
+    common::spawn(router).await
+}
+
+#[tokio::test]
+async fn paid_routes_are_charged_until_the_credits_run_out() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_router(storage.clone()).await;
+        let user = User::new(3);
+        storage.create_user(&user).await.unwrap();
+        let client = reqwest::Client::new();
+        let get = |path: &str| {
+            client
+                .get(format!("{base}{path}"))
+                .bearer_auth(&user.id)
+                .send()
+        };
+
+        let paid = get("/reports/7").await.unwrap();
+        assert_eq!(paid.status(), StatusCode::OK, "{name}");
+        assert_eq!(paid.text().await.unwrap(), "report", "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+
+        let refused = get("/reports/8").await.unwrap();
+        assert_eq!(refused.status(), StatusCode::PAYMENT_REQUIRED, "{name}");
+        assert_eq!(refused.headers()[CREDITS_REMAINING_HEADER], "1", "{name}");
+        let challenge: Value = refused.json().await.unwrap();
+        assert_eq!(challenge["payment_context_token"], "test-token", "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+
+        let free = client.get(format!("{base}/health")).send().await.unwrap();
+        assert_eq!(free.status(), StatusCode::OK, "{name}");
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        let spend = entries.last().unwrap();
+        assert_eq!(
+            (spend.reason, spend.amount, spend.route.as_deref()),
+            (CreditReason::Spend, -2, Some("/reports/{id}")),
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn failed_requests_are_refunded() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_router(storage.clone()).await;
+        let user = User::new(2);
+        storage.create_user(&user).await.unwrap();
+
+        let response = reqwest::Client::new()
+            .get(format!("{base}/broken"))
+            .bearer_auth(&user.id)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "{name}"
+        );
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 2);
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        let reasons: Vec<_> = entries.iter().map(|entry| entry.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                CreditReason::Grant,
+                CreditReason::Spend,
+                CreditReason::Refund
+            ],
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn paid_routes_require_a_known_user() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, backend.storage.clone());
+        let base = spawn_router(storage).await;
+        let client = reqwest::Client::new();
+
+        let anonymous = client
+            .get(format!("{base}/reports/7"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED, "{name}");
+
+        let unknown = client
+            .get(format!("{base}/reports/7"))
+            .bearer_auth(uuid::Uuid::new_v4().to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unknown.status(), StatusCode::UNAUTHORIZED, "{name}");
+    }
+}
@@ -0,0 +1,173 @@
+//! Storage behaviour every backend shares
+
+mod common;
+
+use chrono::{Duration, Utc};
+use l402_server_example_rs::models::{
+    CreditReason, PaymentMethod, PaymentRequest, PaymentStatus, User,
+};
+use l402_server_example_rs::storage::{LedgerQuery, StorageError};
+
+#[tokio::test]
+async fn overdrawing_spends_are_rejected_without_changes() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+
+        let result = storage.spend_user_credits(&user.id, 2, None, None).await;
+        assert!(
+            matches!(
+                result,
+                Err(StorageError::InsufficientCredits { balance: 1 })
+            ),
+            "{name}: {result:?}"
+        );
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 1);
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1, "{name}");
+
+        let result = storage
+            .spend_user_credits(&uuid::Uuid::new_v4().to_string(), 1, None, None)
+            .await;
+        assert!(matches!(result, Err(StorageError::UserNotFound)), "{name}");
+    }
+}
+
+#[tokio::test]
+async fn only_one_payment_transition_wins() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(0);
+        storage.create_user(&user).await.unwrap();
+        let mut request = PaymentRequest::new(
+            user.id.clone(),
+            "offer1".to_string(),
+            5,
+            PaymentMethod::Lightning,
+            Utc::now() + Duration::minutes(30),
+        );
+        storage.store_payment_request(&request).await.unwrap();
+
+        let is_pending =
+            |pending: Vec<PaymentRequest>| pending.iter().any(|pending| pending.id == request.id);
+        assert!(
+            is_pending(storage.list_pending_payment_requests().await.unwrap()),
+            "{name}"
+        );
+
+        request.status = PaymentStatus::Paid;
+        let from = PaymentStatus::Paid.reachable_from();
+        assert!(
+            storage
+                .transition_payment_request(&request, from)
+                .await
+                .unwrap(),
+            "{name}"
+        );
+        assert!(
+            !storage
+                .transition_payment_request(&request, from)
+                .await
+                .unwrap(),
+            "{name}"
+        );
+
+        request.status = PaymentStatus::Expired;
+        assert!(
+            !storage
+                .transition_payment_request(&request, PaymentStatus::Expired.reachable_from())
+                .await
+                .unwrap(),
+            "{name}"
+        );
+
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Paid, "{name}");
+        assert!(
+            !is_pending(storage.list_pending_payment_requests().await.unwrap()),
+            "{name}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn settling_a_payment_credits_it_once() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let user = User::new(1);
+        storage.create_user(&user).await.unwrap();
+        let mut request = PaymentRequest::new(
+            user.id.clone(),
+            "offer1".to_string(),
+            5,
+            PaymentMethod::Lightning,
+            Utc::now() + Duration::minutes(30),
+        );
+        storage.store_payment_request(&request).await.unwrap();
+
+        request.status = PaymentStatus::Paid;
+        let from = PaymentStatus::Paid.reachable_from();
+        assert!(
+            storage
+                .settle_payment_request(&request, from)
+                .await
+                .unwrap(),
+            "{name}"
+        );
+        assert!(
+            !storage
+                .settle_payment_request(&request, from)
+                .await
+                .unwrap(),
+            "{name}"
+        );
+
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Paid, "{name}");
+        assert_eq!(storage.get_user(&user.id).await.unwrap().credits, 6);
+
+        let entries = storage
+            .list_ledger_entries(&user.id, &LedgerQuery::default())
+            .await
+            .unwrap();
+        let purchase = entries.last().unwrap();
+        assert_eq!(
+            (purchase.reason, purchase.amount, purchase.balance),
+            (CreditReason::Purchase, 5, 6),
+            "{name}"
+        );
+        assert_eq!(purchase.reference.as_deref(), Some(request.id.as_str()));
+    }
+}
+
+#[tokio::test]
+async fn failed_settlements_leave_the_payment_pending() {
+    for backend in common::backends().await {
+        let (name, storage) = (backend.name, &backend.storage);
+        let mut request = PaymentRequest::new(
+            uuid::Uuid::new_v4().to_string(),
+            "offer1".to_string(),
+            5,
+            PaymentMethod::Lightning,
+            Utc::now() + Duration::minutes(30),
+        );
+        // The SQL backends don't store payments for unknown users at all
+        if storage.store_payment_request(&request).await.is_err() {
+            continue;
+        }
+
+        request.status = PaymentStatus::Paid;
+        let result = storage
+            .settle_payment_request(&request, PaymentStatus::Paid.reachable_from())
+            .await;
+        assert!(matches!(result, Err(StorageError::UserNotFound)), "{name}");
+
+        let stored = storage.get_payment_request(&request.id).await.unwrap();
+        assert_eq!(stored.status, PaymentStatus::Pending, "{name}");
+    }
+}